@@ -0,0 +1,66 @@
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+
+// An entry of the API key registry. Keys are handed out as `<id>.<secret>` and only a salted
+// hash of the secret is stored
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyEntry {
+    pub id: String,
+    pub owner: String,
+    // Hex encoded
+    pub salt: String,
+    // Hex encoded SHA-256 of the salt bytes followed by the secret
+    pub hash: String,
+    // Unix timestamps in seconds
+    pub created: u64,
+    pub expires: Option<u64>,
+    // Operation names the key can run, any operation when empty. The client picks the name and
+    // writes the document, so this only limits what runs when the router only accepts persisted
+    // queries. Otherwise it is a label, not authorization
+    #[serde(default)]
+    pub operations: Vec<String>,
+}
+
+// The id is what comes before the first `.` of a key
+pub fn check_key_id(id: &str) -> Result<(), String> {
+    if id.trim().is_empty() || id.contains('.') {
+        return Err(format!("API key id {:?} must be non-empty and can't contain '.'", id));
+    }
+    Ok(())
+}
+
+pub fn hash_secret(salt: &[u8], secret: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(secret.as_bytes());
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_the_salt_followed_by_the_secret() {
+        // SHA-256 of "abc"
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+        assert_eq!(hex(&hash_secret(b"", "abc")), expected);
+        assert_eq!(hex(&hash_secret(b"a", "bc")), expected);
+    }
+
+    #[test]
+    fn checks_key_ids() {
+        assert!(check_key_id("ci").is_ok());
+        assert!(check_key_id(" ").is_err());
+        assert!(check_key_id("ci.prod").is_err());
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
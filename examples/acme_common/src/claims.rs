@@ -0,0 +1,179 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+fn default_clock_skew_secs() -> u64 {
+    60
+}
+
+fn default_require_exp() -> bool {
+    true
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TokenValidation {
+    // Tolerance applied to `exp`, `nbf` and `iat` for clocks that drift
+    #[serde(default = "default_clock_skew_secs")]
+    pub clock_skew_secs: u64,
+    // When not empty, the token `aud` must contain at least one of these
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    #[serde(default = "default_require_exp")]
+    pub require_exp: bool,
+}
+
+impl Default for TokenValidation {
+    fn default() -> Self {
+        Self {
+            clock_skew_secs: default_clock_skew_secs(),
+            audiences: Vec::new(),
+            require_exp: default_require_exp(),
+        }
+    }
+}
+
+// The claims of a token checked against TokenValidation, flattened into each plugin's payload
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RegisteredClaims {
+    pub exp: Option<u64>,
+    pub nbf: Option<u64>,
+    pub iat: Option<u64>,
+    // `aud` can be a single string or a list of strings
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub aud: Vec<String>,
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error> where D: serde::Deserializer<'de> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        Some(OneOrMany::One(aud)) => vec![aud],
+        Some(OneOrMany::Many(aud)) => aud,
+        None => Vec::new(),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClaimError {
+    MissingExpiration,
+    Expired,
+    NotYetValid,
+    InvalidAudience,
+}
+
+pub fn validate_claims(
+    claims: &RegisteredClaims,
+    token_validation: &TokenValidation,
+    now: u64
+) -> Result<(), ClaimError> {
+    let skew = token_validation.clock_skew_secs;
+
+    match claims.exp {
+        Some(exp) if now > exp.saturating_add(skew) => {
+            return Err(ClaimError::Expired);
+        }
+        None if token_validation.require_exp => {
+            return Err(ClaimError::MissingExpiration);
+        }
+        _ => {}
+    }
+
+    // A token issued in the future is as unusable as one whose `nbf` isn't reached yet
+    if let Some(not_before) = claims.nbf.into_iter().chain(claims.iat).max() {
+        if not_before > now.saturating_add(skew) {
+            return Err(ClaimError::NotYetValid);
+        }
+    }
+
+    if
+        !token_validation.audiences.is_empty() &&
+        !claims.aud.iter().any(|aud| token_validation.audiences.contains(aud))
+    {
+        return Err(ClaimError::InvalidAudience);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn claims(claims: serde_json::Value) -> RegisteredClaims {
+        serde_json::from_value(claims).unwrap()
+    }
+
+    #[test]
+    fn enforces_expiration_with_clock_skew() {
+        let validation = TokenValidation::default();
+        let token = claims(json!({ "exp": 1_000 }));
+
+        assert_eq!(validate_claims(&token, &validation, 1_000), Ok(()));
+        assert_eq!(validate_claims(&token, &validation, 1_060), Ok(()));
+        assert_eq!(validate_claims(&token, &validation, 1_061), Err(ClaimError::Expired));
+        assert_eq!(
+            validate_claims(&token, &(TokenValidation { clock_skew_secs: 0, ..validation }), 1_001),
+            Err(ClaimError::Expired)
+        );
+    }
+
+    #[test]
+    fn requires_expiration_unless_disabled() {
+        let token = claims(json!({}));
+
+        assert_eq!(validate_claims(&token, &TokenValidation::default(), 1_000), Err(ClaimError::MissingExpiration));
+        assert_eq!(
+            validate_claims(&token, &(TokenValidation { require_exp: false, ..TokenValidation::default() }), 1_000),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_tokens_not_yet_valid() {
+        let validation = TokenValidation::default();
+
+        assert_eq!(
+            validate_claims(&claims(json!({ "exp": 5_000, "nbf": 2_000 })), &validation, 1_000),
+            Err(ClaimError::NotYetValid)
+        );
+        assert_eq!(validate_claims(&claims(json!({ "exp": 5_000, "nbf": 1_050 })), &validation, 1_000), Ok(()));
+        // Issued in the future
+        assert_eq!(
+            validate_claims(&claims(json!({ "exp": 5_000, "iat": 2_000 })), &validation, 1_000),
+            Err(ClaimError::NotYetValid)
+        );
+    }
+
+    #[test]
+    fn matches_audience_against_configuration() {
+        let validation = TokenValidation {
+            audiences: vec!["acme-router".to_string(), "acme-api".to_string()],
+            ..TokenValidation::default()
+        };
+
+        assert_eq!(validate_claims(&claims(json!({ "exp": 5_000, "aud": "acme-api" })), &validation, 1_000), Ok(()));
+        assert_eq!(
+            validate_claims(&claims(json!({ "exp": 5_000, "aud": ["other", "acme-router"] })), &validation, 1_000),
+            Ok(())
+        );
+        assert_eq!(
+            validate_claims(&claims(json!({ "exp": 5_000, "aud": "other" })), &validation, 1_000),
+            Err(ClaimError::InvalidAudience)
+        );
+        assert_eq!(
+            validate_claims(&claims(json!({ "exp": 5_000 })), &validation, 1_000),
+            Err(ClaimError::InvalidAudience)
+        );
+        assert_eq!(
+            validate_claims(&claims(json!({ "exp": 5_000, "aud": "other" })), &TokenValidation::default(), 1_000),
+            Ok(())
+        );
+    }
+}
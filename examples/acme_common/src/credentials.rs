@@ -0,0 +1,240 @@
+use http::header::COOKIE;
+use http::HeaderName;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+// A place the credential, a token or a client id, can be read from
+#[derive(Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum CredentialSource {
+    // `prefix` is an authorization scheme like `Bearer`, matched case insensitively.
+    // Values with another scheme are skipped, so the next location is tried
+    Header {
+        name: String,
+        prefix: Option<String>,
+    },
+    Cookie {
+        name: String,
+    },
+    // For WebSocket upgrades, browsers can't set headers on them. The query string usually
+    // ends up in access logs, so only short lived tokens or ids that aren't secret should be sent this way
+    Query {
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CredentialError {
+    Missing,
+    // Present but not readable as a string
+    Invalid,
+}
+
+enum Location {
+    Header {
+        name: HeaderName,
+        prefix: Option<String>,
+    },
+    Cookie(String),
+    Query(String),
+}
+
+// Reads the credential from the first configured location the request has
+pub struct CredentialExtractor {
+    locations: Vec<Location>,
+}
+
+impl CredentialExtractor {
+    // `header` is the shorthand for a single header read as is
+    pub fn new(header: Option<&str>, sources: &[CredentialSource]) -> Result<CredentialExtractor, String> {
+        let sources = match (header, sources) {
+            (Some(_header), [_first, ..]) => {
+                return Err("set either `header` or `credentials`, not both".to_string());
+            }
+            (Some(header), []) => vec![CredentialSource::Header { name: header.to_string(), prefix: None }],
+            (None, []) => {
+                return Err("`header` or `credentials` is required".to_string());
+            }
+            (None, sources) => sources.to_vec(),
+        };
+
+        let locations = sources
+            .into_iter()
+            .map(|source| {
+                match source {
+                    CredentialSource::Header { name, prefix } => {
+                        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_err|
+                            format!("{:?} is not a valid header name", name)
+                        )?;
+                        if prefix.as_ref().map(|prefix| prefix.trim().is_empty()).unwrap_or(false) {
+                            return Err(format!("the prefix of the {:?} header is empty", name.as_str()));
+                        }
+                        Ok(Location::Header { name, prefix: prefix.map(|prefix| prefix.trim().to_string()) })
+                    }
+                    CredentialSource::Cookie { name } => Ok(Location::Cookie(non_empty("cookie", name)?)),
+                    CredentialSource::Query { name } => Ok(Location::Query(non_empty("query parameter", name)?)),
+                }
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(CredentialExtractor { locations })
+    }
+
+    pub fn extract<T>(&self, req: &http::Request<T>) -> Result<String, CredentialError> {
+        for location in &self.locations {
+            if let Some(credential) = location.read(req)? {
+                return Ok(credential);
+            }
+        }
+        Err(CredentialError::Missing)
+    }
+
+    // Where the credential was expected, for error messages
+    pub fn describe(&self) -> String {
+        self.locations
+            .iter()
+            .map(|location| {
+                match location {
+                    Location::Header { name, .. } => format!("'{}' header", name),
+                    Location::Cookie(name) => format!("'{}' cookie", name),
+                    Location::Query(name) => format!("'{}' query parameter", name),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" or ")
+    }
+}
+
+impl Location {
+    // Empty values count as missing
+    fn read<T>(&self, req: &http::Request<T>) -> Result<Option<String>, CredentialError> {
+        let credential = match self {
+            Location::Header { name, prefix } => {
+                let mut found = None;
+                for value in req.headers().get_all(name) {
+                    let value = value.to_str().map_err(|_err| CredentialError::Invalid)?;
+                    found = match prefix {
+                        Some(prefix) => strip_scheme(value, prefix),
+                        None => Some(value.trim()),
+                    };
+                    if found.is_some() {
+                        break;
+                    }
+                }
+                found.map(str::to_string)
+            }
+            Location::Cookie(cookie) =>
+                req
+                    .headers()
+                    .get_all(COOKIE)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(';'))
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(name, _value)| name == cookie)
+                    .map(|(_name, value)| value.trim_matches('"').to_string()),
+            Location::Query(parameter) =>
+                req
+                    .uri()
+                    .query()
+                    .and_then(|query| {
+                        url::form_urlencoded
+                            ::parse(query.as_bytes())
+                            .find(|(name, _value)| name == parameter)
+                            .map(|(_name, value)| value.into_owned())
+                    }),
+        };
+
+        Ok(credential.filter(|credential| !credential.is_empty()))
+    }
+}
+
+// `Bearer abc` gives `abc` for the `Bearer` scheme
+fn strip_scheme<'a>(value: &'a str, scheme: &str) -> Option<&'a str> {
+    let (value_scheme, credential) = value.trim().split_once(' ')?;
+    if value_scheme.eq_ignore_ascii_case(scheme) { Some(credential.trim()) } else { None }
+}
+
+fn non_empty(kind: &str, name: String) -> Result<String, String> {
+    if name.trim().is_empty() {
+        return Err(format!("a credential {} has an empty name", kind));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extractor(sources: serde_json::Value) -> CredentialExtractor {
+        let sources: Vec<CredentialSource> = serde_json::from_value(sources).unwrap();
+        CredentialExtractor::new(None, &sources).unwrap()
+    }
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> http::Request<()> {
+        let mut builder = http::Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn strips_the_configured_scheme() {
+        let credentials = extractor(serde_json::json!([{ "header": { "name": "Authorization", "prefix": "Bearer" } }]));
+
+        let bearer = request("/", &[("authorization", "bearer  abc.def ")]);
+        assert_eq!(credentials.extract(&bearer), Ok("abc.def".to_string()));
+
+        let basic = request("/", &[("authorization", "Basic dXNlcg==")]);
+        assert_eq!(credentials.extract(&basic), Err(CredentialError::Missing));
+    }
+
+    #[test]
+    fn falls_back_in_order() {
+        let credentials = extractor(
+            serde_json::json!([
+                { "header": { "name": "Authorization", "prefix": "Bearer" } },
+                { "cookie": { "name": "session" } },
+                { "query": { "name": "access_token" } }
+            ])
+        );
+
+        let all = request("/graphql?access_token=from-query", &[("cookie", "theme=dark; session=from-cookie")]);
+        assert_eq!(credentials.extract(&all), Ok("from-cookie".to_string()));
+
+        let query = request("/graphql?a=1&access_token=from%20query", &[("cookie", "theme=dark")]);
+        assert_eq!(credentials.extract(&query), Ok("from query".to_string()));
+
+        let empty = request("/graphql?access_token=", &[("authorization", "Bearer ")]);
+        assert_eq!(credentials.extract(&empty), Err(CredentialError::Missing));
+    }
+
+    #[test]
+    fn describes_every_location() {
+        let credentials = extractor(
+            serde_json::json!([{ "header": { "name": "x-client-id" } }, { "query": { "name": "client_id" } }])
+        );
+
+        assert_eq!(credentials.describe(), "'x-client-id' header or 'client_id' query parameter");
+    }
+
+    #[test]
+    fn unreadable_headers_are_invalid() {
+        let credentials = CredentialExtractor::new(Some("x-token"), &[]).unwrap();
+        let mut req = request("/", &[]);
+        req.headers_mut().insert("x-token", http::HeaderValue::from_bytes(b"\xff\xfe").unwrap());
+
+        assert_eq!(credentials.extract(&req), Err(CredentialError::Invalid));
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        let header = [CredentialSource::Header { name: "Authorization".to_string(), prefix: None }];
+
+        assert!(CredentialExtractor::new(Some("Authorization"), &header).is_err());
+        assert!(CredentialExtractor::new(None, &[]).is_err());
+        assert!(CredentialExtractor::new(Some("Authorization header"), &[]).is_err());
+        assert!(CredentialExtractor::new(None, &[CredentialSource::Cookie { name: " ".to_string() }]).is_err());
+    }
+}
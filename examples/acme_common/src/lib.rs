@@ -0,0 +1,7 @@
+// Building blocks shared by the example routers
+pub mod api_keys;
+pub mod claims;
+pub mod credentials;
+pub mod messages;
+pub mod shadow;
+pub mod watched_file;
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use http::header::ACCEPT_LANGUAGE;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+// Locale of the catalogs bundled with every router, the last fallback for missing keys
+pub const FALLBACK_LOCALE: &str = "es";
+
+fn default_locale() -> String {
+    FALLBACK_LOCALE.to_string()
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MessagesConfig {
+    // Used when `Accept-Language` is missing or doesn't match any catalog
+    #[serde(default = "default_locale")]
+    pub default_locale: String,
+    // JSON files mapping message keys to templates, by locale. They add new locales
+    // or override messages of the bundled `es` and `en` catalogs
+    #[serde(default)]
+    pub catalogs: HashMap<String, PathBuf>,
+}
+
+impl Default for MessagesConfig {
+    fn default() -> Self {
+        Self { default_locale: default_locale(), catalogs: HashMap::new() }
+    }
+}
+
+// Message templates by locale and key. Templates take named arguments like `{field}`
+#[derive(Debug, Clone)]
+pub struct MessageCatalog {
+    default_locale: String,
+    locales: HashMap<String, HashMap<String, String>>,
+}
+
+impl MessageCatalog {
+    // Catalogs compiled into a router as `(locale, JSON)` pairs, one of them in the fallback locale
+    pub fn bundled(catalogs: &[(&str, &str)]) -> MessageCatalog {
+        let locales: HashMap<String, HashMap<String, String>> = catalogs
+            .iter()
+            .map(|(locale, content)| {
+                let messages = serde_json::from_str(content).expect("bundled catalogs are valid");
+                (locale.to_string(), messages)
+            })
+            .collect();
+        assert!(locales.contains_key(FALLBACK_LOCALE), "bundled catalogs include '{}'", FALLBACK_LOCALE);
+
+        MessageCatalog { default_locale: default_locale(), locales }
+    }
+
+    // A copy of these catalogs with the configured default locale and extra catalogs
+    pub fn with_config(&self, config: &MessagesConfig) -> Result<MessageCatalog, String> {
+        let mut catalog = self.clone();
+        catalog.default_locale = config.default_locale.to_lowercase();
+
+        for (locale, path) in &config.catalogs {
+            let content = std::fs::read_to_string(path).map_err(|err| format!("could not read {:?}: {}", path, err))?;
+            let messages: HashMap<String, String> = serde_json
+                ::from_str(&content)
+                .map_err(|err| format!("invalid message catalog {:?}: {}", path, err))?;
+
+            // A typo in a key would silently fall back to the default locale
+            if let Some(key) = messages.keys().find(|key| !self.locales[FALLBACK_LOCALE].contains_key(*key)) {
+                return Err(format!("unknown message key '{}' in {:?}", key, path));
+            }
+            catalog.locales.entry(locale.to_lowercase()).or_default().extend(messages);
+        }
+
+        if !catalog.locales.contains_key(&catalog.default_locale) {
+            return Err(format!("there is no message catalog for the default locale '{}'", config.default_locale));
+        }
+
+        Ok(catalog)
+    }
+
+    pub fn default_locale(&self) -> &str {
+        &self.default_locale
+    }
+
+    // Sorted, to compare the keys catalogs define
+    pub fn keys(&self, locale: &str) -> Vec<&str> {
+        let mut keys: Vec<&str> = self.locales
+            .get(locale)
+            .map(|messages| messages.keys().map(String::as_str).collect())
+            .unwrap_or_default();
+        keys.sort();
+        keys
+    }
+
+    // Best locale for an `Accept-Language` value, e.g. `en-US,en;q=0.9,es;q=0.8`
+    pub fn negotiate(&self, accept_language: Option<&str>) -> &str {
+        let mut ranges: Vec<(&str, f32)> = accept_language
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map(|quality| quality.parse().unwrap_or(0.0))
+                    .unwrap_or(1.0);
+                (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+            })
+            .collect();
+        // Stable, so ranges with the same quality keep the client's order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        for (tag, _quality) in ranges {
+            let tag = tag.to_lowercase();
+            let primary = tag.split('-').next().unwrap_or_default();
+
+            for candidate in [tag.as_str(), primary] {
+                if let Some((locale, _messages)) = self.locales.get_key_value(candidate) {
+                    return locale;
+                }
+            }
+        }
+
+        &self.default_locale
+    }
+
+    pub fn request_locale<T>(&self, req: &http::Request<T>) -> &str {
+        let accept_language = req
+            .headers()
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok());
+
+        self.negotiate(accept_language)
+    }
+
+    // Falls back to the default locale, then to Spanish, for keys a catalog doesn't define
+    pub fn message(&self, locale: &str, key: &str, args: &[(&str, &str)]) -> String {
+        let template = [locale, self.default_locale.as_str(), FALLBACK_LOCALE]
+            .iter()
+            .find_map(|locale| self.locales.get(*locale).and_then(|messages| messages.get(key)))
+            .map(String::as_str)
+            .unwrap_or(key);
+
+        // In a single pass, a value containing `{name}` isn't expanded by the next argument
+        let mut message = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            message.push_str(&rest[..start]);
+            let placeholder = &rest[start + 1..];
+            let argument = placeholder.find('}').and_then(|end| {
+                args.iter()
+                    .find(|(name, _value)| *name == &placeholder[..end])
+                    .map(|(_name, value)| (end, *value))
+            });
+
+            match argument {
+                Some((end, value)) => {
+                    message.push_str(value);
+                    rest = &placeholder[end + 1..];
+                }
+                None => {
+                    message.push('{');
+                    rest = placeholder;
+                }
+            }
+        }
+        message.push_str(rest);
+
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUNDLED: [(&str, &str); 2] = [
+        ("es", r#"{ "NOT_REGISTERED": "Aplicación no registrada", "DENIED": "No puedes consultar '{field}'" }"#),
+        ("en", r#"{ "NOT_REGISTERED": "Application not registered", "DENIED": "You can't query '{field}'" }"#),
+    ];
+
+    fn catalog_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("messages-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn negotiates_accept_language() {
+        let catalog = MessageCatalog::bundled(&BUNDLED);
+
+        assert_eq!(catalog.negotiate(None), "es");
+        assert_eq!(catalog.negotiate(Some("en")), "en");
+        assert_eq!(catalog.negotiate(Some("en-US,en;q=0.9")), "en");
+        assert_eq!(catalog.negotiate(Some("fr-FR, en;q=0.5, es;q=0.8")), "es");
+        assert_eq!(catalog.negotiate(Some("EN-gb")), "en");
+        assert_eq!(catalog.negotiate(Some("fr, en;q=0")), "es");
+        assert_eq!(catalog.negotiate(Some("*")), "es");
+    }
+
+    #[test]
+    fn renders_templates_with_arguments() {
+        let catalog = MessageCatalog::bundled(&BUNDLED);
+
+        assert_eq!(catalog.message("en", "DENIED", &[("field", "product.costPrice")]), "You can't query 'product.costPrice'");
+        assert_eq!(catalog.message("fr", "NOT_REGISTERED", &[]), "Aplicación no registrada");
+        assert_eq!(catalog.message("en", "NOT_A_KEY", &[]), "NOT_A_KEY");
+    }
+
+    #[test]
+    fn does_not_expand_placeholders_in_arguments() {
+        let catalog = MessageCatalog::bundled(&BUNDLED);
+
+        assert_eq!(
+            catalog.message("en", "DENIED", &[("field", "{other}"), ("other", "secret")]),
+            "You can't query '{other}'"
+        );
+        assert_eq!(catalog.message("en", "DENIED", &[]), "You can't query '{field}'");
+    }
+
+    #[test]
+    fn loads_extra_catalogs_from_files() {
+        let french = catalog_file("fr", r#"{ "NOT_REGISTERED": "Application non enregistrée" }"#);
+        let english = catalog_file("en", r#"{ "DENIED": "Not allowed: {field}" }"#);
+        let config = MessagesConfig {
+            default_locale: "fr".to_string(),
+            catalogs: HashMap::from([("fr".to_string(), french), ("en".to_string(), english)]),
+        };
+        let catalog = MessageCatalog::bundled(&BUNDLED).with_config(&config).unwrap();
+
+        assert_eq!(catalog.negotiate(Some("de")), "fr");
+        assert_eq!(catalog.message("fr", "NOT_REGISTERED", &[]), "Application non enregistrée");
+        // Missing in the French catalog, so it comes from Spanish
+        assert_eq!(catalog.message("fr", "DENIED", &[("field", "id")]), "No puedes consultar 'id'");
+        assert_eq!(catalog.message("en", "DENIED", &[("field", "id")]), "Not allowed: id");
+        assert_eq!(catalog.message("en", "NOT_REGISTERED", &[]), "Application not registered");
+    }
+
+    #[test]
+    fn rejects_invalid_catalogs() {
+        let bundled = MessageCatalog::bundled(&BUNDLED);
+        let typo = catalog_file("typo", r#"{ "NOT_REGISTRED": "..." }"#);
+        let config = MessagesConfig { default_locale: "es".to_string(), catalogs: HashMap::from([("fr".to_string(), typo)]) };
+        assert!(bundled.with_config(&config).is_err());
+
+        let config = MessagesConfig { default_locale: "de".to_string(), catalogs: HashMap::new() };
+        assert!(bundled.with_config(&config).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+
+#[derive(Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnforcementMode {
+    #[default]
+    Enforce,
+    // Every check runs but nothing is rejected, redacted or filtered. Decisions are logged,
+    // counted and set in the request context so they can be compared before enforcing
+    Shadow,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShadowDecision {
+    pub allowed: bool,
+    // Extension code and message of the error the request would have been rejected with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    // Nested fields `on_denied_field: field_error` would have redacted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redacted_fields: Vec<String>,
+}
+
+impl ShadowDecision {
+    pub fn allowed(redacted_fields: Vec<String>) -> ShadowDecision {
+        ShadowDecision { allowed: true, code: None, reason: None, redacted_fields }
+    }
+
+    pub fn denied(code: &str, reason: String) -> ShadowDecision {
+        ShadowDecision { allowed: false, code: Some(code.to_string()), reason: Some(reason), redacted_fields: Vec::new() }
+    }
+
+    // `plugin` is the plugin's name in router.yaml and `stage` is `supergraph` or the name of the
+    // subgraph the decision was made for. The reason is only logged, it would give the metric
+    // too many attributes
+    pub fn record(&self, plugin: &str, stage: &str) {
+        let code = self.code.as_deref().unwrap_or_default();
+        tracing::info!(
+            monotonic_counter.shadow_decisions = 1u64,
+            plugin = plugin,
+            stage = stage,
+            allowed = self.allowed,
+            code = code
+        );
+        if !self.allowed {
+            tracing::warn!(plugin = plugin, stage = stage, code = code, reason = ?self.reason, "{} would reject the request", plugin);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denied_decisions_carry_the_error() {
+        let decision = ShadowDecision::denied("UNAUTHORIZED", "no".to_string());
+
+        assert_eq!(
+            serde_json::to_value(&decision).unwrap(),
+            serde_json::json!({ "allowed": false, "code": "UNAUTHORIZED", "reason": "no" })
+        );
+    }
+
+    #[test]
+    fn allowed_decisions_serialize_without_an_error() {
+        let decision = ShadowDecision::allowed(Vec::new());
+
+        assert_eq!(serde_json::to_value(&decision).unwrap(), serde_json::json!({ "allowed": true }));
+    }
+
+    #[test]
+    fn enforces_by_default() {
+        assert_eq!(EnforcementMode::default(), EnforcementMode::Enforce);
+        assert_eq!(serde_json::from_str::<EnforcementMode>("\"shadow\"").unwrap(), EnforcementMode::Shadow);
+    }
+}
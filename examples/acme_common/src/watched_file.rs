@@ -0,0 +1,181 @@
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, RwLock, Weak };
+use std::time::Duration;
+
+use notify::{ RecommendedWatcher, RecursiveMode, Watcher };
+use tokio::sync::mpsc;
+
+type Parser<T> = Box<dyn Fn(&str) -> Result<T, String> + Send + Sync>;
+
+// Several events usually fire for a single save, a reload waits until none came for this long
+pub const RELOAD_DEBOUNCE: Duration = Duration::from_millis(250);
+
+// Parsed contents of a file kept in memory and swapped atomically when the file changes on disk.
+// A change that can't be read or parsed is logged and the last good version keeps serving.
+pub struct WatchedFile<T> {
+    path: PathBuf,
+    parse: Parser<T>,
+    current: RwLock<Arc<T>>,
+    // Dropping the watcher closes the channel and ends the reload task
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl<T: Send + Sync + 'static> WatchedFile<T> {
+    // The first load has to succeed, later failures only keep the previous version
+    pub fn load<F>(path: &Path, debounce: Duration, parse: F) -> Result<Arc<Self>, String>
+        where F: Fn(&str) -> Result<T, String> + Send + Sync + 'static
+    {
+        let parse: Parser<T> = Box::new(parse);
+        let current = read(path, &parse)?;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let watcher = match watch(path, sender) {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                tracing::warn!("changes to {:?} won't be picked up until the router restarts: {}", path, err);
+                None
+            }
+        };
+
+        let file = Arc::new(Self {
+            path: path.to_path_buf(),
+            parse,
+            current: RwLock::new(Arc::new(current)),
+            _watcher: watcher,
+        });
+        spawn_reload(Arc::downgrade(&file), receiver, debounce);
+
+        Ok(file)
+    }
+
+    pub fn current(&self) -> Arc<T> {
+        self.current.read().expect("watched file lock poisoned").clone()
+    }
+
+    pub fn reload(&self) -> Result<(), String> {
+        let current = read(&self.path, &self.parse)?;
+        *self.current.write().expect("watched file lock poisoned") = Arc::new(current);
+
+        Ok(())
+    }
+}
+
+fn read<T>(path: &Path, parse: &Parser<T>) -> Result<T, String> {
+    let content = std::fs::read_to_string(path).map_err(|err| format!("could not read {:?}: {}", path, err))?;
+
+    parse(&content).map_err(|err| format!("invalid {:?}: {}", path, err))
+}
+
+// Editors usually replace the file instead of writing it in place, so the whole directory is watched
+fn watch(path: &Path, sender: mpsc::UnboundedSender<()>) -> notify::Result<RecommendedWatcher> {
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if event.kind.is_access() {
+                return;
+            }
+            if event.paths.iter().any(|changed| changed.file_name().map(|name| name.to_os_string()) == file_name) {
+                let _ = sender.send(());
+            }
+        }
+    })?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+
+    Ok(watcher)
+}
+
+fn spawn_reload<T: Send + Sync + 'static>(
+    file: Weak<WatchedFile<T>>,
+    mut receiver: mpsc::UnboundedReceiver<()>,
+    debounce: Duration
+) {
+    tokio::spawn(async move {
+        while receiver.recv().await.is_some() {
+            while let Ok(Some(())) = tokio::time::timeout(debounce, receiver.recv()).await {}
+
+            let Some(file) = file.upgrade() else {
+                break;
+            };
+            match file.reload() {
+                Ok(()) => tracing::info!("reloaded {:?}", file.path),
+                Err(err) => tracing::warn!("keeping the previous version of {:?}: {}", file.path, err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    use super::*;
+
+    static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+    fn temp_file(content: &str) -> PathBuf {
+        let directory = std::env
+            ::temp_dir()
+            .join(format!("watched-file-{}-{}", std::process::id(), NEXT_FILE.fetch_add(1, Ordering::SeqCst)));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let path = directory.join("registry.json");
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn parse_ids(content: &str) -> Result<Vec<String>, String> {
+        serde_json::from_str(content).map_err(|err| err.to_string())
+    }
+
+    async fn wait_for(file: &WatchedFile<Vec<String>>, expected: &[&str]) -> bool {
+        for _ in 0..100 {
+            if file.current().as_slice() == expected {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn first_load_must_succeed() {
+        assert!(WatchedFile::load(Path::new("missing.json"), Duration::ZERO, parse_ids).is_err());
+        assert!(WatchedFile::load(&temp_file("not json"), Duration::ZERO, parse_ids).is_err());
+    }
+
+    #[tokio::test]
+    async fn reloads_when_the_file_changes() {
+        let path = temp_file(r#"["a"]"#);
+        let file = WatchedFile::load(&path, Duration::from_millis(20), parse_ids).unwrap();
+        assert_eq!(file.current().as_slice(), ["a"]);
+
+        std::fs::write(&path, r#"["a", "b"]"#).unwrap();
+        assert!(wait_for(&file, &["a", "b"]).await);
+
+        // Replaced through a rename, the way most editors save
+        let replacement = path.with_extension("tmp");
+        std::fs::write(&replacement, r#"["c"]"#).unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+        assert!(wait_for(&file, &["c"]).await);
+    }
+
+    #[tokio::test]
+    async fn keeps_last_good_version_on_bad_edits() {
+        let path = temp_file(r#"["a"]"#);
+        let file = WatchedFile::load(&path, Duration::from_millis(20), parse_ids).unwrap();
+
+        std::fs::write(&path, "[\"a\", ").unwrap();
+        assert!(file.reload().is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(file.reload().is_err());
+        assert_eq!(file.current().as_slice(), ["a"]);
+
+        std::fs::write(&path, r#"["b"]"#).unwrap();
+        assert!(wait_for(&file, &["b"]).await);
+    }
+}
@@ -1,32 +1,71 @@
+use std::collections::HashMap;
 use std::ops::ControlFlow;
 use std::path::PathBuf;
+use std::sync::Arc;
 
+use acme_common::credentials::CredentialExtractor;
+use acme_common::credentials::CredentialSource;
+use acme_common::watched_file::WatchedFile;
+use acme_common::watched_file::RELOAD_DEBOUNCE;
 use apollo_router::layers::ServiceBuilderExt;
 use apollo_router::plugin::PluginInit;
 use apollo_router::plugin::Plugin;
 use apollo_router::register_plugin;
 use apollo_router::services::supergraph;
-use http::StatusCode;
-use http::HeaderValue;
 use schemars::JsonSchema;
 use serde::Deserialize;
 use tower::BoxError;
 use tower::ServiceBuilder;
 use tower::ServiceExt;
 
+use acme_router::messages::load_catalog;
+use acme_router::messages::MessageCatalog;
+use acme_router::messages::MessagesConfig;
 use acme_router::plugin_functions::validate_operation;
-use acme_router::plugin_functions::error_response;
+use acme_router::plugin_functions::auth_error_response;
+use acme_router::plugin_functions::insert_header;
+use acme_router::plugin_functions::AuthError;
 use acme_router::plugin_functions::get_payload;
+use acme_router::plugin_functions::get_operations_name;
+use acme_router::plugin_functions::verification_keys;
+use acme_router::plugin_functions::KeyConfig;
+use acme_router::plugin_functions::TokenValidation;
+use acme_router::plugin_functions::VerificationKey;
+use acme_router::plugin_functions::parse_apps;
+use acme_router::shadow::denied_decision;
+use acme_router::shadow::EnforcementMode;
+use acme_router::shadow::ShadowDecision;
+use acme_router::shadow::SHADOW_DECISION_CONTEXT_KEY;
+use acme_router::AppConfig;
+
+const PLUGIN_NAME: &str = "apps.allow_app";
 
 #[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 struct AllowAppConfig {
-    header: String,
+    // `shadow` only records what `enforce` would do, to try a configuration on real traffic
+    #[serde(default)]
+    mode: EnforcementMode,
+    // A single header read as is, the shorthand for `credentials`
+    header: Option<String>,
+    // Locations the token is read from, the first one the request has is used
+    #[serde(default)]
+    credentials: Vec<CredentialSource>,
     path: String,
+    keys: Vec<KeyConfig>,
+    #[serde(default)]
+    token_validation: TokenValidation,
+    #[serde(default)]
+    messages: MessagesConfig,
 }
 
 struct AllowApp {
-    header: String,
-    file_path: PathBuf,
+    mode: EnforcementMode,
+    credentials: Arc<CredentialExtractor>,
+    apps: Arc<WatchedFile<HashMap<String, AppConfig>>>,
+    keys: Vec<VerificationKey>,
+    token_validation: TokenValidation,
+    messages: Arc<MessageCatalog>,
 }
 
 #[async_trait::async_trait]
@@ -34,115 +73,57 @@ impl Plugin for AllowApp {
     type Config = AllowAppConfig;
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
-        let AllowAppConfig { path, header } = init.config;
+        let AllowAppConfig { mode, path, header, credentials, keys, token_validation, messages } = init.config;
         let file_path = PathBuf::from(path.as_str());
 
+        // Everything is checked here so the router refuses to start with a bad configuration
+        // instead of failing on the first request
+        let credentials = CredentialExtractor::new(header.as_deref(), &credentials).map_err(|err|
+            format!("apps.allow_app: {}", err)
+        )?;
+        if keys.is_empty() {
+            return Err("apps.allow_app needs at least one entry in `keys` to verify tokens".into());
+        }
+        let apps = WatchedFile::load(&file_path, RELOAD_DEBOUNCE, parse_apps).map_err(|err|
+            format!("apps.allow_app: {}", err)
+        )?;
+        let keys = verification_keys(&keys).map_err(|err| format!("apps.allow_app: {}", err))?;
+        let messages = load_catalog(&messages).map_err(|err| format!("apps.allow_app: {}", err))?;
+
         Ok(Self {
-            file_path,
-            header,
+            mode,
+            apps,
+            credentials: Arc::new(credentials),
+            keys,
+            token_validation,
+            messages: Arc::new(messages),
         })
     }
 
     fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
-        let header_key = self.header.clone();
-        let file_path = self.file_path.clone();
+        let mode = self.mode;
+        let credentials = self.credentials.clone();
+        let apps = self.apps.clone();
+        let keys = self.keys.clone();
+        let token_validation = self.token_validation.clone();
+        let messages = self.messages.clone();
 
         let handler = move |mut req: supergraph::Request| {
-            let mut res = None;
-            //Get query from the body
-            let query = &req.supergraph_request.body().query;
-
-            match query {
-                Some(query_string) => {
-                    // First it is checked if the request has the Authorization header
-                    if !req.supergraph_request.headers().contains_key(&header_key) {
-                        res = error_response(
-                            "No se ha recibido el encabezado de autorización",
-                            StatusCode::UNAUTHORIZED,
-                            "AUTH_ERROR",
-                            &req
-                        );
-                    } else {
-                        // Get token from the Authorization header
-                        let token = req.supergraph_request
-                            .headers()
-                            .get("Authorization")
-                            .expect("No se pudo extraer el token de la petición")
-                            .to_str();
-
-                        match token {
-                            Ok(token) => {
-                                //Get token Payload
-                                match get_payload(&token) {
-                                    Ok(token_payload) => {
-                                        // Validate query to execute
-                                        let validated_app = validate_operation(
-                                            &token_payload.iss,
-                                            query_string,
-                                            file_path.clone()
-                                        );
-                                        match validated_app {
-                                            Ok(app) => {
-                                                req.supergraph_request
-                                                    .headers_mut()
-                                                    .insert(
-                                                        "app_name",
-                                                        HeaderValue::from_str(&app.nombre).unwrap()
-                                                    );
-
-                                                req.supergraph_request
-                                                    .headers_mut()
-                                                    .insert(
-                                                        "user_id",
-                                                        HeaderValue::from_str(
-                                                            &token_payload._id
-                                                        ).unwrap()
-                                                    );
-                                            }
-                                            Err(err) => {
-                                                res = error_response(
-                                                    err,
-                                                    StatusCode::UNAUTHORIZED,
-                                                    "UNAUTHORIZED",
-                                                    &req
-                                                );
-                                            }
-                                        }
-                                    }
-                                    Err(_err) => {
-                                        let error_message = format!("Token de acceso no válido: {}", _err);
-                                        res = error_response(
-                                            &error_message,
-                                            StatusCode::UNAUTHORIZED,
-                                            "UNAUTHORIZED",
-                                            &req
-                                        );
-                                    }
-                                }
-                            }
-                            Err(_err) => {
-                                res = error_response(
-                                    "Error al validar access Token",
-                                    StatusCode::UNAUTHORIZED,
-                                    "UNAUTHORIZED",
-                                    &req
-                                );
-                            }
-                        }
-                    }
-                }
-                None => {
-                    res = error_response(
-                        "Query is not present",
-                        StatusCode::BAD_REQUEST,
-                        "GRAPHQL_ERROR",
-                        &req
-                    );
+            let result = authorize(&mut req, &credentials, &keys, &token_validation, &apps.current());
+            let messages = messages.clone();
+
+            async move {
+                if mode == EnforcementMode::Shadow {
+                    let decision = match &result {
+                        Ok(()) => ShadowDecision::allowed(Vec::new()),
+                        Err(err) => denied_decision(err, &messages),
+                    };
+                    decision.record(PLUGIN_NAME, "supergraph");
+                    let _ = req.context.insert(SHADOW_DECISION_CONTEXT_KEY, decision);
+                    return Ok(ControlFlow::Continue(req));
                 }
-            }
 
-            async {
-                match res {
+                match result.err().and_then(|err| auth_error_response(&err, &messages, &req)) {
                     Some(res) => Ok(ControlFlow::Break(res)),
                     None => Ok(ControlFlow::Continue(req)),
                 }
@@ -153,4 +134,89 @@ impl Plugin for AllowApp {
     }
 }
 
+fn authorize(
+    req: &mut supergraph::Request,
+    credentials: &CredentialExtractor,
+    keys: &[VerificationKey],
+    token_validation: &TokenValidation,
+    apps: &HashMap<String, AppConfig>
+) -> Result<(), AuthError> {
+    //Get query from the body
+    let query_string = req.supergraph_request.body().query.clone().ok_or(AuthError::MissingQuery)?;
+
+    let token = credentials.extract(&req.supergraph_request)?;
+
+    let token_payload = get_payload(&token, keys, token_validation).map_err(AuthError::Token)?;
+
+    // Get the root fields of the operation to execute
+    let operation_name = req.supergraph_request.body().operation_name.as_deref();
+    let operations = get_operations_name(&query_string, operation_name).map_err(AuthError::InvalidOperation)?;
+
+    // Validate query to execute
+    let app = validate_operation(&token_payload.iss, &operations, apps)?;
+
+    insert_header(req, "app_name", &app.nombre)?;
+    insert_header(req, "user_id", &token_payload._id)?;
+
+    Ok(())
+}
+
 register_plugin!("apps", "allow_app", AllowApp);
+
+#[cfg(test)]
+mod tests {
+    use apollo_router::plugin::test;
+    use jsonwebtoken::{ encode, EncodingKey, Header };
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &str = "dev-secret";
+
+    async fn plugin(mode: &str) -> AllowApp {
+        let config = json!({
+            "mode": mode,
+            "header": "Authorization",
+            "path": "allowedApps.json",
+            "keys": [{ "algorithm": "HS256", "key": SECRET }],
+        });
+        let init = PluginInit::fake_builder().config(serde_json::from_value(config).unwrap()).build();
+        AllowApp::new(init).await.unwrap()
+    }
+
+    fn request(query: &str) -> supergraph::Request {
+        let exp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 3600;
+        let token = json!({ "_id": "user-1", "iss": "1234", "exp": exp });
+        let token = encode(&Header::default(), &token, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap();
+        supergraph::Request::fake_builder().query(query).header("Authorization", token).build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn shadow_mode_records_decisions_without_rejecting() {
+        let plugin = plugin("shadow").await;
+
+        for (query, allowed) in [("{ product { id } }", true), ("{ notAllowed }", false)] {
+            let mut mock = test::MockSupergraphService::new();
+            mock.expect_call()
+                .times(1)
+                .returning(|req| Ok(supergraph::Response::fake_builder().context(req.context).build().unwrap()));
+
+            let response = plugin.supergraph_service(mock.boxed()).oneshot(request(query)).await.unwrap();
+            let decision = response.context.get::<_, ShadowDecision>(SHADOW_DECISION_CONTEXT_KEY).unwrap().unwrap();
+            assert_eq!(decision.allowed, allowed);
+            if !allowed {
+                assert_eq!(decision.code.as_deref(), Some(AuthError::OperationNotAllowed.extension_code()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn enforce_mode_rejects() {
+        let plugin = plugin("enforce").await;
+
+        // The mock fails the test if the request gets through
+        let service = plugin.supergraph_service(test::MockSupergraphService::new().boxed());
+        let response = service.oneshot(request("{ notAllowed }")).await.unwrap();
+        assert!(response.context.get::<_, ShadowDecision>(SHADOW_DECISION_CONTEXT_KEY).unwrap().is_none());
+    }
+}
@@ -1,15 +1,21 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
 
 use apollo_router::graphql;
 use apollo_router::services::supergraph;
+use apollo_parser::{ cst, Parser };
 
-use base64::decode;
 use http::StatusCode;
+use http::HeaderValue;
+use jsonwebtoken::{ decode, decode_header, Algorithm, DecodingKey, Validation };
 use serde::Deserialize;
 use schemars::JsonSchema;
 
+pub mod messages;
+pub mod shadow;
+
 #[warn(dead_code)]
-#[derive(Deserialize, JsonSchema, Clone)]
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct AppConfig {
     id: String,
     pub nombre: String,
@@ -18,18 +24,315 @@ pub struct AppConfig {
 
 pub mod plugin_functions {
     use super::*;
+    use acme_common::claims::{ validate_claims, ClaimError, RegisteredClaims };
+    use acme_common::credentials::CredentialError;
+    use crate::messages::{ bundled_catalog, MessageCatalog };
+
+    pub use acme_common::claims::TokenValidation;
 
     #[warn(dead_code)]
     #[derive(Debug, serde::Deserialize, Clone)]
     pub struct Payload {
         pub _id: String,
         pub iss: String,
+        #[serde(flatten)]
+        pub registered: RegisteredClaims,
+    }
+
+    #[derive(Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+    pub enum KeyAlgorithm {
+        HS256,
+        RS256,
+        ES256,
+    }
+
+    #[derive(Deserialize, JsonSchema, Clone)]
+    #[serde(deny_unknown_fields)]
+    pub struct KeyConfig {
+        pub algorithm: KeyAlgorithm,
+        // Only used to select the key when the token header carries a `kid`
+        pub kid: Option<String>,
+        // Shared secret for HS256, PEM encoded public key for RS256 and ES256
+        pub key: String,
+    }
+
+    #[derive(Clone)]
+    pub struct VerificationKey {
+        pub kid: Option<String>,
+        pub algorithm: Algorithm,
+        pub key: DecodingKey,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum TokenError {
+        Malformed,
+        InvalidSignature,
+        MissingExpiration,
+        Expired,
+        NotYetValid,
+        InvalidAudience,
+    }
+
+    impl TokenError {
+        pub fn extension_code(&self) -> &'static str {
+            match self {
+                TokenError::Malformed | TokenError::MissingExpiration => "UNAUTHORIZED",
+                TokenError::InvalidSignature => "INVALID_TOKEN_SIGNATURE",
+                TokenError::Expired => "TOKEN_EXPIRED",
+                TokenError::NotYetValid => "TOKEN_NOT_YET_VALID",
+                TokenError::InvalidAudience => "INVALID_AUDIENCE",
+            }
+        }
+    }
+
+    impl TokenError {
+        pub fn message_key(&self) -> &'static str {
+            match self {
+                TokenError::Malformed => "TOKEN_MALFORMED",
+                TokenError::InvalidSignature => "TOKEN_INVALID_SIGNATURE",
+                TokenError::MissingExpiration => "TOKEN_MISSING_EXPIRATION",
+                TokenError::Expired => "TOKEN_EXPIRED",
+                TokenError::NotYetValid => "TOKEN_NOT_YET_VALID",
+                TokenError::InvalidAudience => "TOKEN_INVALID_AUDIENCE",
+            }
+        }
+    }
+
+    impl From<ClaimError> for TokenError {
+        fn from(err: ClaimError) -> TokenError {
+            match err {
+                ClaimError::MissingExpiration => TokenError::MissingExpiration,
+                ClaimError::Expired => TokenError::Expired,
+                ClaimError::NotYetValid => TokenError::NotYetValid,
+                ClaimError::InvalidAudience => TokenError::InvalidAudience,
+            }
+        }
+    }
+
+    impl std::fmt::Display for TokenError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let catalog = bundled_catalog();
+            write!(f, "{}", catalog.message(catalog.default_locale(), self.message_key(), &[]))
+        }
+    }
+
+    // Every way a request can fail to be authorized. Faults on our side map to 5xx so they
+    // aren't mistaken for a client sending bad credentials
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum AuthError {
+        MissingQuery,
+        MissingHeader,
+        InvalidHeader,
+        Token(TokenError),
+        AppNotRegistered,
+        InvalidOperation(OperationError),
+        OperationNotAllowed,
+        InvalidHeaderValue(&'static str),
+    }
+
+    impl AuthError {
+        pub fn status_code(&self) -> StatusCode {
+            match self {
+                AuthError::MissingQuery | AuthError::InvalidOperation(_) => StatusCode::BAD_REQUEST,
+                AuthError::InvalidHeaderValue(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::UNAUTHORIZED,
+            }
+        }
+
+        pub fn extension_code(&self) -> &'static str {
+            match self {
+                AuthError::MissingQuery => "GRAPHQL_ERROR",
+                AuthError::MissingHeader => "AUTH_ERROR",
+                AuthError::Token(err) => err.extension_code(),
+                AuthError::InvalidOperation(_) => "GRAPHQL_VALIDATION_FAILED",
+                AuthError::InvalidHeaderValue(_) => "INTERNAL_SERVER_ERROR",
+                _ => "UNAUTHORIZED",
+            }
+        }
+    }
+
+    impl AuthError {
+        pub fn message_key(&self) -> &'static str {
+            match self {
+                AuthError::MissingQuery => "MISSING_QUERY",
+                AuthError::MissingHeader => "MISSING_HEADER",
+                AuthError::InvalidHeader => "INVALID_HEADER",
+                AuthError::Token(_) => "INVALID_TOKEN",
+                AuthError::AppNotRegistered => "APP_NOT_REGISTERED",
+                AuthError::InvalidOperation(err) => err.message_key(),
+                AuthError::OperationNotAllowed => "OPERATION_NOT_ALLOWED",
+                // The value comes from the token or the registry, it isn't shown to the client
+                AuthError::InvalidHeaderValue(_) => "IDENTITY_UNAVAILABLE",
+            }
+        }
+
+        pub fn localized(&self, catalog: &MessageCatalog, locale: &str) -> String {
+            match self {
+                AuthError::Token(err) => {
+                    let reason = catalog.message(locale, err.message_key(), &[]);
+                    catalog.message(locale, self.message_key(), &[("reason", &reason)])
+                }
+                AuthError::InvalidOperation(err) => err.localized(catalog, locale),
+                _ => catalog.message(locale, self.message_key(), &[]),
+            }
+        }
+    }
+
+    impl std::fmt::Display for AuthError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let catalog = bundled_catalog();
+            write!(f, "{}", self.localized(catalog, catalog.default_locale()))
+        }
+    }
+
+    impl From<CredentialError> for AuthError {
+        fn from(err: CredentialError) -> Self {
+            match err {
+                CredentialError::Missing => AuthError::MissingHeader,
+                CredentialError::Invalid => AuthError::InvalidHeader,
+            }
+        }
     }
 
-    pub fn get_operation_name(query_string: &str) -> String {
-        let ops: Vec<&str> = query_string.split('{').collect();
-        let op1: Vec<&str> = ops[1].split('(').collect();
-        op1[0].replace(|c: char| !c.is_alphanumeric(), "")
+    // Documents we can't pick the root fields to authorize from
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum OperationError {
+        // The parser message, it isn't translated
+        Syntax(String),
+        UnknownOperation(String),
+        NoOperation,
+        OperationNameRequired,
+        UnknownFragment(String),
+        FragmentCycle(String),
+    }
+
+    impl OperationError {
+        pub fn message_key(&self) -> &'static str {
+            match self {
+                OperationError::Syntax(_) => "SYNTAX_ERROR",
+                OperationError::UnknownOperation(_) => "UNKNOWN_OPERATION",
+                OperationError::NoOperation => "NO_OPERATION",
+                OperationError::OperationNameRequired => "OPERATION_NAME_REQUIRED",
+                OperationError::UnknownFragment(_) => "UNKNOWN_FRAGMENT",
+                OperationError::FragmentCycle(_) => "FRAGMENT_CYCLE",
+            }
+        }
+
+        pub fn localized(&self, catalog: &MessageCatalog, locale: &str) -> String {
+            match self {
+                OperationError::Syntax(error) => catalog.message(locale, self.message_key(), &[("error", error)]),
+                OperationError::UnknownOperation(name) => {
+                    catalog.message(locale, self.message_key(), &[("operation", name)])
+                }
+                OperationError::UnknownFragment(name) | OperationError::FragmentCycle(name) => {
+                    catalog.message(locale, self.message_key(), &[("fragment", name)])
+                }
+                _ => catalog.message(locale, self.message_key(), &[]),
+            }
+        }
+    }
+
+    impl std::fmt::Display for OperationError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let catalog = bundled_catalog();
+            write!(f, "{}", self.localized(catalog, catalog.default_locale()))
+        }
+    }
+
+    // Root fields (by field name, not alias) of the operation the router will execute
+    pub fn get_operations_name(
+        query_string: &str,
+        operation_name: Option<&str>
+    ) -> Result<Vec<String>, OperationError> {
+        let parser = Parser::new(query_string);
+        let cst = parser.parse();
+
+        if let Some(err) = cst.errors().next() {
+            return Err(OperationError::Syntax(err.message().to_string()));
+        }
+
+        let doc = cst.document();
+        let mut operations = Vec::new();
+        let mut fragments = HashMap::new();
+
+        for def in doc.definitions() {
+            match def {
+                cst::Definition::OperationDefinition(op_def) => operations.push(op_def),
+                cst::Definition::FragmentDefinition(fragment) => {
+                    if let Some(name) = fragment.fragment_name().and_then(|name| name.name()) {
+                        fragments.insert(name.text().to_string(), fragment);
+                    }
+                }
+                _definition => {}
+            }
+        }
+
+        let op_def = match operation_name {
+            Some(operation_name) =>
+                operations
+                    .into_iter()
+                    .find(|op_def| op_def.name().map(|name| name.text() == operation_name).unwrap_or(false))
+                    .ok_or_else(|| OperationError::UnknownOperation(operation_name.to_string()))?,
+            None if operations.len() == 1 => operations.remove(0),
+            None if operations.is_empty() => {
+                return Err(OperationError::NoOperation);
+            }
+            None => {
+                return Err(OperationError::OperationNameRequired);
+            }
+        };
+
+        let mut fields = Vec::new();
+        if let Some(selection_set) = op_def.selection_set() {
+            root_fields(&selection_set, &fragments, &mut Vec::new(), &mut fields)?;
+        }
+
+        Ok(fields)
+    }
+
+    fn root_fields(
+        selection_set: &cst::SelectionSet,
+        fragments: &HashMap<String, cst::FragmentDefinition>,
+        visiting: &mut Vec<String>,
+        fields: &mut Vec<String>
+    ) -> Result<(), OperationError> {
+        for selection in selection_set.selections() {
+            match selection {
+                cst::Selection::Field(field) => {
+                    if let Some(name) = field.name() {
+                        // `__typename` doesn't reach any subgraph data
+                        if name.text() != "__typename" {
+                            fields.push(name.text().to_string());
+                        }
+                    }
+                }
+                cst::Selection::InlineFragment(fragment) => {
+                    if let Some(selection_set) = fragment.selection_set() {
+                        root_fields(&selection_set, fragments, visiting, fields)?;
+                    }
+                }
+                cst::Selection::FragmentSpread(spread) => {
+                    let name = spread
+                        .fragment_name()
+                        .and_then(|name| name.name())
+                        .map(|name| name.text().to_string())
+                        .unwrap_or_default();
+
+                    if visiting.contains(&name) {
+                        return Err(OperationError::FragmentCycle(name));
+                    }
+                    let fragment = fragments.get(&name).ok_or_else(|| OperationError::UnknownFragment(name.clone()))?;
+
+                    if let Some(selection_set) = fragment.selection_set() {
+                        visiting.push(name);
+                        root_fields(&selection_set, fragments, visiting, fields)?;
+                        visiting.pop();
+                    }
+                }
+            }
+        }
+
+        Ok(())
     }
 
     pub fn error_response(
@@ -38,7 +341,7 @@ pub mod plugin_functions {
         extension_code: &str,
         req: &supergraph::Request
     ) -> Option<supergraph::Response> {
-        return Some(
+        Some(
             supergraph::Response
                 ::error_builder()
                 .error(
@@ -52,58 +355,301 @@ pub mod plugin_functions {
                 .context(req.context.clone())
                 .build()
                 .expect("response is valid")
-        );
+        )
     }
 
-    pub fn validate_operation(
-        app_id: &str,
-        query_string: &str,
-        file_path: PathBuf
-    ) -> Result<AppConfig, &'static str> {
-        // Get query to execute
-        let operation_name = get_operation_name(query_string);
+    // In the language the client asked for through `Accept-Language`
+    pub fn auth_error_response(
+        err: &AuthError,
+        catalog: &MessageCatalog,
+        req: &supergraph::Request
+    ) -> Option<supergraph::Response> {
+        let message = err.localized(catalog, catalog.request_locale(&req.supergraph_request));
+        error_response(&message, err.status_code(), err.extension_code(), req)
+    }
 
-        let apps: Vec<AppConfig> = serde_json
-            ::from_str(std::fs::read_to_string(file_path).unwrap().as_str())
-            .unwrap();
+    // Registered apps indexed by `id`
+    pub fn parse_apps(content: &str) -> Result<HashMap<String, AppConfig>, String> {
+        let apps: Vec<AppConfig> = serde_json::from_str(content).map_err(|err| err.to_string())?;
 
-        if let Some(app) = apps.iter().find(|app| app.id == app_id) {
-            let query_is_allowed = app.queries.iter().any(|query| query == &operation_name);
+        let mut indexed = HashMap::new();
+        for app in apps {
+            if app.id.trim().is_empty() {
+                return Err("an app has an empty `id`".to_string());
+            }
+            // An app without queries could never run anything, it's most likely a mistake
+            if app.queries.is_empty() {
+                return Err(format!("app '{}' has no queries", app.id));
+            }
+            if indexed.contains_key(&app.id) {
+                return Err(format!("app '{}' is registered more than once", app.id));
+            }
+            indexed.insert(app.id.clone(), app);
+        }
+        Ok(indexed)
+    }
+
+    pub fn validate_operation(
+        app_id: &str,
+        operations: &[String],
+        apps: &HashMap<String, AppConfig>
+    ) -> Result<AppConfig, AuthError> {
+        if let Some(app) = apps.get(app_id) {
+            let query_is_allowed = operations.iter().all(|operation| app.queries.contains(operation));
 
             if !query_is_allowed {
-                return Err("No tienes permisos para ejecutar esta acción");
+                return Err(AuthError::OperationNotAllowed);
             }
 
             Ok(app.clone())
         } else {
-            Err("Aplicación no registrada")
-        }
-    }
-
-    pub fn get_payload(token: &str) -> Result<Payload, &'static str> {
-        let token_base_64: Vec<&str> = token.split('.').collect();
-
-        // Decode payload from jwt
-        match token_base_64.get(1) {
-            Some(token_payload) => {
-                match decode(token_payload) {
-                    Ok(decoded_bytes) => {
-                        match String::from_utf8(decoded_bytes) {
-                            Ok(payload) => {
-                                // Cast to Payload
-                                if let Ok(payload_data) = serde_json::from_str::<Payload>(&payload) {
-                                    return Ok(payload_data.clone());
-                                } else {
-                                    return Err("El formato es incorrecto");
-                                }
-                            }
-                            Err(_err) => Err("No se pudo decodificar el payload del token"),
-                        }
+            Err(AuthError::AppNotRegistered)
+        }
+    }
+
+    pub fn insert_header(req: &mut supergraph::Request, key: &'static str, value: &str) -> Result<(), AuthError> {
+        let value = HeaderValue::from_str(value).map_err(|_err| {
+            tracing::error!("value for the '{}' header contains invalid characters", key);
+            AuthError::InvalidHeaderValue(key)
+        })?;
+        req.supergraph_request.headers_mut().insert(key, value);
+
+        Ok(())
+    }
+
+    pub fn verification_keys(keys: &[KeyConfig]) -> Result<Vec<VerificationKey>, String> {
+        keys.iter()
+            .map(|config| {
+                let (algorithm, key) = match config.algorithm {
+                    KeyAlgorithm::HS256 => (Algorithm::HS256, Ok(DecodingKey::from_secret(config.key.as_bytes()))),
+                    KeyAlgorithm::RS256 => (Algorithm::RS256, DecodingKey::from_rsa_pem(config.key.as_bytes())),
+                    KeyAlgorithm::ES256 => (Algorithm::ES256, DecodingKey::from_ec_pem(config.key.as_bytes())),
+                };
+
+                match key {
+                    Ok(key) => Ok(VerificationKey { kid: config.kid.clone(), algorithm, key }),
+                    Err(err) => Err(format!("invalid {:?} key {:?}: {}", config.algorithm, config.kid, err)),
+                }
+            })
+            .collect()
+    }
+
+    pub fn get_payload(
+        token: &str,
+        keys: &[VerificationKey],
+        token_validation: &TokenValidation
+    ) -> Result<Payload, TokenError> {
+        // Tokens using an algorithm we don't know about (including `none`) fail here
+        let header = decode_header(token).map_err(|_err| TokenError::Malformed)?;
+
+        // Only keys configured for the algorithm announced by the token are candidates,
+        // so a token can't make us verify an HMAC with a public key
+        let candidates = keys
+            .iter()
+            .filter(|key| key.algorithm == header.alg)
+            .filter(|key| {
+                match (&header.kid, &key.kid) {
+                    (Some(token_kid), Some(key_kid)) => token_kid == key_kid,
+                    _ => true,
+                }
+            });
+
+        // `exp`, `nbf` and `aud` are checked below with the configured skew and audiences
+        let mut validation = Validation::new(header.alg);
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+        validation.validate_aud = false;
+
+        let mut error = TokenError::InvalidSignature;
+        for candidate in candidates {
+            match decode::<Payload>(token, &candidate.key, &validation) {
+                Ok(token_data) => {
+                    let now = std::time::SystemTime
+                        ::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .expect("system clock is after the epoch")
+                        .as_secs();
+                    validate_claims(&token_data.claims.registered, token_validation, now)?;
+
+                    return Ok(token_data.claims);
+                }
+                Err(err) => {
+                    if let jsonwebtoken::errors::ErrorKind::Json(_) = err.kind() {
+                        error = TokenError::Malformed;
                     }
-                    Err(_err) => Err("No se pudo decodificar el token."),
                 }
             }
-            None => Err("El formato es incorrecto"),
         }
+
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{ encode_config, URL_SAFE_NO_PAD };
+    use jsonwebtoken::{ encode, EncodingKey, Header };
+    use serde_json::json;
+
+    use super::plugin_functions::*;
+
+    fn keys() -> Vec<VerificationKey> {
+        let configs = vec![KeyConfig { algorithm: KeyAlgorithm::HS256, kid: None, key: "dev-secret".to_string() }];
+        verification_keys(&configs).expect("keys are valid")
+    }
+
+    fn verify(token: &str) -> Result<Payload, TokenError> {
+        get_payload(token, &keys(), &TokenValidation::default())
+    }
+
+    fn now() -> u64 {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn signed(claims: serde_json::Value, secret: &str) -> String {
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    fn token(secret: &str) -> String {
+        signed(json!({ "_id": "user-1", "iss": "1234", "exp": now() + 3600 }), secret)
+    }
+
+    #[test]
+    fn accepts_signed_token() {
+        let payload = verify(&token("dev-secret")).expect("token is valid");
+        assert_eq!(payload.iss, "1234");
+    }
+
+    #[test]
+    fn rejects_tampered_payload() {
+        let signed = token("dev-secret");
+        let parts: Vec<&str> = signed.split('.').collect();
+        let forged = encode_config(json!({ "_id": "user-1", "iss": "1233" }).to_string(), URL_SAFE_NO_PAD);
+
+        assert_eq!(
+            verify(&format!("{}.{}.{}", parts[0], forged, parts[2])).unwrap_err(),
+            TokenError::InvalidSignature
+        );
+        assert_eq!(verify(&token("another-secret")).unwrap_err(), TokenError::InvalidSignature);
+    }
+
+    #[test]
+    fn validates_time_and_audience_claims() {
+        let expired = signed(json!({ "_id": "user-1", "iss": "1234", "exp": now() - 3600 }), "dev-secret");
+        let future = json!({ "_id": "user-1", "iss": "1234", "exp": now() + 7200, "nbf": now() + 3600 });
+        let future = signed(future, "dev-secret");
+        let no_exp = signed(json!({ "_id": "user-1", "iss": "1234" }), "dev-secret");
+
+        assert_eq!(verify(&expired).unwrap_err(), TokenError::Expired);
+        assert_eq!(verify(&future).unwrap_err(), TokenError::NotYetValid);
+        assert_eq!(verify(&no_exp).unwrap_err(), TokenError::MissingExpiration);
+
+        let audiences = TokenValidation { audiences: vec!["acme-app".to_string()], ..TokenValidation::default() };
+        assert_eq!(get_payload(&token("dev-secret"), &keys(), &audiences).unwrap_err(), TokenError::InvalidAudience);
+    }
+
+    #[test]
+    fn rejects_alg_none() {
+        let header = encode_config(json!({ "alg": "none" }).to_string(), URL_SAFE_NO_PAD);
+        let payload = encode_config(json!({ "_id": "user-1", "iss": "1234" }).to_string(), URL_SAFE_NO_PAD);
+
+        assert!(verify(&format!("{}.{}.", header, payload)).is_err());
+    }
+
+    fn fields(query: &str, operation_name: Option<&str>) -> Vec<String> {
+        get_operations_name(query, operation_name).expect("query is valid")
+    }
+
+    #[test]
+    fn reads_root_fields_by_name_not_alias() {
+        assert_eq!(fields("{ product(id: 1) { name } }", None), vec!["product"]);
+        assert_eq!(fields("{ p: product(id: 1) { name } }", None), vec!["product"]);
+        assert_eq!(fields("query GetProduct($id: ID!) { product(id: $id) { name } }", None), vec!["product"]);
+    }
+
+    #[test]
+    fn returns_every_root_field() {
+        assert_eq!(
+            fields("# comment { ignored }\n{ product(id: 1) { name } __typename allPandas { name } }", None),
+            vec!["product", "allPandas"]
+        );
+    }
+
+    #[test]
+    fn expands_fragments_at_root() {
+        let query = "fragment F on Query { panda { name } } query { ...F ... on Query { review { id } } }";
+        assert_eq!(fields(query, None), vec!["panda", "review"]);
+
+        assert_eq!(
+            get_operations_name("{ ...Missing }", None).unwrap_err(),
+            OperationError::UnknownFragment("Missing".to_string())
+        );
+        assert_eq!(
+            get_operations_name("fragment F on Query { ...F } { ...F }", None).unwrap_err(),
+            OperationError::FragmentCycle("F".to_string())
+        );
+    }
+
+    #[test]
+    fn selects_operation_by_name() {
+        let query = "query A { product(id: 1) { name } } query B { allPandas { name } }";
+
+        assert_eq!(fields(query, Some("A")), vec!["product"]);
+        assert_eq!(fields(query, Some("B")), vec!["allPandas"]);
+        assert!(get_operations_name(query, None).is_err());
+        assert!(get_operations_name(query, Some("C")).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_queries_without_panicking() {
+        for query in ["product", "", "{ product(id: ", "query { product { name }", "fragment F on Query { a }"] {
+            assert!(get_operations_name(query, None).is_err(), "{:?} should be rejected", query);
+        }
+    }
+
+    #[test]
+    fn validates_operations_against_the_registry() {
+        let apps = parse_apps(&std::fs::read_to_string("allowedApps.json").unwrap()).expect("registry is valid");
+        let operations = |fields: &[&str]| -> Vec<String> {
+            fields
+                .iter()
+                .map(|field| field.to_string())
+                .collect()
+        };
+
+        assert_eq!(validate_operation("1234", &operations(&["product", "review"]), &apps).unwrap().nombre, "app1-Name");
+        assert_eq!(
+            validate_operation("1234", &operations(&["product", "panda"]), &apps).err(),
+            Some(AuthError::OperationNotAllowed)
+        );
+        assert_eq!(validate_operation("9999", &operations(&["product"]), &apps).err(), Some(AuthError::AppNotRegistered));
+        for content in ["", "[{", "{}", r#"[{ "id": "1234" }]"#] {
+            assert!(parse_apps(content).is_err(), "{:?} should be rejected", content);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_registry_entries() {
+        let app = |id: &str, queries: &[&str]| json!({ "id": id, "nombre": "app", "queries": queries });
+
+        let duplicated = json!([app("1234", &["product"]), app("1234", &["review"])]).to_string();
+        assert_eq!(parse_apps(&duplicated).err().unwrap(), "app '1234' is registered more than once");
+
+        let empty = json!([app("1234", &[])]).to_string();
+        assert_eq!(parse_apps(&empty).err().unwrap(), "app '1234' has no queries");
+
+        let mut unknown_key = app("1234", &["product"]);
+        unknown_key["name"] = json!("app");
+        assert!(parse_apps(&json!([unknown_key]).to_string()).unwrap_err().contains("name"));
+    }
+
+    #[test]
+    fn invalid_header_values_are_server_errors() {
+        let mut req = apollo_router::services::supergraph::Request::fake_builder().build().unwrap();
+
+        let err = insert_header(&mut req, "app_name", "line\nbreak").unwrap_err();
+        assert_eq!(err, AuthError::InvalidHeaderValue("app_name"));
+        assert_eq!(err.status_code(), http::StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(insert_header(&mut req, "app_name", "app1-Name").is_ok());
     }
 }
@@ -0,0 +1,42 @@
+use std::sync::OnceLock;
+
+pub use acme_common::messages::{ MessageCatalog, MessagesConfig };
+
+const BUNDLED: [(&str, &str); 2] = [
+    ("es", include_str!("../messages/es.json")),
+    ("en", include_str!("../messages/en.json")),
+];
+
+// Spanish and English, Spanish by default
+pub fn bundled_catalog() -> &'static MessageCatalog {
+    static BUNDLED_CATALOG: OnceLock<MessageCatalog> = OnceLock::new();
+
+    BUNDLED_CATALOG.get_or_init(|| MessageCatalog::bundled(&BUNDLED))
+}
+
+pub fn load_catalog(config: &MessagesConfig) -> Result<MessageCatalog, String> {
+    bundled_catalog().with_config(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_catalogs_define_the_same_keys() {
+        let catalog = bundled_catalog();
+
+        assert_eq!(catalog.keys("es"), catalog.keys("en"));
+    }
+
+    #[test]
+    fn bundled_catalogs_render_every_locale() {
+        let catalog = bundled_catalog();
+
+        assert_eq!(
+            catalog.message("en", "UNKNOWN_FRAGMENT", &[("fragment", "ProductFields")]),
+            "The fragment 'ProductFields' doesn't exist in the document"
+        );
+        assert_eq!(catalog.message("fr", "APP_NOT_REGISTERED", &[]), "Aplicación no registrada");
+    }
+}
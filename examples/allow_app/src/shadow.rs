@@ -0,0 +1,12 @@
+pub use acme_common::shadow::{ EnforcementMode, ShadowDecision };
+
+use crate::messages::MessageCatalog;
+use crate::plugin_functions::AuthError;
+
+// Set in shadow mode on every request the plugin checks, with what enforce mode would have done
+pub const SHADOW_DECISION_CONTEXT_KEY: &str = "acme::allow_app::shadow_decision";
+
+// The reason is in the catalog's default locale, whatever language the client asked for
+pub fn denied_decision(err: &AuthError, catalog: &MessageCatalog) -> ShadowDecision {
+    ShadowDecision::denied(err.extension_code(), err.localized(catalog, catalog.default_locale()))
+}
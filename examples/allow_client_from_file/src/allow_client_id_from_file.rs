@@ -1,6 +1,13 @@
+use std::collections::HashSet;
 use std::ops::ControlFlow;
 use std::path::PathBuf;
+use std::sync::Arc;
 
+use acme_common::credentials::CredentialError;
+use acme_common::credentials::CredentialExtractor;
+use acme_common::credentials::CredentialSource;
+use acme_common::shadow::{EnforcementMode, ShadowDecision};
+use acme_common::watched_file::{WatchedFile, RELOAD_DEBOUNCE};
 use apollo_router::graphql;
 use apollo_router::layers::ServiceBuilderExt;
 use apollo_router::plugin::Plugin;
@@ -10,21 +17,58 @@ use apollo_router::services::supergraph;
 use http::StatusCode;
 use schemars::JsonSchema;
 use serde::Deserialize;
-use serde_json_bytes::Value;
 use tower::BoxError;
 use tower::ServiceBuilder;
 use tower::ServiceExt;
 
+use crate::api_keys::{now, ApiKeyError, ApiKeys, API_KEY_CONTEXT_KEY};
+
+const PLUGIN_NAME: &str = "example.allow_client_id_from_file";
+
+// Set in shadow mode on every request, with what enforce mode would have done
+const SHADOW_DECISION_CONTEXT_KEY: &str = "example::allow_client_id_from_file::shadow_decision";
+
 // This structure is the one we'll deserialize the yml configuration into
 #[derive(Deserialize, JsonSchema)]
 struct AllowClientIdConfig {
-    header: String,
+    // A single header read as is, the shorthand for `credentials`
+    header: Option<String>,
+    // Locations the client id is read from, the first one the request has is used
+    #[serde(default)]
+    credentials: Vec<CredentialSource>,
+    // `client_id` reads a JSON array of allowed ids, `api_key` a registry of hashed keys
+    #[serde(default)]
+    registry: RegistryKind,
     path: String,
+    // `shadow` only records what `enforce` would do, to try a registry on real traffic
+    #[serde(default)]
+    mode: EnforcementMode,
+}
+
+#[derive(Deserialize, JsonSchema, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum RegistryKind {
+    #[default]
+    ClientId,
+    ApiKey,
+}
+
+enum Registry {
+    ClientIds(Arc<WatchedFile<HashSet<String>>>),
+    ApiKeys(Arc<WatchedFile<ApiKeys>>),
 }
 
 struct AllowClientIdFromFile {
-    header: String,
-    allowed_ids_path: PathBuf,
+    mode: EnforcementMode,
+    credentials: Arc<CredentialExtractor>,
+    registry: Arc<Registry>,
+}
+
+// Why a request is refused, sent back in enforce mode and recorded in shadow mode
+struct Rejection {
+    message: String,
+    code: &'static str,
+    status: StatusCode,
 }
 
 #[async_trait::async_trait]
@@ -32,107 +76,116 @@ impl Plugin for AllowClientIdFromFile {
     type Config = AllowClientIdConfig;
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
-        let AllowClientIdConfig { path, header } = init.config;
-        let allowed_ids_path = PathBuf::from(path.as_str());
-        Ok(Self {
-            allowed_ids_path,
+        let AllowClientIdConfig {
+            path,
             header,
+            credentials,
+            registry,
+            mode,
+        } = init.config;
+        let credentials = CredentialExtractor::new(header.as_deref(), &credentials)
+            .map_err(|err| format!("example.allow_client_id_from_file: {err}"))?;
+        let registry_path = PathBuf::from(path.as_str());
+        // Loaded once here and reloaded whenever the file changes,
+        // requests never touch the filesystem
+        let registry = match registry {
+            RegistryKind::ClientId => Registry::ClientIds(WatchedFile::load(
+                &registry_path,
+                RELOAD_DEBOUNCE,
+                |content| serde_json::from_str(content).map_err(|err| err.to_string()),
+            )?),
+            RegistryKind::ApiKey => Registry::ApiKeys(WatchedFile::load(
+                &registry_path,
+                RELOAD_DEBOUNCE,
+                ApiKeys::parse,
+            )?),
+        };
+        Ok(Self {
+            mode,
+            registry: Arc::new(registry),
+            credentials: Arc::new(credentials),
         })
     }
 
-    // On each request, this plugin will extract a x-client-id header, and check against a file
-    // whether the client is allowed to run a request.
-    //
-    // While this is not the most performant and efficient usecase,
-    // We could easily change the place where the file list is stored,
-    // switching the async file read with an async http request
+    // On each request, this plugin will extract the client id, and check against
+    // the in-memory copy of the file whether the client is allowed to run a request.
     fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
-        let header_key = self.header.clone();
+        let mode = self.mode;
+        let credentials = self.credentials.clone();
         // oneshot_async_checkpoint is an async function.
         // this means it will run whenever the service `await`s it
         // given we're getting a mutable reference to self,
         // self won't be present anymore when we `await` the checkpoint.
         //
-        // this is solved by cloning the path and moving it into the oneshot_async_checkpoint callback.
+        // this is solved by cloning the Arc and moving it into the oneshot_async_checkpoint callback.
         //
         // see https://rust-lang.github.io/async-book/03_async_await/01_chapter.html#async-lifetimes for more information
-        let allowed_ids_path = self.allowed_ids_path.clone();
+        let registry = self.registry.clone();
 
         let handler = move |req: supergraph::Request| {
-            // If we set a res, then we are going to break execution
-            // If not, we are continuing
-            let mut res = None;
-            if !req.supergraph_request.headers().contains_key(&header_key) {
-                // Prepare an HTTP 401 response with a GraphQL error message
-                res = Some(
-                    supergraph::Response::error_builder()
-                        .error(
-                            graphql::Error::builder()
-                                .message(format!("Missing '{header_key}' header"))
-                                .extension_code("AUTH_ERROR")
-                                .build(),
-                        )
-                        .status_code(StatusCode::UNAUTHORIZED)
-                        .context(req.context.clone())
-                        .build()
-                        .expect("response is valid"),
-                );
-            } else {
-                // It is best practice to perform checks before we unwrap,
-                // And to use `expect()` instead of `unwrap()`, with a message
-                // that explains why the use of `expect()` is safe
-                let client_id = req
-                    .supergraph_request
-                    .headers()
-                    .get("x-client-id")
-                    .expect("this cannot fail; we checked for header presence above;qed")
-                    .to_str();
-
-                match client_id {
-                    Ok(client_id) => {
-                        let allowed_clients: Vec<String> = serde_json::from_str(
-                            std::fs::read_to_string(allowed_ids_path.clone())
-                                .unwrap()
-                                .as_str(),
-                        )
-                        .unwrap();
-
-                        if !allowed_clients.contains(&client_id.to_string()) {
-                            // Prepare an HTTP 403 response with a GraphQL error message
-                            res = Some(
-                                supergraph::Response::builder()
-                                    .data(Value::default())
-                                    .error(
-                                        graphql::Error::builder()
-                                            .message("client-id is not allowed")
-                                            .extension_code("UNAUTHORIZED_CLIENT_ID")
-                                            .build(),
-                                    )
-                                    .status_code(StatusCode::FORBIDDEN)
-                                    .context(req.context.clone())
-                                    .build()
-                                    .expect("response is valid"),
-                            );
+            let result = match credentials.extract(&req.supergraph_request) {
+                Ok(credential) => match &*registry {
+                    Registry::ClientIds(allowed_ids) => {
+                        if allowed_ids.current().contains(&credential) {
+                            Ok(())
+                        } else {
+                            // An HTTP 403 response with a GraphQL error message
+                            Err(Rejection {
+                                message: "client-id is not allowed".to_string(),
+                                code: "UNAUTHORIZED_CLIENT_ID",
+                                status: StatusCode::FORBIDDEN,
+                            })
+                        }
+                    }
+                    Registry::ApiKeys(api_keys) => {
+                        let operation_name =
+                            req.supergraph_request.body().operation_name.as_deref();
+                        match api_keys
+                            .current()
+                            .verify(&credential, operation_name, now())
+                        {
+                            // Other plugins and scripts can tell who is calling without the key
+                            Ok(metadata) => {
+                                let _ = req.context.insert(API_KEY_CONTEXT_KEY, metadata.clone());
+                                Ok(())
+                            }
+                            Err(err) => Err(api_key_rejection(&err)),
                         }
                     }
-                    Err(_not_a_string_error) => {
-                        // Prepare an HTTP 400 response with a GraphQL error message
-                        res = Some(
-                            supergraph::Response::error_builder()
-                                .error(
-                                    graphql::Error::builder()
-                                        .message(format!("'{header_key}' value is not a string"))
-                                        .extension_code("BAD_CLIENT_ID")
-                                        .build(),
-                                )
-                                .status_code(StatusCode::BAD_REQUEST)
-                                .context(req.context.clone())
-                                .build()
-                                .expect("response is valid"),
-                        );
+                },
+                // An HTTP 401 response with a GraphQL error message
+                Err(CredentialError::Missing) => Err(Rejection {
+                    message: format!("Missing {}", credentials.describe()),
+                    code: "AUTH_ERROR",
+                    status: StatusCode::UNAUTHORIZED,
+                }),
+                // An HTTP 400 response with a GraphQL error message
+                Err(CredentialError::Invalid) => Err(Rejection {
+                    message: format!("{} value is not a string", credentials.describe()),
+                    code: "BAD_CLIENT_ID",
+                    status: StatusCode::BAD_REQUEST,
+                }),
+            };
+
+            if mode == EnforcementMode::Shadow {
+                let decision = match &result {
+                    Ok(()) => ShadowDecision::allowed(Vec::new()),
+                    Err(rejection) => {
+                        ShadowDecision::denied(rejection.code, rejection.message.clone())
                     }
                 };
+                decision.record(PLUGIN_NAME, "supergraph");
+                let _ = req.context.insert(SHADOW_DECISION_CONTEXT_KEY, decision);
             }
+
+            // If we set a res, then we are going to break execution
+            // If not, we are continuing
+            let res = match result {
+                Err(rejection) if mode == EnforcementMode::Enforce => {
+                    Some(rejection.response(&req))
+                }
+                _result => None,
+            };
             async {
                 // Check to see if we built a response. If we did, we need to Break.
                 match res {
@@ -152,6 +205,49 @@ impl Plugin for AllowClientIdFromFile {
     }
 }
 
+// Unknown ids and wrong secrets get the same answer, so ids can't be probed
+fn api_key_rejection(err: &ApiKeyError) -> Rejection {
+    let (message, code, status) = match err {
+        ApiKeyError::Invalid => (
+            "API key is not valid".to_string(),
+            "INVALID_API_KEY",
+            StatusCode::UNAUTHORIZED,
+        ),
+        ApiKeyError::Expired => (
+            "API key has expired".to_string(),
+            "EXPIRED_API_KEY",
+            StatusCode::UNAUTHORIZED,
+        ),
+        ApiKeyError::OperationNotAllowed(operation_name) => (
+            format!("operation '{operation_name}' is not allowed for this API key"),
+            "OPERATION_NOT_ALLOWED",
+            StatusCode::FORBIDDEN,
+        ),
+    };
+
+    Rejection {
+        message,
+        code,
+        status,
+    }
+}
+
+impl Rejection {
+    fn response(self, req: &supergraph::Request) -> supergraph::Response {
+        supergraph::Response::error_builder()
+            .error(
+                graphql::Error::builder()
+                    .message(self.message)
+                    .extension_code(self.code)
+                    .build(),
+            )
+            .status_code(self.status)
+            .context(req.context.clone())
+            .build()
+            .expect("response is valid")
+    }
+}
+
 // This macro allows us to use it in our plugin registry!
 // register_plugin takes a group name, and a plugin name.
 //
@@ -169,6 +265,7 @@ register_plugin!(
 // and test your plugins in isolation:
 #[cfg(test)]
 mod tests {
+    use acme_common::shadow::{EnforcementMode, ShadowDecision};
     use apollo_router::graphql;
     use apollo_router::plugin::test;
     use apollo_router::plugin::Plugin;
@@ -181,6 +278,10 @@ mod tests {
 
     use super::AllowClientIdFromFile;
     use crate::allow_client_id_from_file::AllowClientIdConfig;
+    use crate::allow_client_id_from_file::RegistryKind;
+    use crate::allow_client_id_from_file::SHADOW_DECISION_CONTEXT_KEY;
+    use crate::api_keys::ApiKeyMetadata;
+    use crate::api_keys::API_KEY_CONTEXT_KEY;
 
     // This test ensures the router will be able to
     // find our `allow-client-id-from-file` plugin,
@@ -216,7 +317,10 @@ mod tests {
         let init = PluginInit::fake_builder()
             .config(AllowClientIdConfig {
                 path: "allowedClientIds.json".to_string(),
-                header: "x-client-id".to_string(),
+                header: Some("x-client-id".to_string()),
+                credentials: vec![],
+                registry: RegistryKind::ClientId,
+                mode: EnforcementMode::Enforce,
             })
             .build();
         let service_stack = AllowClientIdFromFile::new(init)
@@ -259,7 +363,10 @@ mod tests {
         let init = PluginInit::fake_builder()
             .config(AllowClientIdConfig {
                 path: "allowedClientIds.json".to_string(),
-                header: "x-client-id".to_string(),
+                header: Some("x-client-id".to_string()),
+                credentials: vec![],
+                registry: RegistryKind::ClientId,
+                mode: EnforcementMode::Enforce,
             })
             .build();
         let service_stack = AllowClientIdFromFile::new(init)
@@ -291,6 +398,53 @@ mod tests {
         )
     }
 
+    #[tokio::test]
+    async fn test_shadow_mode_records_decisions() {
+        let init = PluginInit::fake_builder()
+            .config(AllowClientIdConfig {
+                path: "allowedClientIds.json".to_string(),
+                header: Some("x-client-id".to_string()),
+                credentials: vec![],
+                registry: RegistryKind::ClientId,
+                mode: EnforcementMode::Shadow,
+            })
+            .build();
+        let plugin = AllowClientIdFromFile::new(init)
+            .await
+            .expect("couldn't create AllowClientIdFromFile");
+
+        // Every request reaches the mock, the decision is on the context
+        for (client_id, allowed) in [("jeremy", true), ("invalid_client_id", false)] {
+            let mut mock_service = test::MockSupergraphService::new();
+            mock_service.expect_call().times(1).returning(|req| {
+                Ok(supergraph::Response::fake_builder()
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+            let request = supergraph::Request::fake_builder()
+                .header("x-client-id", client_id)
+                .build()
+                .expect("expecting valid request");
+            let service_response = plugin
+                .supergraph_service(mock_service.boxed())
+                .oneshot(request)
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::OK, service_response.response.status());
+
+            let decision = service_response
+                .context
+                .get::<_, ShadowDecision>(SHADOW_DECISION_CONTEXT_KEY)
+                .unwrap()
+                .unwrap();
+            assert_eq!(decision.allowed, allowed);
+            if !allowed {
+                assert_eq!(decision.code.as_deref(), Some("UNAUTHORIZED_CLIENT_ID"));
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_client_id_allowed() {
         let valid_client_id = "jeremy";
@@ -328,7 +482,10 @@ mod tests {
         let init = PluginInit::fake_builder()
             .config(AllowClientIdConfig {
                 path: "allowedClientIds.json".to_string(),
-                header: "x-client-id".to_string(),
+                header: Some("x-client-id".to_string()),
+                credentials: vec![],
+                registry: RegistryKind::ClientId,
+                mode: EnforcementMode::Enforce,
             })
             .build();
         let service_stack = AllowClientIdFromFile::new(init)
@@ -360,4 +517,141 @@ mod tests {
             expected_mock_response_data
         )
     }
+
+    #[tokio::test]
+    async fn test_client_id_from_configured_locations() {
+        // Reading a header other than `x-client-id` used to panic the worker
+        let init = PluginInit::fake_builder()
+            .config(AllowClientIdConfig {
+                path: "allowedClientIds.json".to_string(),
+                header: None,
+                registry: RegistryKind::ClientId,
+                mode: EnforcementMode::Enforce,
+                credentials: serde_json::from_value(json!([
+                    { "header": { "name": "x-api-client" } },
+                    { "cookie": { "name": "client_id" } }
+                ]))
+                .unwrap(),
+            })
+            .build();
+        let plugin = AllowClientIdFromFile::new(init)
+            .await
+            .expect("couldn't create AllowClientIdFromFile");
+
+        let from_header = supergraph::Request::fake_builder()
+            .header("x-api-client", "jeremy")
+            .build()
+            .expect("expecting valid request");
+        let from_cookie = supergraph::Request::fake_builder()
+            .header("cookie", "theme=dark; client_id=jeremy")
+            .build()
+            .expect("expecting valid request");
+
+        for request in [from_header, from_cookie] {
+            let mut mock_service = test::MockSupergraphService::new();
+            mock_service.expect_call().times(1).returning(|_req| {
+                Ok(supergraph::Response::fake_builder()
+                    .data("allowed")
+                    .build()
+                    .unwrap())
+            });
+            let service_response = plugin
+                .supergraph_service(mock_service.boxed())
+                .oneshot(request)
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::OK, service_response.response.status());
+        }
+
+        let mut service_response = plugin
+            .supergraph_service(test::MockSupergraphService::new().boxed())
+            .oneshot(supergraph::Request::fake_builder().build().unwrap())
+            .await
+            .unwrap();
+        let graphql_response: graphql::Response = service_response.next_response().await.unwrap();
+        assert_eq!(
+            "Missing 'x-api-client' header or 'client_id' cookie",
+            graphql_response.errors[0].message
+        );
+    }
+
+    #[tokio::test]
+    async fn test_api_keys() {
+        let init = PluginInit::fake_builder()
+            .config(AllowClientIdConfig {
+                path: "allowedApiKeys.json".to_string(),
+                header: Some("x-api-key".to_string()),
+                credentials: vec![],
+                registry: RegistryKind::ApiKey,
+                mode: EnforcementMode::Enforce,
+            })
+            .build();
+        let plugin = AllowClientIdFromFile::new(init)
+            .await
+            .expect("couldn't create AllowClientIdFromFile");
+
+        // The matched key's metadata is on the context, the key itself is not
+        let mut mock_service = test::MockSupergraphService::new();
+        mock_service.expect_call().times(1).returning(|req| {
+            let metadata = req
+                .context
+                .get::<_, ApiKeyMetadata>(API_KEY_CONTEXT_KEY)
+                .unwrap()
+                .unwrap();
+            assert_eq!(metadata.owner, "platform-team");
+            Ok(supergraph::Response::fake_builder()
+                .data("allowed")
+                .build()
+                .unwrap())
+        });
+        let request = supergraph::Request::fake_builder()
+            .operation_name("GetProducts")
+            .header("x-api-key", "dev-ci.dev-secret")
+            .build()
+            .expect("expecting valid request");
+        let service_response = plugin
+            .supergraph_service(mock_service.boxed())
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, service_response.response.status());
+
+        for (key, operation_name, status, code) in [
+            (
+                "dev-ci.wrong",
+                "GetProducts",
+                StatusCode::UNAUTHORIZED,
+                "INVALID_API_KEY",
+            ),
+            (
+                "dev-ci.dev-secret",
+                "DeleteProduct",
+                StatusCode::FORBIDDEN,
+                "OPERATION_NOT_ALLOWED",
+            ),
+        ] {
+            let request = supergraph::Request::fake_builder()
+                .operation_name(operation_name)
+                .header("x-api-key", key)
+                .build()
+                .expect("expecting valid request");
+            let mut service_response = plugin
+                .supergraph_service(test::MockSupergraphService::new().boxed())
+                .oneshot(request)
+                .await
+                .unwrap();
+            assert_eq!(status, service_response.response.status());
+
+            let graphql_response: graphql::Response =
+                service_response.next_response().await.unwrap();
+            assert_eq!(
+                graphql_response.errors[0]
+                    .extensions
+                    .get("code")
+                    .unwrap()
+                    .as_str(),
+                Some(code)
+            );
+        }
+    }
 }
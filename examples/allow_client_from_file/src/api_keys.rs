@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use acme_common::api_keys::{check_key_id, hash_secret, ApiKeyEntry};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+// Set on requests authenticated with an API key, for other plugins, Rhai scripts and telemetry
+pub const API_KEY_CONTEXT_KEY: &str = "example::allow_client_id_from_file::api_key";
+
+// Hashed in place of the salt of unknown ids, so they take as long to reject as wrong secrets
+const UNKNOWN_ID_SALT: [u8; 16] = [0; 16];
+
+// What the request context gets about the matched key, never the hash
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiKeyMetadata {
+    pub id: String,
+    pub owner: String,
+    pub created: u64,
+    pub expires: Option<u64>,
+    pub operations: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApiKeyError {
+    // Unknown id or wrong secret, they aren't told apart
+    Invalid,
+    Expired,
+    OperationNotAllowed(String),
+}
+
+struct StoredKey {
+    salt: Vec<u8>,
+    hash: [u8; 32],
+    metadata: ApiKeyMetadata,
+}
+
+pub struct ApiKeys {
+    keys: HashMap<String, StoredKey>,
+}
+
+impl ApiKeys {
+    pub fn parse(content: &str) -> Result<ApiKeys, String> {
+        let entries: Vec<ApiKeyEntry> =
+            serde_json::from_str(content).map_err(|err| err.to_string())?;
+
+        let mut keys = HashMap::new();
+        for entry in entries {
+            let (id, key) = stored_key(entry)?;
+            if keys.contains_key(&id) {
+                return Err(format!("API key '{id}' is registered more than once"));
+            }
+            keys.insert(id, key);
+        }
+        Ok(ApiKeys { keys })
+    }
+
+    // `operation_name` is the one sent with the request, keys limited to some operations
+    // can't run anonymous ones. Nothing here looks at the document behind the name
+    pub fn verify(
+        &self,
+        key: &str,
+        operation_name: Option<&str>,
+        now: u64,
+    ) -> Result<&ApiKeyMetadata, ApiKeyError> {
+        let (id, secret) = key.split_once('.').ok_or(ApiKeyError::Invalid)?;
+        let stored = self.keys.get(id);
+
+        let (salt, expected) = match stored {
+            Some(stored) => (stored.salt.as_slice(), stored.hash),
+            None => (UNKNOWN_ID_SALT.as_slice(), [0; 32]),
+        };
+        let matches: bool = hash_secret(salt, secret).ct_eq(&expected).into();
+        let metadata = match (stored, matches) {
+            (Some(stored), true) => &stored.metadata,
+            _unknown_or_wrong => return Err(ApiKeyError::Invalid),
+        };
+
+        if metadata
+            .expires
+            .map(|expires| now >= expires)
+            .unwrap_or(false)
+        {
+            return Err(ApiKeyError::Expired);
+        }
+        if !metadata.operations.is_empty() {
+            let operation_name = operation_name.unwrap_or_default();
+            if !metadata
+                .operations
+                .iter()
+                .any(|allowed| allowed == operation_name)
+            {
+                return Err(ApiKeyError::OperationNotAllowed(operation_name.to_string()));
+            }
+        }
+
+        Ok(metadata)
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+fn stored_key(entry: ApiKeyEntry) -> Result<(String, StoredKey), String> {
+    let id = entry.id;
+    check_key_id(&id)?;
+    let salt = hex::decode(&entry.salt)
+        .ok()
+        .filter(|salt| !salt.is_empty())
+        .ok_or_else(|| format!("API key '{id}' has an invalid `salt`"))?;
+    let hash: [u8; 32] = hex::decode(&entry.hash)
+        .ok()
+        .and_then(|hash| hash.try_into().ok())
+        .ok_or_else(|| format!("API key '{id}' has an invalid `hash`"))?;
+    if entry
+        .expires
+        .map(|expires| expires <= entry.created)
+        .unwrap_or(false)
+    {
+        return Err(format!("API key '{id}' expires before it was created"));
+    }
+
+    let metadata = ApiKeyMetadata {
+        id: id.clone(),
+        owner: entry.owner,
+        created: entry.created,
+        expires: entry.expires,
+        operations: entry.operations,
+    };
+    Ok((
+        id,
+        StoredKey {
+            salt,
+            hash,
+            metadata,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn entry(
+        id: &str,
+        secret: &str,
+        expires: Option<u64>,
+        operations: &[&str],
+    ) -> serde_json::Value {
+        let salt = b"0123456789abcdef";
+        json!({
+            "id": id,
+            "owner": "platform",
+            "salt": hex::encode(salt),
+            "hash": hex::encode(hash_secret(salt, secret)),
+            "created": 1000,
+            "expires": expires,
+            "operations": operations,
+        })
+    }
+
+    fn keys(entries: &[serde_json::Value]) -> ApiKeys {
+        ApiKeys::parse(&serde_json::to_string(entries).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn verifies_the_secret() {
+        let keys = keys(&[entry("ci", "s3cret", None, &[])]);
+
+        assert_eq!(
+            keys.verify("ci.s3cret", None, 2000).unwrap().owner,
+            "platform"
+        );
+        assert_eq!(
+            keys.verify("ci.wrong", None, 2000),
+            Err(ApiKeyError::Invalid)
+        );
+        assert_eq!(
+            keys.verify("other.s3cret", None, 2000),
+            Err(ApiKeyError::Invalid)
+        );
+        assert_eq!(keys.verify("s3cret", None, 2000), Err(ApiKeyError::Invalid));
+    }
+
+    #[test]
+    fn enforces_expiry_and_operations() {
+        let keys = keys(&[entry("ci", "s3cret", Some(2000), &["GetProducts"])]);
+
+        assert!(keys.verify("ci.s3cret", Some("GetProducts"), 1999).is_ok());
+        assert_eq!(
+            keys.verify("ci.s3cret", Some("GetProducts"), 2000),
+            Err(ApiKeyError::Expired)
+        );
+        assert_eq!(
+            keys.verify("ci.s3cret", Some("DeleteProduct"), 1500),
+            Err(ApiKeyError::OperationNotAllowed(
+                "DeleteProduct".to_string()
+            ))
+        );
+        assert!(keys.verify("ci.s3cret", None, 1500).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_registries() {
+        let duplicated = [entry("ci", "a", None, &[]), entry("ci", "b", None, &[])];
+        let mut bad_hash = entry("ci", "a", None, &[]);
+        bad_hash["hash"] = json!("abcd");
+        let bad_id = entry("ci.prod", "a", None, &[]);
+
+        for registry in [
+            json!(duplicated),
+            json!([bad_hash]),
+            json!([bad_id]),
+            json!([entry("ci", "a", Some(500), &[])]),
+            json!(["jeremy"]),
+        ] {
+            assert!(ApiKeys::parse(&registry.to_string()).is_err(), "{registry}");
+        }
+    }
+
+    #[test]
+    fn sample_registry_is_valid() {
+        let content = std::fs::read_to_string("allowedApiKeys.json").unwrap();
+        let keys = ApiKeys::parse(&content).unwrap();
+
+        assert!(keys
+            .verify("dev-ci.dev-secret", Some("GetProducts"), now())
+            .is_ok());
+    }
+}
@@ -1,4 +1,5 @@
 mod allow_client_id_from_file;
+mod api_keys;
 
 use anyhow::Result;
 
@@ -0,0 +1,307 @@
+use std::collections::{ BTreeMap, BTreeSet, HashSet };
+use std::path::Path;
+
+use acme_common::api_keys::{ check_key_id, hash_secret, ApiKeyEntry };
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::plugin_functions::{ validate_app, AppConfig };
+use crate::schema::SchemaTypes;
+
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let content = std::fs::read_to_string(path).map_err(|err| format!("could not read {:?}: {}", path, err))?;
+    serde_json::from_str(&content).map_err(|err| format!("invalid {:?}: {}", path, err))
+}
+
+// The file is replaced with a rename, the plugins watching it never read half of it
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), String> {
+    let mut content = serde_json::to_string_pretty(value).map_err(|err| err.to_string())?;
+    content.push('\n');
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, content).map_err(|err| format!("could not write {:?}: {}", temporary, err))?;
+    std::fs::rename(&temporary, path).map_err(|err| format!("could not replace {:?}: {}", path, err))
+}
+
+pub fn add_app(apps: &mut Vec<AppConfig>, app: AppConfig) -> Result<(), String> {
+    if apps.iter().any(|registered| registered._id == app._id) {
+        return Err(format!("app '{}' is already registered", app._id));
+    }
+    validate_app(&app)?;
+    apps.push(app);
+    Ok(())
+}
+
+pub fn remove_app(apps: &mut Vec<AppConfig>, id: &str) -> Result<AppConfig, String> {
+    let position = apps
+        .iter()
+        .position(|app| app._id == id)
+        .ok_or_else(|| format!("app '{}' isn't registered", id))?;
+    Ok(apps.remove(position))
+}
+
+// Permissions the app already has are left as they are
+pub fn grant(apps: &mut [AppConfig], id: &str, permissions: &[String]) -> Result<(), String> {
+    let app = find_app(apps, id)?;
+    for permission in permissions {
+        if !app.permissions.contains(permission) {
+            app.permissions.push(permission.clone());
+        }
+    }
+    Ok(())
+}
+
+pub fn revoke(apps: &mut [AppConfig], id: &str, permissions: &[String]) -> Result<(), String> {
+    let app = find_app(apps, id)?;
+    if let Some(missing) = permissions.iter().find(|permission| !app.permissions.contains(permission)) {
+        return Err(format!("app '{}' doesn't have the '{}' permission", id, missing));
+    }
+
+    let mut revoked = app.clone();
+    revoked.permissions.retain(|permission| !permissions.contains(permission));
+    // An app left without permissions would make the whole registry invalid
+    validate_app(&revoked)?;
+    *app = revoked;
+    Ok(())
+}
+
+// Everything the plugin would refuse to start with, all at once instead of the first one.
+// Permissions and subgraphs are only checked with a schema
+pub fn lint(apps: &[AppConfig], schema: Option<&SchemaTypes>) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut seen = HashSet::new();
+
+    for app in apps {
+        if !seen.insert(app._id.as_str()) {
+            problems.push(format!("app '{}' is registered more than once", app._id));
+        }
+        if let Err(err) = validate_app(app) {
+            problems.push(err);
+        }
+        if let Some(schema) = schema {
+            for permission in schema.unknown_permissions(&app.permissions) {
+                problems.push(format!("app '{}': permission '{}' not found in the schema", app._id, permission));
+            }
+            for subgraph in schema.unknown_subgraphs(app.subgraphs.as_deref().unwrap_or_default()) {
+                problems.push(format!("app '{}': subgraph '{}' not found in the schema", app._id, subgraph));
+            }
+        }
+    }
+
+    problems
+}
+
+// One line per change: `+` added, `-` removed, `~` changed, sorted by app id
+pub fn diff(old: &[AppConfig], new: &[AppConfig]) -> Vec<String> {
+    let old: BTreeMap<&str, &AppConfig> = old
+        .iter()
+        .map(|app| (app._id.as_str(), app))
+        .collect();
+    let new: BTreeMap<&str, &AppConfig> = new
+        .iter()
+        .map(|app| (app._id.as_str(), app))
+        .collect();
+    let ids: BTreeSet<&str> = old.keys().chain(new.keys()).copied().collect();
+
+    let mut changes = Vec::new();
+    for id in ids {
+        match (old.get(id), new.get(id)) {
+            (Some(app), None) => changes.push(format!("- app '{}' ({})", id, app.name)),
+            (None, Some(app)) => {
+                changes.push(format!("+ app '{}' ({}) with {}", id, app.name, app.permissions.join(", ")));
+            }
+            (Some(old), Some(new)) => changes.extend(app_changes(old, new)),
+            (None, None) => {}
+        }
+    }
+
+    changes
+}
+
+fn app_changes(old: &AppConfig, new: &AppConfig) -> Vec<String> {
+    let id = &old._id;
+    let mut changes = Vec::new();
+
+    for (field, old_value, new_value) in [
+        ("name", json(&old.name), json(&new.name)),
+        ("url", json(&old.url), json(&new.url)),
+        ("introspection", json(&old.introspection), json(&new.introspection)),
+        ("subgraphs", json(&old.subgraphs), json(&new.subgraphs)),
+    ] {
+        if old_value != new_value {
+            changes.push(format!("~ app '{}': {} {} -> {}", id, field, old_value, new_value));
+        }
+    }
+    for permission in &old.permissions {
+        if !new.permissions.contains(permission) {
+            changes.push(format!("~ app '{}': - permission '{}'", id, permission));
+        }
+    }
+    for permission in &new.permissions {
+        if !old.permissions.contains(permission) {
+            changes.push(format!("~ app '{}': + permission '{}'", id, permission));
+        }
+    }
+
+    changes
+}
+
+fn json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+fn find_app<'a>(apps: &'a mut [AppConfig], id: &str) -> Result<&'a mut AppConfig, String> {
+    apps.iter_mut()
+        .find(|app| app._id == id)
+        .ok_or_else(|| format!("app '{}' isn't registered", id))
+}
+
+// Returns the key, `<id>.<secret>`. Only its hash is stored, it can't be shown again
+pub fn mint_api_key(
+    keys: &mut Vec<ApiKeyEntry>,
+    id: &str,
+    owner: &str,
+    expires: Option<u64>,
+    operations: Vec<String>,
+    now: u64
+) -> Result<String, String> {
+    check_key_id(id)?;
+    if keys.iter().any(|key| key.id == id) {
+        return Err(format!("API key '{}' is already registered", id));
+    }
+    if expires.map(|expires| expires <= now).unwrap_or(false) {
+        return Err("the API key would already be expired".to_string());
+    }
+
+    let mut salt = [0u8; 16];
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut secret);
+    let secret = hex::encode(secret);
+
+    keys.push(ApiKeyEntry {
+        id: id.to_string(),
+        owner: owner.to_string(),
+        salt: hex::encode(salt),
+        hash: hex::encode(hash_secret(&salt, &secret)),
+        created: now,
+        expires,
+        operations,
+    });
+
+    Ok(format!("{}.{}", id, secret))
+}
+
+pub fn revoke_api_key(keys: &mut Vec<ApiKeyEntry>, id: &str) -> Result<(), String> {
+    let before = keys.len();
+    keys.retain(|key| key.id != id);
+    if keys.len() == before {
+        return Err(format!("API key '{}' isn't registered", id));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn apps() -> Vec<AppConfig> {
+        serde_json
+            ::from_value(
+                json!([
+                    { "_id": "1234", "name": "web", "url": "http://web/", "permissions": ["product", "review"] },
+                    { "_id": "1233", "name": "batch", "url": "http://batch/", "permissions": ["*"] }
+                ])
+            )
+            .unwrap()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values
+            .iter()
+            .map(|value| value.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn edits_apps_and_permissions() {
+        let mut apps = apps();
+        let mut new_app = apps[0].clone();
+
+        assert!(add_app(&mut apps, new_app.clone()).unwrap_err().contains("already registered"));
+        new_app._id = "1235".to_string();
+        new_app.permissions = Vec::new();
+        assert!(add_app(&mut apps, new_app.clone()).unwrap_err().contains("no permissions"));
+
+        grant(&mut apps, "1234", &strings(&["product", "Product.costPrice"])).unwrap();
+        assert_eq!(apps[0].permissions, strings(&["product", "review", "Product.costPrice"]));
+
+        revoke(&mut apps, "1234", &strings(&["review"])).unwrap();
+        assert!(revoke(&mut apps, "1234", &strings(&["review"])).is_err());
+        assert!(revoke(&mut apps, "1233", &strings(&["*"])).unwrap_err().contains("no permissions"));
+        assert_eq!(apps[1].permissions, strings(&["*"]));
+
+        assert_eq!(remove_app(&mut apps, "1233").unwrap().name, "batch");
+        assert!(remove_app(&mut apps, "1233").is_err());
+    }
+
+    #[test]
+    fn lints_against_the_schema() {
+        let schema = SchemaTypes::parse("type Query { product: Product } type Product { id: ID }").unwrap();
+        let mut apps = apps();
+        apps.push(apps[1].clone());
+
+        assert_eq!(lint(&apps, Some(&schema)), vec![
+            "app '1234': permission 'review' not found in the schema".to_string(),
+            "app '1233' is registered more than once".to_string(),
+        ]);
+        assert_eq!(lint(&apps, None).len(), 1);
+    }
+
+    #[test]
+    fn diffs_registries() {
+        let old = apps();
+        let mut new = apps();
+        new.remove(1);
+        new[0].url = "http://web-v2/".to_string();
+        new[0].permissions = strings(&["product", "Product.costPrice"]);
+
+        assert_eq!(diff(&old, &new), vec![
+            "- app '1233' (batch)",
+            "~ app '1234': url \"http://web/\" -> \"http://web-v2/\"",
+            "~ app '1234': - permission 'review'",
+            "~ app '1234': + permission 'Product.costPrice'",
+        ]);
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn minted_keys_match_their_hash() {
+        let mut keys = Vec::new();
+        let key = mint_api_key(&mut keys, "ci", "platform", None, strings(&["GetProducts"]), 1000).unwrap();
+
+        let (id, secret) = key.split_once('.').unwrap();
+        assert_eq!(id, "ci");
+        assert_eq!(keys[0].hash, hex::encode(hash_secret(&hex::decode(&keys[0].salt).unwrap(), secret)));
+        assert!(mint_api_key(&mut keys, "ci", "platform", None, Vec::new(), 1000).is_err());
+        assert!(mint_api_key(&mut keys, "ci.prod", "platform", None, Vec::new(), 1000).is_err());
+        assert!(mint_api_key(&mut keys, "old", "platform", Some(900), Vec::new(), 1000).is_err());
+
+        revoke_api_key(&mut keys, "ci").unwrap();
+        assert!(keys.is_empty() && revoke_api_key(&mut keys, "ci").is_err());
+    }
+
+    #[test]
+    fn writes_registries_the_plugin_accepts() {
+        let path = std::env::temp_dir().join(format!("admin-registry-{}.json", std::process::id()));
+
+        write_json(&path, &apps()).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(crate::plugin_functions::parse_apps(&content).unwrap().len(), 2);
+        assert_eq!(read_json::<Vec<AppConfig>>(&path).unwrap(), apps());
+    }
+}
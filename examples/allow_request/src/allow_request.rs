@@ -1,36 +1,80 @@
 use std::ops::ControlFlow;
-use std::path::PathBuf;
+use std::sync::Arc;
 
+use acme_common::credentials::CredentialExtractor;
 use apollo_router::layers::ServiceBuilderExt;
 use apollo_router::plugin::PluginInit;
 use apollo_router::plugin::Plugin;
 use apollo_router::register_plugin;
+use apollo_router::services::subgraph;
 use apollo_router::services::supergraph;
-use http::StatusCode;
-use schemars::JsonSchema;
-use serde::Deserialize;
 use tower::BoxError;
 use tower::ServiceBuilder;
 use tower::ServiceExt;
 
+use acme_router::bypass::BypassRule;
+use acme_router::bypass::BYPASS_CONTEXT_KEY;
+use acme_router::config::AllowRequestConfig;
+use acme_router::config::PLUGIN_NAME;
+use acme_router::field_authorization::DeniedFieldMode;
+use acme_router::field_authorization::DeniedField;
+use acme_router::field_authorization::DENIED_FIELDS_CONTEXT_KEY;
+use acme_router::field_authorization::LOCALE_CONTEXT_KEY;
+use acme_router::field_authorization::redact_response;
+use acme_router::identity::AuthenticatedIdentity;
+use acme_router::identity::IDENTITY_CONTEXT_KEY;
+use acme_router::identity::IdentityHeaders;
+use acme_router::introspection::filter_introspection;
+use acme_router::introspection::introspection_filter;
+use acme_router::introspection::IntrospectionAccess;
+use acme_router::introspection::IntrospectionFilter;
+use acme_router::introspection::INTROSPECTION_FILTER_CONTEXT_KEY;
+use acme_router::jwks::Jwks;
+use acme_router::messages::load_catalog;
+use acme_router::messages::MessageCatalog;
 use acme_router::plugin_functions::validate_operation;
-use acme_router::plugin_functions::error_response;
-use acme_router::plugin_functions::insert_header;
-use acme_router::plugin_functions::introspection;
+use acme_router::plugin_functions::auth_error_response;
+use acme_router::plugin_functions::subgraph_error_response;
+use acme_router::plugin_functions::classify_operation;
+use acme_router::plugin_functions::check_document;
 use acme_router::plugin_functions::get_payload;
 use acme_router::plugin_functions::get_app;
+use acme_router::plugin_functions::AuthError;
+use acme_router::plugin_functions::Payload;
+use acme_router::plugin_functions::verification_keys;
+use acme_router::plugin_functions::MixedIntrospection;
+use acme_router::plugin_functions::OperationKind;
+use acme_router::plugin_functions::TokenError;
+use acme_router::plugin_functions::TokenValidation;
+use acme_router::plugin_functions::VerificationKey;
+use acme_router::schema::SchemaTypes;
+use acme_router::shadow::denied_decision;
+use acme_router::shadow::EnforcementMode;
+use acme_router::shadow::ShadowDecision;
+use acme_router::shadow::SHADOW_DECISION_CONTEXT_KEY;
+use acme_router::registry::check_apps;
+use acme_router::registry::load_registry;
+use acme_router::registry::AppRegistry;
 
-#[derive(Deserialize, JsonSchema)]
-struct AllowRequestConfig {
-    introspection: bool,
-    header: String,
-    path: String,
+struct AllowRequest {
+    mixed_introspection: MixedIntrospection,
+    bypass: Arc<Vec<BypassRule>>,
+    authorizer: Arc<Authorizer>,
 }
 
-struct AllowRequest {
-    introspection: bool,
-    header: String,
-    file_path: PathBuf,
+// Everything needed to authorize a request, shared by every request the plugin handles
+struct Authorizer {
+    mode: EnforcementMode,
+    credentials: CredentialExtractor,
+    apps: Arc<dyn AppRegistry>,
+    keys: Vec<VerificationKey>,
+    jwks: Option<Arc<Jwks>>,
+    token_validation: TokenValidation,
+    on_denied_field: DeniedFieldMode,
+    default_introspection: IntrospectionAccess,
+    schema: Arc<SchemaTypes>,
+    messages: MessageCatalog,
+    identity: IdentityHeaders,
 }
 
 #[async_trait::async_trait]
@@ -38,116 +82,748 @@ impl Plugin for AllowRequest {
     type Config = AllowRequestConfig;
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
-        let AllowRequestConfig { path, header, introspection } = init.config;
-        let file_path = PathBuf::from(path.as_str());
+        let AllowRequestConfig {
+            mode,
+            source,
+            header,
+            credentials,
+            mixed_introspection,
+            default_introspection,
+            bypass,
+            keys,
+            jwks,
+            token_validation,
+            on_denied_field,
+            messages,
+            identity,
+        } = init.config;
+        // Everything is checked here so the router refuses to start with a bad configuration
+        // instead of failing on the first request
+        let credentials = CredentialExtractor::new(header.as_deref(), &credentials).map_err(|err|
+            format!("auth.allow_request: {}", err)
+        )?;
+        if keys.is_empty() && jwks.is_none() {
+            return Err("auth.allow_request needs `keys` or `jwks` to verify tokens".into());
+        }
+        let messages = load_catalog(&messages).map_err(|err| format!("auth.allow_request: {}", err))?;
+        let identity = IdentityHeaders::load(&identity).map_err(|err| format!("auth.allow_request: {}", err))?;
+        let schema = Arc::new(
+            SchemaTypes::parse(&init.supergraph_sdl).map_err(|err| format!("auth.allow_request: {}", err))?
+        );
+        for rule in &bypass {
+            rule.validate(&schema).map_err(|err| format!("auth.allow_request: {}", err))?;
+        }
+
+        let apps = {
+            let schema = schema.clone();
+            load_registry(&source, Arc::new(move |apps| check_apps(&schema, apps))).await.map_err(|err|
+                format!("auth.allow_request: {}", err)
+            )?
+        };
+
+        let keys = verification_keys(&keys).map_err(|err| format!("auth.allow_request: {}", err))?;
+        let jwks = match jwks {
+            Some(config) => Some(Jwks::load(&config).await.map_err(|err| format!("auth.allow_request: {}", err))?),
+            None => None,
+        };
 
         Ok(Self {
-            introspection,
-            file_path,
-            header,
+            mixed_introspection,
+            bypass: Arc::new(bypass),
+            authorizer: Arc::new(Authorizer {
+                mode,
+                credentials,
+                apps,
+                keys,
+                jwks,
+                token_validation,
+                on_denied_field,
+                default_introspection,
+                schema,
+                messages,
+                identity,
+            }),
         })
     }
 
     fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
-        let introspection_cfg = self.introspection.clone();
-        let file_path = self.file_path.clone();
-        let header_key = self.header.clone();
+        let mixed_introspection = self.mixed_introspection;
+        let bypass = self.bypass.clone();
+        let authorizer = self.authorizer.clone();
 
         let handler = move |mut req: supergraph::Request| {
-            let mut res = None;
-
-            //Get query from the body
-            if let Some(query_string) = &req.supergraph_request.body().query {
-                // Check if the introspection is enabled to allow query
-                if introspection_cfg {
-                    if !introspection(query_string) {
-                        // Check if the request has the Authorization header
-                        if !req.supergraph_request.headers().contains_key(&header_key) {
-                            res = error_response(
-                                "No se ha recibido el encabezado de autorización",
-                                StatusCode::UNAUTHORIZED,
-                                "AUTH_ERROR",
-                                &req
-                            );
-                        } else {
-                            // Get token from the Authorization header
-                            if
-                                let Ok(token) = req.supergraph_request
-                                    .headers()
-                                    .get("Authorization")
-                                    .expect("No se pudo extraer el token de la petición")
-                                    .to_str()
-                            {
-                                //Get token Payload
-                                match get_payload(&token) {
-                                    Ok(payload) => {
-                                        if let Ok(app) = get_app(&payload.iss, file_path.clone()) {
-                                            // Validate query to execute
-                                            match validate_operation(&app.permissions, &payload.claims, query_string) {
-                                                Ok(_query) => {
-                                                    insert_header(&mut req, "user_id", &payload._id);
-                                                    insert_header(&mut req, "app_id", &app._id);
-                                                    insert_header(&mut req, "app_name", &app.name);
-                                                    insert_header(&mut req, "app_url", &app.url);
-                                                }
-                                                Err(err) => {
-                                                    res = error_response(
-                                                        err,
-                                                        StatusCode::UNAUTHORIZED,
-                                                        "UNAUTHORIZED",
-                                                        &req
-                                                    );
-                                                }
-                                            }
-                                        } else {
-                                            res = error_response(
-                                                "Aplicación no registrada",
-                                                StatusCode::UNAUTHORIZED,
-                                                "UNAUTHORIZED",
-                                                &req
-                                            );
-                                        }
-                                    }
-                                    Err(_err) => {
-                                        let error_message = format!("Token de acceso no válido: {}", _err);
-                                        res = error_response(
-                                            &error_message,
-                                            StatusCode::UNAUTHORIZED,
-                                            "UNAUTHORIZED",
-                                            &req
-                                        );
-                                    }
-                                }
-                            } else {
-                                res = error_response(
-                                    "Error al validar access Token",
-                                    StatusCode::UNAUTHORIZED,
-                                    "UNAUTHORIZED",
-                                    &req
-                                );
+            let bypass = bypass.clone();
+            let authorizer = authorizer.clone();
+
+            async move {
+                // Only the plugin sets them, also for requests it lets through without a token
+                authorizer.identity.strip(req.supergraph_request.headers_mut());
+
+                let body = req.supergraph_request.body();
+                let result = match body.query.clone() {
+                    None => Err(AuthError::MissingQuery),
+                    Some(query) => {
+                        let operation_name = body.operation_name.clone();
+                        let kind = classify_operation(&query, operation_name.as_deref());
+
+                        match
+                            check_document(
+                                kind,
+                                mixed_introspection,
+                                &bypass,
+                                &query,
+                                operation_name.as_deref(),
+                                &authorizer.schema
+                            )
+                        {
+                            Err(err) => Err(err),
+                            Ok(Some(rule)) => {
+                                let _ = req.context.insert(BYPASS_CONTEXT_KEY, rule.label());
+                                Ok(())
                             }
+                            Ok(None) => authorizer.authorize(&mut req, &query, operation_name.as_deref(), kind).await,
                         }
                     }
+                };
+
+                if authorizer.mode == EnforcementMode::Shadow {
+                    let decision = match result {
+                        Ok(()) => {
+                            let denied = req.context
+                                .get::<_, Vec<DeniedField>>(DENIED_FIELDS_CONTEXT_KEY)
+                                .ok()
+                                .flatten()
+                                .unwrap_or_default();
+                            ShadowDecision::allowed(denied.into_iter().map(|field| field.path).collect())
+                        }
+                        Err(err) => denied_decision(&err, &authorizer.messages),
+                    };
+                    decision.record(PLUGIN_NAME, "supergraph");
+                    let _ = req.context.insert(SHADOW_DECISION_CONTEXT_KEY, decision);
+                    return Ok(ControlFlow::Continue(req));
                 }
-            } else {
-                res = error_response(
-                    "La consulta no puede estar vacía",
-                    StatusCode::BAD_REQUEST,
-                    "GRAPHQL_ERROR",
-                    &req
-                );
-            }
 
-            async {
-                match res {
-                    Some(res) => Ok(ControlFlow::Break(res)),
-                    None => Ok(ControlFlow::Continue(req)),
+                match result {
+                    Ok(()) => Ok(ControlFlow::Continue(req)),
+                    Err(err) => {
+                        match auth_error_response(&err, &authorizer.messages, &req) {
+                            Some(res) => Ok(ControlFlow::Break(res)),
+                            None => Ok(ControlFlow::Continue(req)),
+                        }
+                    }
                 }
             }
         };
 
-        ServiceBuilder::new().oneshot_checkpoint_async(handler).service(service).boxed()
+        let authorizer = self.authorizer.clone();
+
+        ServiceBuilder::new()
+            .map_response(move |res: supergraph::Response| {
+                // Responses are left as they are in shadow mode
+                if authorizer.mode == EnforcementMode::Shadow {
+                    return res;
+                }
+
+                let denied = res.context.get::<_, Vec<DeniedField>>(DENIED_FIELDS_CONTEXT_KEY).ok().flatten();
+                let filter = res.context
+                    .get::<_, IntrospectionFilter>(INTROSPECTION_FILTER_CONTEXT_KEY)
+                    .ok()
+                    .flatten();
+                if denied.is_none() && filter.is_none() {
+                    return res;
+                }
+
+                let authorizer = authorizer.clone();
+                let locale = res.context
+                    .get::<_, String>(LOCALE_CONTEXT_KEY)
+                    .ok()
+                    .flatten()
+                    .unwrap_or_else(|| authorizer.messages.default_locale().to_string());
+
+                res.map_stream(move |mut response| {
+                    if let Some(filter) = &filter {
+                        filter_introspection(&mut response, filter, &authorizer.schema);
+                    }
+                    if let Some(denied) = &denied {
+                        redact_response(&mut response, denied, &authorizer.messages, &locale);
+                    }
+                    response
+                })
+            })
+            .oneshot_checkpoint_async(handler)
+            .service(service)
+            .boxed()
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let authorizer = self.authorizer.clone();
+        let name = name.to_string();
+
+        ServiceBuilder::new()
+            .checkpoint(move |mut req: subgraph::Request| {
+                let identity = req.context.get::<_, AuthenticatedIdentity>(IDENTITY_CONTEXT_KEY).ok().flatten();
+
+                // Bypassed requests have no identity and no app restricting them
+                if let Some(subgraphs) = identity.as_ref().and_then(|identity| identity.subgraphs.as_ref()) {
+                    if !subgraphs.contains(&name) {
+                        let err = AuthError::SubgraphNotAllowed(name.clone());
+
+                        if authorizer.mode == EnforcementMode::Enforce {
+                            let locale = req.context
+                                .get::<_, String>(LOCALE_CONTEXT_KEY)
+                                .ok()
+                                .flatten()
+                                .unwrap_or_else(|| authorizer.messages.default_locale().to_string());
+                            return Ok(
+                                ControlFlow::Break(subgraph_error_response(&err, &authorizer.messages, &locale, &req))
+                            );
+                        }
+
+                        let decision = denied_decision(&err, &authorizer.messages);
+                        decision.record(PLUGIN_NAME, &name);
+                        // The first subgraph the request couldn't reach is kept
+                        let recorded = req.context.get::<_, ShadowDecision>(SHADOW_DECISION_CONTEXT_KEY).ok().flatten();
+                        if recorded.map(|recorded| recorded.allowed).unwrap_or(true) {
+                            let _ = req.context.insert(SHADOW_DECISION_CONTEXT_KEY, decision);
+                        }
+                    }
+                }
+
+                authorizer.identity.insert_subgraph_headers(&name, &mut req, identity.as_ref());
+                Ok(ControlFlow::Continue(req))
+            })
+            .service(service)
+            .boxed()
     }
 }
 
+impl Authorizer {
+    async fn authorize(
+        &self,
+        req: &mut supergraph::Request,
+        query: &str,
+        operation_name: Option<&str>,
+        kind: OperationKind
+    ) -> Result<(), AuthError> {
+        let token = self.credentials.extract(&req.supergraph_request)?;
+
+        let payload = self.verify(&token).await.map_err(AuthError::Token)?;
+        let app = get_app(&payload.iss, self.apps.as_ref()).await?;
+
+        let denied = validate_operation(
+            &app.permissions,
+            &payload.claims,
+            query,
+            operation_name,
+            Some(&self.schema)
+        )?;
+        if !denied.is_empty() && self.on_denied_field == DeniedFieldMode::Reject {
+            return Err(AuthError::FieldsNotAllowed(denied.into_iter().map(|field| field.path).collect()));
+        }
+
+        let introspection_filter = introspection_filter(
+            kind,
+            app.introspection.unwrap_or(self.default_introspection),
+            &app.permissions,
+            &payload.claims,
+            query,
+            operation_name
+        )?;
+
+        self.identity.insert(req, &AuthenticatedIdentity::new(&payload, &app))?;
+
+        // Applied to the response once it comes back
+        if let Some(filter) = introspection_filter {
+            let _ = req.context.insert(INTROSPECTION_FILTER_CONTEXT_KEY, filter);
+        }
+        if !denied.is_empty() {
+            let _ = req.context.insert(DENIED_FIELDS_CONTEXT_KEY, denied);
+        }
+        // Errors raised later, for denied fields or subgraphs, use the request's language
+        let locale = self.messages.request_locale(&req.supergraph_request).to_string();
+        let _ = req.context.insert(LOCALE_CONTEXT_KEY, locale);
+
+        Ok(())
+    }
+
+    async fn verify(&self, token: &str) -> Result<Payload, TokenError> {
+        let payload = get_payload(token, &current_keys(&self.keys, self.jwks.as_ref()), &self.token_validation);
+
+        // The identity provider may have rotated its keys since the last refresh
+        if let (Err(TokenError::UnknownKey), Some(jwks)) = (&payload, &self.jwks) {
+            if jwks.refresh_on_miss().await {
+                return get_payload(token, &current_keys(&self.keys, Some(jwks)), &self.token_validation);
+            }
+        }
+
+        payload
+    }
+}
+
+// Static keys from the configuration plus the current JWKS snapshot
+fn current_keys(keys: &[VerificationKey], jwks: Option<&Arc<Jwks>>) -> Vec<VerificationKey> {
+    let mut current_keys = keys.to_vec();
+    if let Some(jwks) = jwks {
+        current_keys.extend(jwks.keys());
+    }
+    current_keys
+}
+
 register_plugin!("auth", "allow_request", AllowRequest);
+
+#[cfg(test)]
+mod tests {
+    use std::time::{ SystemTime, UNIX_EPOCH };
+
+    use apollo_router::graphql;
+    use apollo_router::plugin::test;
+    use http::HeaderValue;
+    use http::StatusCode;
+    use jsonwebtoken::{ encode, EncodingKey, Header };
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &str = "dev-secret";
+    const SDL: &str = "type Query { product: Product } type Product { id: ID name: String }";
+
+    fn config(source: serde_json::Value) -> AllowRequestConfig {
+        serde_json
+            ::from_value(
+                json!({
+                    "header": "Authorization",
+                    "source": source,
+                    "keys": [{ "algorithm": "HS256", "key": SECRET }],
+                })
+            )
+            .expect("config is valid")
+    }
+
+    async fn plugin(source: serde_json::Value) -> Result<AllowRequest, BoxError> {
+        let init = PluginInit::fake_builder()
+            .config(config(source))
+            .supergraph_sdl(Arc::new(SDL.to_string()))
+            .build();
+        AllowRequest::new(init).await
+    }
+
+    fn token(claims: &[&str]) -> String {
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 300;
+        let payload = json!({ "_id": "user-1", "iss": "1234", "claims": claims, "exp": exp });
+        encode(&Header::default(), &payload, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+    }
+
+    // Sends the request through the plugin, the mock fails the test if the request gets through
+    async fn rejected(plugin: AllowRequest, authorization: HeaderValue) -> (StatusCode, graphql::Error) {
+        rejected_query(plugin, "{ product { id } }", authorization).await
+    }
+
+    async fn rejected_query(
+        plugin: AllowRequest,
+        query: &str,
+        authorization: HeaderValue
+    ) -> (StatusCode, graphql::Error) {
+        let service = plugin.supergraph_service(test::MockSupergraphService::new().boxed());
+        let request = supergraph::Request
+            ::fake_builder()
+            .query(query)
+            .header("Authorization", authorization)
+            .build()
+            .unwrap();
+
+        let mut response = service.oneshot(request).await.unwrap();
+        let status = response.response.status();
+        let graphql_response = response.next_response().await.unwrap();
+        (status, graphql_response.errors[0].clone())
+    }
+
+    fn file_source() -> serde_json::Value {
+        json!({ "file": { "path": "allowedApps.json" } })
+    }
+
+    #[tokio::test]
+    async fn rejects_authorization_header_with_invalid_bytes() {
+        let authorization = HeaderValue::from_bytes(b"Bearer \xff\xfe").unwrap();
+        let (status, error) = rejected(plugin(file_source()).await.unwrap(), authorization).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(error.message, "La credencial de autorización no es válida");
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_without_claims() {
+        let authorization = HeaderValue::from_str(&token(&[])).unwrap();
+        let (status, error) = rejected(plugin(file_source()).await.unwrap(), authorization).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(error.message, "El token no contiene permisos");
+    }
+
+    #[tokio::test]
+    async fn rejects_introspection_mixed_with_data() {
+        let query = "{ ...Meta product { id } } fragment Meta on Query { s: __schema { types { name } } }";
+        let authorization = HeaderValue::from_str(&token(&["*"])).unwrap();
+        let (status, error) = rejected_query(plugin(file_source()).await.unwrap(), query, authorization).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.extensions.get("code"), Some(&"GRAPHQL_VALIDATION_FAILED".into()));
+    }
+
+    #[tokio::test]
+    async fn authorizes_introspection_mixed_with_data_when_configured() {
+        let mut config = config(file_source());
+        config.mixed_introspection = MixedIntrospection::Authorize;
+        config.default_introspection = IntrospectionAccess::Full;
+        let init = PluginInit::fake_builder().config(config).supergraph_sdl(Arc::new(SDL.to_string())).build();
+
+        let mut mock = test::MockSupergraphService::new();
+        mock.expect_call()
+            .times(1)
+            .returning(|req| Ok(supergraph::Response::fake_builder().context(req.context).build().unwrap()));
+        let service = AllowRequest::new(init).await.unwrap().supergraph_service(mock.boxed());
+        let request = supergraph::Request
+            ::fake_builder()
+            .query("{ __schema { types { name } } product { id } }")
+            .header("Authorization", token(&["*"]))
+            .build()
+            .unwrap();
+
+        let response = service.oneshot(request).await.unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn introspection_needs_an_app_allowed_to_introspect() {
+        let query = "{ __schema { types { name } } }";
+
+        let anonymous = HeaderValue::from_static("");
+        let (status, _error) = rejected_query(plugin(file_source()).await.unwrap(), query, anonymous).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let path = std::env::temp_dir().join(format!("no-introspection-{}.json", std::process::id()));
+        let apps = json!([{ "_id": "1234", "name": "app", "url": "http://app/", "permissions": ["product"] }]);
+        std::fs::write(&path, apps.to_string()).unwrap();
+
+        let authorization = HeaderValue::from_str(&token(&["*"])).unwrap();
+        let plugin = plugin(json!({ "file": { "path": path } })).await.unwrap();
+        let (status, error) = rejected_query(plugin, query, authorization).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(error.message, "No tienes permisos para consultar el esquema");
+    }
+
+    #[tokio::test]
+    async fn filters_introspection_to_permitted_fields() {
+        let path = std::env::temp_dir().join(format!("permitted-introspection-{}.json", std::process::id()));
+        let apps = json!([
+            {
+                "_id": "1234",
+                "name": "app",
+                "url": "http://app/",
+                "permissions": ["product.name"],
+                "introspection": "permitted"
+            }
+        ]);
+        std::fs::write(&path, apps.to_string()).unwrap();
+
+        let mut mock = test::MockSupergraphService::new();
+        mock.expect_call()
+            .times(1)
+            .returning(|req| {
+                let types = json!([
+                    { "name": "Query", "fields": [{ "name": "product" }] },
+                    { "name": "Product", "fields": [{ "name": "id" }, { "name": "name" }] }
+                ]);
+                Ok(
+                    supergraph::Response
+                        ::fake_builder()
+                        .data(json!({ "__schema": { "types": types } }))
+                        .context(req.context)
+                        .build()
+                        .unwrap()
+                )
+            });
+        let service = plugin(json!({ "file": { "path": path } })).await.unwrap().supergraph_service(mock.boxed());
+        let request = supergraph::Request
+            ::fake_builder()
+            .query("{ __schema { types { name fields { name } } } }")
+            .header("Authorization", token(&["*"]))
+            .build()
+            .unwrap();
+
+        let mut response = service.oneshot(request).await.unwrap();
+        let data = serde_json::to_value(response.next_response().await.unwrap().data.unwrap()).unwrap();
+        assert_eq!(data["__schema"]["types"][1]["fields"], json!([{ "name": "name" }]));
+    }
+
+    #[tokio::test]
+    async fn registry_introspection_overrides_the_default() {
+        let path = std::env::temp_dir().join(format!("full-introspection-{}.json", std::process::id()));
+        let apps = json!([
+            { "_id": "1234", "name": "app", "url": "http://app/", "permissions": ["product"], "introspection": "full" }
+        ]);
+        std::fs::write(&path, apps.to_string()).unwrap();
+
+        let mut mock = test::MockSupergraphService::new();
+        mock.expect_call()
+            .times(1)
+            .returning(|req| Ok(supergraph::Response::fake_builder().context(req.context).build().unwrap()));
+        let plugin = plugin(json!({ "file": { "path": path } })).await.unwrap();
+        assert_eq!(plugin.authorizer.default_introspection, IntrospectionAccess::None);
+        let request = supergraph::Request
+            ::fake_builder()
+            .query("{ __schema { types { name } } }")
+            .header("Authorization", token(&["*"]))
+            .build()
+            .unwrap();
+
+        let response = plugin.supergraph_service(mock.boxed()).oneshot(request).await.unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn records_the_bypass_rule_in_the_context() {
+        let mut config = config(file_source());
+        config.bypass = vec![
+            BypassRule::Introspection,
+            BypassRule::Operation { name: "Health".to_string(), permissions: vec!["product.id".to_string()] },
+            BypassRule::Typename
+        ];
+        let init = PluginInit::fake_builder().config(config).supergraph_sdl(Arc::new(SDL.to_string())).build();
+
+        let plugin = AllowRequest::new(init).await.unwrap();
+
+        for (query, rule) in [("query Ping { __typename }", "typename"), ("query Health { product { id } }", "operation:Health")] {
+            let mut mock = test::MockSupergraphService::new();
+            mock.expect_call()
+                .times(1)
+                .returning(|req| Ok(supergraph::Response::fake_builder().context(req.context).build().unwrap()));
+            let operation_name = rule.strip_prefix("operation:").unwrap_or("Ping");
+            let request = supergraph::Request::fake_builder().query(query).operation_name(operation_name).build().unwrap();
+            let response = plugin.supergraph_service(mock.boxed()).oneshot(request).await.unwrap();
+
+            assert_eq!(response.context.get::<_, String>(BYPASS_CONTEXT_KEY).unwrap(), Some(rule.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn strips_spoofed_identity_headers_from_bypassed_requests() {
+        let mut config = config(file_source());
+        config.bypass = vec![BypassRule::Typename];
+        let init = PluginInit::fake_builder().config(config).supergraph_sdl(Arc::new(SDL.to_string())).build();
+
+        let mut mock = test::MockSupergraphService::new();
+        mock.expect_call()
+            .times(1)
+            .returning(|req| {
+                assert!(!req.supergraph_request.headers().contains_key("user_id"));
+                Ok(supergraph::Response::fake_builder().context(req.context).build().unwrap())
+            });
+        let service = AllowRequest::new(init).await.unwrap().supergraph_service(mock.boxed());
+        let request = supergraph::Request::fake_builder().query("{ __typename }").header("user_id", "admin").build().unwrap();
+
+        service.oneshot(request).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn localizes_errors_with_accept_language() {
+        let service = plugin(file_source()).await.unwrap().supergraph_service(test::MockSupergraphService::new().boxed());
+        let request = supergraph::Request
+            ::fake_builder()
+            .query("{ product { id } }")
+            .header("Authorization", token(&[]))
+            .header("Accept-Language", "en-US,en;q=0.9")
+            .build()
+            .unwrap();
+
+        let mut response = service.oneshot(request).await.unwrap();
+        let error = response.next_response().await.unwrap().errors[0].clone();
+        assert_eq!(error.message, "The token doesn't contain any permission");
+    }
+
+    #[tokio::test]
+    async fn reads_the_token_from_the_configured_locations() {
+        let mut config = config(file_source());
+        config.header = None;
+        config.credentials = serde_json
+            ::from_value(
+                json!([
+                    { "header": { "name": "Authorization", "prefix": "Bearer" } },
+                    { "query": { "name": "access_token" } }
+                ])
+            )
+            .unwrap();
+        let init = PluginInit::fake_builder().config(config).supergraph_sdl(Arc::new(SDL.to_string())).build();
+        let plugin = AllowRequest::new(init).await.unwrap();
+
+        let bearer = supergraph::Request
+            ::fake_builder()
+            .query("{ product { id } }")
+            .header("Authorization", format!("Bearer {}", token(&["*"])))
+            .build()
+            .unwrap();
+        let mut upgrade = supergraph::Request::fake_builder().query("{ product { id } }").build().unwrap();
+        *upgrade.supergraph_request.uri_mut() = format!("/graphql?access_token={}", token(&["*"])).parse().unwrap();
+
+        for request in [bearer, upgrade] {
+            let mut mock = test::MockSupergraphService::new();
+            mock.expect_call()
+                .times(1)
+                .returning(|req| Ok(supergraph::Response::fake_builder().context(req.context).build().unwrap()));
+            plugin.supergraph_service(mock.boxed()).oneshot(request).await.unwrap();
+        }
+
+        let raw = HeaderValue::from_str(&token(&["*"])).unwrap();
+        let (status, error) = rejected(plugin, raw).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(error.extensions.get("code").unwrap().as_str(), Some("AUTH_ERROR"));
+    }
+
+    #[tokio::test]
+    async fn restricts_the_subgraphs_an_app_can_reach() {
+        let plugin = plugin(file_source()).await.unwrap();
+        let identity = AuthenticatedIdentity {
+            user_id: "user-1".to_string(),
+            app_id: "1234".to_string(),
+            app_name: "app".to_string(),
+            app_url: "http://app/".to_string(),
+            claims: vec!["product".to_string()],
+            permissions: vec!["product".to_string()],
+            subgraphs: Some(vec!["products".to_string()]),
+        };
+        let request = || {
+            let context = apollo_router::Context::new();
+            let _ = context.insert(IDENTITY_CONTEXT_KEY, identity.clone());
+            let _ = context.insert(LOCALE_CONTEXT_KEY, "en".to_string());
+            subgraph::Request::fake_builder().context(context).build()
+        };
+
+        let denied = plugin.subgraph_service("reviews", test::MockSubgraphService::new().boxed());
+        let response = denied.oneshot(request()).await.unwrap();
+        assert_eq!(response.response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.response.body().errors[0].message, "You are not allowed to reach the subgraph 'reviews'");
+
+        let mut mock = test::MockSubgraphService::new();
+        mock.expect_call()
+            .times(1)
+            .returning(|req| Ok(subgraph::Response::fake_builder().context(req.context).build()));
+        plugin.subgraph_service("products", mock.boxed()).oneshot(request()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shadow_mode_records_decisions_without_rejecting() {
+        let mut config = config(file_source());
+        config.mode = EnforcementMode::Shadow;
+        let init = PluginInit::fake_builder().config(config).supergraph_sdl(Arc::new(SDL.to_string())).build();
+        let plugin = AllowRequest::new(init).await.unwrap();
+
+        for (claims, allowed) in [(vec!["*"], true), (vec![], false)] {
+            let mut mock = test::MockSupergraphService::new();
+            mock.expect_call()
+                .times(1)
+                .returning(|req| Ok(supergraph::Response::fake_builder().context(req.context).build().unwrap()));
+            let request = supergraph::Request
+                ::fake_builder()
+                .query("{ product { id } }")
+                .header("Authorization", token(&claims))
+                .build()
+                .unwrap();
+
+            let response = plugin.supergraph_service(mock.boxed()).oneshot(request).await.unwrap();
+            let decision = response.context.get::<_, ShadowDecision>(SHADOW_DECISION_CONTEXT_KEY).unwrap().unwrap();
+            assert_eq!(decision.allowed, allowed);
+            if !allowed {
+                assert_eq!(decision.code.as_deref(), Some(AuthError::MissingClaims.extension_code()));
+            }
+        }
+
+        let context = apollo_router::Context::new();
+        let identity = AuthenticatedIdentity {
+            user_id: "user-1".to_string(),
+            app_id: "1234".to_string(),
+            app_name: "app".to_string(),
+            app_url: "http://app/".to_string(),
+            claims: vec!["*".to_string()],
+            permissions: vec!["*".to_string()],
+            subgraphs: Some(vec!["products".to_string()]),
+        };
+        let _ = context.insert(IDENTITY_CONTEXT_KEY, identity);
+        let _ = context.insert(SHADOW_DECISION_CONTEXT_KEY, ShadowDecision::allowed(Vec::new()));
+
+        let mut mock = test::MockSubgraphService::new();
+        mock.expect_call()
+            .times(1)
+            .returning(|req| Ok(subgraph::Response::fake_builder().context(req.context).build()));
+        let request = subgraph::Request::fake_builder().context(context).build();
+        let response = plugin.subgraph_service("reviews", mock.boxed()).oneshot(request).await.unwrap();
+
+        let decision = response.context.get::<_, ShadowDecision>(SHADOW_DECISION_CONTEXT_KEY).unwrap().unwrap();
+        assert_eq!(decision.code.as_deref(), Some(AuthError::SubgraphNotAllowed(String::new()).extension_code()));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_start_with_corrupt_registry() {
+        let path = std::env::temp_dir().join(format!("corrupt-registry-{}.json", std::process::id()));
+        std::fs::write(&path, "[{ \"_id\": ").unwrap();
+
+        assert!(plugin(json!({ "file": { "path": path } })).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_to_start_with_invalid_configuration() {
+        let missing = plugin(json!({ "file": { "path": "alowedApps.json" } })).await.err().unwrap();
+        assert!(missing.to_string().contains("alowedApps.json"), "{}", missing);
+
+        let mut unknown_key = json!({
+            "header": "Authorization",
+            "source": file_source(),
+            "keys": [],
+        });
+        unknown_key["on_denied_fields"] = json!("reject");
+        assert!(serde_json::from_value::<AllowRequestConfig>(unknown_key.clone()).is_err());
+        // Replaced by `default_introspection` and the registry's per-app `introspection`
+        unknown_key.as_object_mut().unwrap().remove("on_denied_fields");
+        unknown_key["introspection"] = json!(true);
+        assert!(serde_json::from_value::<AllowRequestConfig>(unknown_key).is_err());
+
+        let mut bad_header = config(file_source());
+        bad_header.header = Some("Authorization header".to_string());
+        let init = PluginInit::fake_builder().config(bad_header).supergraph_sdl(Arc::new(SDL.to_string())).build();
+        assert!(AllowRequest::new(init).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_to_start_with_permissions_outside_the_schema() {
+        let path = std::env::temp_dir().join(format!("unknown-permissions-{}.json", std::process::id()));
+        let apps = json!([
+            { "_id": "1234", "name": "app", "url": "http://app/", "permissions": ["product", "Product.price"] }
+        ]);
+        std::fs::write(&path, apps.to_string()).unwrap();
+
+        let err = plugin(json!({ "file": { "path": path } })).await.err().unwrap();
+        assert!(err.to_string().contains("Product.price (1234)"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn registry_failures_are_server_errors() {
+        let path = std::env::temp_dir().join(format!("unavailable-registry-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE apps (
+                    _id TEXT PRIMARY KEY, name TEXT, url TEXT, permissions TEXT, introspection TEXT, subgraphs TEXT
+                 );
+                 INSERT INTO apps VALUES ('1234', 'app', 'http://app/', '[\"product\"]', NULL, NULL);"
+            )
+            .unwrap();
+
+        let plugin = plugin(json!({ "sqlite": { "path": path } })).await.unwrap();
+        connection.execute_batch("DROP TABLE apps;").unwrap();
+
+        let authorization = HeaderValue::from_str(&token(&["*"])).unwrap();
+        let (status, error) = rejected(plugin, authorization).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error.extensions.get("code"), Some(&"SERVICE_UNAVAILABLE".into()));
+    }
+}
@@ -0,0 +1,258 @@
+use std::path::{ Path, PathBuf };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use acme_common::api_keys::ApiKeyEntry;
+use anyhow::{ anyhow, Result };
+use clap::{ Parser, Subcommand };
+
+use acme_router::admin;
+use acme_router::introspection::IntrospectionAccess;
+use acme_router::plugin_functions::AppConfig;
+use acme_router::schema::SchemaTypes;
+
+// Edits the registries the plugins read. Every change is validated like the plugin would before
+// the file is replaced, so a bad edit never reaches a running router
+#[derive(Parser)]
+#[command(name = "acme_admin", about = "Manages the app, API key and client id registries of acme_router")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Register, remove or list apps")]
+    Apps {
+        #[arg(long, default_value = "allowedApps.json")]
+        registry: PathBuf,
+        #[command(subcommand)]
+        command: AppsCommand,
+    },
+    #[command(about = "Grant or revoke app permissions")]
+    Permissions {
+        #[arg(long, default_value = "allowedApps.json")]
+        registry: PathBuf,
+        #[command(subcommand)]
+        command: PermissionsCommand,
+    },
+    #[command(about = "Mint or revoke API keys")]
+    Keys {
+        #[arg(long, default_value = "allowedApiKeys.json")]
+        registry: PathBuf,
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+    #[command(about = "Add or remove plain client ids")]
+    ClientIds {
+        #[arg(long, default_value = "allowedClientIds.json")]
+        registry: PathBuf,
+        #[command(subcommand)]
+        command: ClientIdsCommand,
+    },
+    #[command(about = "Report every problem of an app registry, exits with 1 when there is any")]
+    Lint {
+        #[arg(long, default_value = "allowedApps.json")]
+        registry: PathBuf,
+        #[arg(long, help = "Supergraph schema to check permissions and subgraphs against")]
+        schema: Option<PathBuf>,
+    },
+    #[command(about = "Show what changes between two versions of an app registry")]
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum AppsCommand {
+    List,
+    Add {
+        #[arg(long)]
+        id: String,
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        url: String,
+        #[arg(long = "permission", required = true)]
+        permissions: Vec<String>,
+        #[arg(long, value_parser = parse_introspection, help = "none, full or permitted")]
+        introspection: Option<IntrospectionAccess>,
+        #[arg(long = "subgraph")]
+        subgraphs: Vec<String>,
+    },
+    Remove {
+        #[arg(long)]
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum PermissionsCommand {
+    Grant {
+        #[arg(long)]
+        id: String,
+        #[arg(required = true)]
+        permissions: Vec<String>,
+    },
+    Revoke {
+        #[arg(long)]
+        id: String,
+        #[arg(required = true)]
+        permissions: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    #[command(about = "Prints the new key, it can't be shown again")]
+    Mint {
+        #[arg(long)]
+        id: String,
+        #[arg(long)]
+        owner: String,
+        #[arg(long)]
+        expires_in_days: Option<u64>,
+        #[arg(long = "operation", help = "Operation names the key can send, only enforced with persisted queries")]
+        operations: Vec<String>,
+    },
+    Revoke {
+        #[arg(long)]
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ClientIdsCommand {
+    Add {
+        id: String,
+    },
+    Remove {
+        id: String,
+    },
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Apps { registry, command } => apps(&registry, command),
+        Command::Permissions { registry, command } => permissions(&registry, command),
+        Command::Keys { registry, command } => keys(&registry, command),
+        Command::ClientIds { registry, command } => client_ids(&registry, command),
+        Command::Lint { registry, schema } => lint(&registry, schema.as_deref()),
+        Command::Diff { old, new } => {
+            let old: Vec<AppConfig> = admin::read_json(&old).map_err(|err| anyhow!(err))?;
+            let new: Vec<AppConfig> = admin::read_json(&new).map_err(|err| anyhow!(err))?;
+            for change in admin::diff(&old, &new) {
+                println!("{}", change);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn apps(registry: &Path, command: AppsCommand) -> Result<()> {
+    let mut apps: Vec<AppConfig> = admin::read_json(registry).map_err(|err| anyhow!(err))?;
+
+    match command {
+        AppsCommand::List => {
+            for app in &apps {
+                println!("{}\t{}\t{}\t{}", app._id, app.name, app.url, app.permissions.join(","));
+            }
+            return Ok(());
+        }
+        AppsCommand::Add { id, name, url, permissions, introspection, subgraphs } => {
+            let subgraphs = if subgraphs.is_empty() { None } else { Some(subgraphs) };
+            let app = AppConfig { _id: id, name, url, permissions, introspection, subgraphs };
+            admin::add_app(&mut apps, app).map_err(|err| anyhow!(err))?;
+        }
+        AppsCommand::Remove { id } => {
+            admin::remove_app(&mut apps, &id).map_err(|err| anyhow!(err))?;
+        }
+    }
+
+    admin::write_json(registry, &apps).map_err(|err| anyhow!(err))
+}
+
+fn permissions(registry: &Path, command: PermissionsCommand) -> Result<()> {
+    let mut apps: Vec<AppConfig> = admin::read_json(registry).map_err(|err| anyhow!(err))?;
+
+    match command {
+        PermissionsCommand::Grant { id, permissions } => admin::grant(&mut apps, &id, &permissions),
+        PermissionsCommand::Revoke { id, permissions } => admin::revoke(&mut apps, &id, &permissions),
+    }.map_err(|err| anyhow!(err))?;
+
+    admin::write_json(registry, &apps).map_err(|err| anyhow!(err))
+}
+
+fn keys(registry: &Path, command: KeysCommand) -> Result<()> {
+    // A registry that doesn't exist yet starts empty
+    let mut keys: Vec<ApiKeyEntry> = if registry.exists() {
+        admin::read_json(registry).map_err(|err| anyhow!(err))?
+    } else {
+        Vec::new()
+    };
+
+    match command {
+        KeysCommand::Mint { id, owner, expires_in_days, operations } => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let expires = expires_in_days.map(|days| now + days * 24 * 60 * 60);
+            let key = admin::mint_api_key(&mut keys, &id, &owner, expires, operations, now).map_err(|err| anyhow!(err))?;
+            admin::write_json(registry, &keys).map_err(|err| anyhow!(err))?;
+            println!("{}", key);
+            Ok(())
+        }
+        KeysCommand::Revoke { id } => {
+            admin::revoke_api_key(&mut keys, &id).map_err(|err| anyhow!(err))?;
+            admin::write_json(registry, &keys).map_err(|err| anyhow!(err))
+        }
+    }
+}
+
+fn client_ids(registry: &Path, command: ClientIdsCommand) -> Result<()> {
+    let mut ids: Vec<String> = admin::read_json(registry).map_err(|err| anyhow!(err))?;
+
+    match command {
+        ClientIdsCommand::Add { id } => {
+            if id.trim().is_empty() || ids.contains(&id) {
+                return Err(anyhow!("client id {:?} is empty or already allowed", id));
+            }
+            ids.push(id);
+            ids.sort();
+        }
+        ClientIdsCommand::Remove { id } => {
+            let before = ids.len();
+            ids.retain(|allowed| allowed != &id);
+            if ids.len() == before {
+                return Err(anyhow!("client id {:?} isn't allowed", id));
+            }
+        }
+    }
+
+    admin::write_json(registry, &ids).map_err(|err| anyhow!(err))
+}
+
+fn lint(registry: &Path, schema: Option<&Path>) -> Result<()> {
+    let apps: Vec<AppConfig> = admin::read_json(registry).map_err(|err| anyhow!(err))?;
+    let schema = match schema {
+        Some(path) => {
+            let sdl = std::fs::read_to_string(path).map_err(|err| anyhow!("could not read {:?}: {}", path, err))?;
+            Some(SchemaTypes::parse(&sdl).map_err(|err| anyhow!(err))?)
+        }
+        None => None,
+    };
+
+    let problems = admin::lint(&apps, schema.as_ref());
+    if problems.is_empty() {
+        println!("{:?}: {} apps, no problems found", registry, apps.len());
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    std::process::exit(1)
+}
+
+fn parse_introspection(value: &str) -> Result<IntrospectionAccess, String> {
+    serde_json::from_value(serde_json::Value::String(value.to_string())).map_err(|_err|
+        format!("{:?} isn't one of none, full or permitted", value)
+    )
+}
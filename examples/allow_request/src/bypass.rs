@@ -0,0 +1,146 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::field_authorization::{ denied_fields, Permissions };
+use crate::plugin_functions::OperationKind;
+use crate::schema::SchemaTypes;
+
+// Name of the rule that let the request through without a token, for auditing
+pub const BYPASS_CONTEXT_KEY: &str = "acme::allow_request::bypass";
+
+// Requests let through before any token check. Rules are tried in order, the first that
+// matches is recorded in the context
+#[derive(Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum BypassRule {
+    // Operations selecting only `__typename`, like health checks
+    Typename,
+    // Introspection of the whole schema, without an app
+    Introspection,
+    // An operation with this name, as long as it only selects what the permissions grant.
+    // Otherwise any client could name its operation after a public one
+    Operation {
+        name: String,
+        permissions: Vec<String>,
+    },
+}
+
+impl BypassRule {
+    pub fn matches(
+        &self,
+        kind: OperationKind,
+        query: &str,
+        operation_name: Option<&str>,
+        schema: &SchemaTypes
+    ) -> bool {
+        match self {
+            BypassRule::Typename => kind == OperationKind::Typename,
+            BypassRule::Introspection => kind == OperationKind::Introspection,
+            // Introspection isn't a denied field, so the name of a public operation would
+            // otherwise unlock the whole schema whatever the app's introspection access
+            BypassRule::Operation { name, permissions } => {
+                matches!(kind, OperationKind::Business | OperationKind::Typename) &&
+                    operation_name == Some(name.as_str()) &&
+                    denied_fields(&Permissions::parse(permissions), query, operation_name, Some(schema))
+                        .map(|denied| denied.is_empty())
+                        .unwrap_or(false)
+            }
+        }
+    }
+
+    pub fn label(&self) -> String {
+        match self {
+            BypassRule::Typename => "typename".to_string(),
+            BypassRule::Introspection => "introspection".to_string(),
+            BypassRule::Operation { name, .. } => format!("operation:{}", name),
+        }
+    }
+
+    pub fn validate(&self, schema: &SchemaTypes) -> Result<(), String> {
+        match self {
+            BypassRule::Operation { name, permissions } => {
+                if name.trim().is_empty() {
+                    return Err("a bypass operation has an empty `name`".to_string());
+                }
+                if permissions.is_empty() {
+                    return Err(format!("bypass operation '{}' has no permissions", name));
+                }
+                let unknown = schema.unknown_permissions(permissions);
+                if !unknown.is_empty() {
+                    return Err(
+                        format!(
+                            "permissions of bypass operation '{}' not found in the supergraph schema: {}",
+                            name,
+                            unknown.join(", ")
+                        )
+                    );
+                }
+                Ok(())
+            }
+            _rule => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin_functions::classify_operation;
+
+    const SDL: &str = "type Query { products: [Product] product(id: ID): Product } type Product { id: ID name: String costPrice: Float }";
+
+    fn operation(permissions: &[&str]) -> BypassRule {
+        BypassRule::Operation {
+            name: "PublicProducts".to_string(),
+            permissions: permissions
+                .iter()
+                .map(|permission| permission.to_string())
+                .collect(),
+        }
+    }
+
+    fn matches(rule: &BypassRule, query: &str, operation_name: Option<&str>) -> bool {
+        let schema = SchemaTypes::parse(SDL).unwrap();
+        rule.matches(classify_operation(query, operation_name), query, operation_name, &schema)
+    }
+
+    #[test]
+    fn matches_typename_and_introspection_only() {
+        assert!(matches(&BypassRule::Typename, "{ __typename }", None));
+        assert!(!matches(&BypassRule::Typename, "{ __typename products { id } }", None));
+        assert!(matches(&BypassRule::Introspection, "{ __type(name: \"Product\") { name } }", None));
+        assert!(!matches(&BypassRule::Introspection, "{ __schema { types { name } } products { id } }", None));
+    }
+
+    #[test]
+    fn named_operations_are_limited_to_their_permissions() {
+        let rule = operation(&["products.name"]);
+
+        assert!(matches(&rule, "query PublicProducts { products { name } }", Some("PublicProducts")));
+        assert!(!matches(&rule, "query PublicProducts { products { name costPrice } }", Some("PublicProducts")));
+        assert!(!matches(&rule, "query Other { products { name } }", Some("Other")));
+        assert!(!matches(&rule, "query PublicProducts { products { name } }", None));
+    }
+
+    #[test]
+    fn named_operations_cannot_carry_introspection() {
+        let rule = operation(&["products.name"]);
+        let introspection = "query PublicProducts { __schema { types { name fields { name } } } }";
+        let mixed = "query PublicProducts { __type(name: \"Product\") { name } products { name } }";
+
+        assert!(!matches(&rule, introspection, Some("PublicProducts")));
+        assert!(!matches(&rule, mixed, Some("PublicProducts")));
+        assert!(matches(&rule, "query PublicProducts { __typename products { name } }", Some("PublicProducts")));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let schema = SchemaTypes::parse(SDL).unwrap();
+
+        assert!(operation(&["products.name"]).validate(&schema).is_ok());
+        assert!(operation(&[]).validate(&schema).is_err());
+        assert!(operation(&["products.price"]).validate(&schema).unwrap_err().contains("products.price"));
+        assert!(serde_json::from_str::<BypassRule>(r#"{ "operation": { "name": "A" } }"#).is_err());
+        assert_eq!(serde_json::from_str::<BypassRule>(r#""typename""#).unwrap(), BypassRule::Typename);
+    }
+}
@@ -0,0 +1,155 @@
+use acme_common::credentials::CredentialSource;
+use acme_common::messages::MessagesConfig;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::bypass::BypassRule;
+use crate::field_authorization::DeniedFieldMode;
+use crate::identity::IdentityConfig;
+use crate::introspection::IntrospectionAccess;
+use crate::jwks::JwksConfig;
+use crate::plugin_functions::{ KeyConfig, MixedIntrospection, TokenValidation };
+use crate::registry::RegistrySource;
+use crate::shadow::EnforcementMode;
+
+// Key of the plugin under `plugins` in router.yaml
+pub const PLUGIN_NAME: &str = "auth.allow_request";
+
+// The `auth.allow_request` section of router.yaml
+#[derive(Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AllowRequestConfig {
+    // `shadow` only records what `enforce` would do, to try a configuration on real traffic
+    #[serde(default)]
+    pub mode: EnforcementMode,
+    #[serde(default)]
+    pub mixed_introspection: MixedIntrospection,
+    // Introspection access of apps whose registry entry doesn't set `introspection`
+    #[serde(default)]
+    pub default_introspection: IntrospectionAccess,
+    // Evaluated in order before any token check
+    #[serde(default)]
+    pub bypass: Vec<BypassRule>,
+    // A single header read as is, the shorthand for `credentials`
+    pub header: Option<String>,
+    // Locations the token is read from, the first one the request has is used
+    #[serde(default)]
+    pub credentials: Vec<CredentialSource>,
+    pub source: RegistrySource,
+    #[serde(default)]
+    pub keys: Vec<KeyConfig>,
+    pub jwks: Option<JwksConfig>,
+    #[serde(default)]
+    pub token_validation: TokenValidation,
+    #[serde(default)]
+    pub on_denied_field: DeniedFieldMode,
+    #[serde(default)]
+    pub messages: MessagesConfig,
+    // Headers forwarded to subgraphs, `user_id`, `app_id`, `app_name` and `app_url` by default
+    #[serde(default)]
+    pub identity: IdentityConfig,
+}
+
+// The plugin configuration from a whole router.yaml, with `${env.NAME}` expanded in its values
+// the way the router does, for tools that run outside of it
+pub fn from_router_yaml(content: &str) -> Result<AllowRequestConfig, String> {
+    plugin_config(content, &(|name| std::env::var(name).ok()))
+}
+
+// `lookup` resolves the variables, the process environment outside of tests
+fn plugin_config(content: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<AllowRequestConfig, String> {
+    let router: serde_yaml::Value = serde_yaml::from_str(content).map_err(|err| err.to_string())?;
+    let mut plugin = router
+        .get("plugins")
+        .and_then(|plugins| plugins.get(PLUGIN_NAME))
+        .cloned()
+        .ok_or_else(|| format!("there is no `plugins.{}` section", PLUGIN_NAME))?;
+    expand_values(&mut plugin, lookup)?;
+
+    serde_yaml::from_value(plugin).map_err(|err| format!("{}: {}", PLUGIN_NAME, err))
+}
+
+fn expand_values(value: &mut serde_yaml::Value, lookup: &dyn Fn(&str) -> Option<String>) -> Result<(), String> {
+    match value {
+        serde_yaml::Value::String(string) => {
+            *string = expand_env(string, lookup)?;
+        }
+        serde_yaml::Value::Sequence(values) => {
+            for value in values {
+                expand_values(value, lookup)?;
+            }
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            for (_key, value) in mapping.iter_mut() {
+                expand_values(value, lookup)?;
+            }
+        }
+        _value => {}
+    }
+    Ok(())
+}
+
+// `${env.NAME}` and `${env.NAME:-default}`, unset variables without a default are an error
+fn expand_env(content: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = rest.find("${env.") {
+        expanded.push_str(&rest[..start]);
+        let variable = &rest[start + "${env.".len()..];
+        let end = variable.find('}').ok_or_else(|| "unterminated `${env.` expansion".to_string())?;
+
+        let (name, default) = match variable[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&variable[..end], None),
+        };
+        match (lookup(name), default) {
+            (Some(value), _default) => expanded.push_str(&value),
+            (None, Some(default)) => expanded.push_str(default),
+            (None, None) => {
+                return Err(format!("environment variable {} is not set", name));
+            }
+        }
+        rest = &variable[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn environment(variables: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let variables: HashMap<String, String> = variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| variables.get(name).cloned()
+    }
+
+    #[test]
+    fn reads_the_plugin_section_of_router_yaml() {
+        let content = std::fs::read_to_string("router.yaml").unwrap();
+
+        let config = plugin_config(&content, &environment(&[("ALLOW_REQUEST_HS256_SECRET", "from-env")])).unwrap();
+        assert_eq!(config.keys[0].key, "from-env");
+        assert_eq!(config.token_validation.clock_skew_secs, 60);
+        assert_eq!(config.bypass, vec![BypassRule::Typename]);
+        assert!(plugin_config(&content, &environment(&[])).is_err());
+    }
+
+    #[test]
+    fn expands_environment_variables() {
+        let lookup = environment(&[("NAME", "value")]);
+
+        assert_eq!(expand_env("a: ${env.NAME}!", &lookup).unwrap(), "a: value!");
+        assert_eq!(expand_env("a: ${env.UNSET:-fallback}", &lookup).unwrap(), "a: fallback");
+        assert!(expand_env("a: ${env.UNSET}", &lookup).is_err());
+        assert!(expand_env("a: ${env.NAME", &lookup).is_err());
+        assert!(plugin_config("plugins: {}", &lookup).is_err());
+    }
+}
@@ -0,0 +1,397 @@
+use std::sync::Arc;
+
+use jsonwebtoken::{ decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation };
+use serde_json::json;
+
+use crate::config::AllowRequestConfig;
+use crate::field_authorization::DeniedFieldMode;
+use crate::introspection::introspection_filter;
+use crate::jwks::{ Jwks, JwksConfig };
+use crate::messages::bundled_catalog;
+use crate::plugin_functions::{
+    check_document,
+    classify_operation,
+    get_app,
+    get_payload,
+    granted_permissions,
+    validate_operation,
+    verification_keys,
+    AuthError,
+    KeyAlgorithm,
+    OperationKind,
+    VerificationKey,
+};
+use crate::registry::{ check_apps, load_registry, AppRegistry };
+use crate::schema::SchemaTypes;
+
+// The same variable router.yaml reads the HS256 secret from
+pub const DEV_SECRET_ENV: &str = "ALLOW_REQUEST_HS256_SECRET";
+
+// Explanations always use the bundled English messages
+const LOCALE: &str = "en";
+
+// The key tokens are signed with, a shared secret for HS256 or a PEM encoded private key
+pub struct SigningKey {
+    pub algorithm: KeyAlgorithm,
+    pub kid: Option<String>,
+    pub key: String,
+}
+
+// Signs a token the plugin accepts once `iss` is registered in the app registry
+pub fn mint_token(
+    signing_key: &SigningKey,
+    iss: &str,
+    user_id: &str,
+    claims: &[String],
+    ttl_secs: u64,
+    now: u64
+) -> Result<String, String> {
+    let (algorithm, key) = match signing_key.algorithm {
+        KeyAlgorithm::HS256 => (Algorithm::HS256, Ok(EncodingKey::from_secret(signing_key.key.as_bytes()))),
+        KeyAlgorithm::RS256 => (Algorithm::RS256, EncodingKey::from_rsa_pem(signing_key.key.as_bytes())),
+        KeyAlgorithm::ES256 => (Algorithm::ES256, EncodingKey::from_ec_pem(signing_key.key.as_bytes())),
+    };
+    let key = key.map_err(|err| format!("invalid {:?} signing key: {}", signing_key.algorithm, err))?;
+
+    let mut header = Header::new(algorithm);
+    header.kid = signing_key.kid.clone();
+    let payload = json!({ "_id": user_id, "iss": iss, "claims": claims, "iat": now, "exp": now + ttl_secs });
+
+    encode(&header, &payload, &key).map_err(|err| err.to_string())
+}
+
+// Header and payload as they are, the signature isn't verified
+pub fn decode_unverified(token: &str) -> Result<(serde_json::Value, serde_json::Value), String> {
+    let header = jsonwebtoken::decode_header(token).map_err(|err| format!("not a JWT: {}", err))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.insecure_disable_signature_validation();
+    validation.required_spec_claims.clear();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    let payload = decode::<serde_json::Value>(token, &DecodingKey::from_secret(&[]), &validation).map_err(|err|
+        format!("not a JWT: {}", err)
+    )?;
+
+    Ok((serde_json::to_value(&header).map_err(|err| err.to_string())?, payload.claims))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: String) -> Check {
+        Check { name, passed: true, detail }
+    }
+
+    fn fail(name: &'static str, err: &AuthError, context: Option<String>) -> Check {
+        let message = err.localized(bundled_catalog(), LOCALE);
+        let detail = match context {
+            Some(context) => format!("{} ({}): {}", err.extension_code(), message, context),
+            None => format!("{} ({})", err.extension_code(), message),
+        };
+        Check { name, passed: false, detail }
+    }
+}
+
+// Runs the plugin's checks on a request, in the plugin's order and with its configuration,
+// and stops at the first failure. Shadow mode is ignored, explanations are what `enforce` does
+pub struct Explainer {
+    config: AllowRequestConfig,
+    keys: Vec<VerificationKey>,
+    apps: Arc<dyn AppRegistry>,
+    schema: Arc<SchemaTypes>,
+}
+
+impl Explainer {
+    // Loaded like the plugin loads them, so configurations it refuses are refused here too
+    pub async fn load(config: AllowRequestConfig, supergraph_sdl: &str) -> Result<Explainer, String> {
+        let schema = Arc::new(SchemaTypes::parse(supergraph_sdl)?);
+        for rule in &config.bypass {
+            rule.validate(&schema)?;
+        }
+
+        let apps = {
+            let schema = schema.clone();
+            load_registry(&config.source, Arc::new(move |apps| check_apps(&schema, apps))).await?
+        };
+
+        let mut keys = verification_keys(&config.keys)?;
+        if let Some(jwks) = &config.jwks {
+            // Fetched once, there is nothing to keep refreshing for a single explanation
+            let jwks = Jwks::load(&(JwksConfig { refresh_interval_secs: 0, ..jwks.clone() })).await?;
+            keys.extend(jwks.keys());
+        }
+        if keys.is_empty() {
+            return Err("the configuration has no `keys` or `jwks` to verify tokens".to_string());
+        }
+
+        Ok(Explainer { config, keys, apps, schema })
+    }
+
+    pub async fn explain(&self, token: &str, query: &str, operation_name: Option<&str>) -> Vec<Check> {
+        let mut checks = Vec::new();
+
+        let kind = classify_operation(query, operation_name);
+        let bypass = check_document(
+            kind,
+            self.config.mixed_introspection,
+            &self.config.bypass,
+            query,
+            operation_name,
+            &self.schema
+        );
+        match bypass {
+            Err(err) => {
+                checks.push(Check::fail("document", &err, None));
+                return checks;
+            }
+            Ok(Some(rule)) => {
+                checks.push(Check::pass("document", format!("{:?} operation", kind).to_lowercase()));
+                checks.push(Check::pass("bypass", format!("'{}' lets it through without a token", rule.label())));
+                return checks;
+            }
+            Ok(None) => {
+                checks.push(Check::pass("document", format!("{:?} operation", kind).to_lowercase()));
+            }
+        }
+
+        let payload = match get_payload(token, &self.keys, &self.config.token_validation) {
+            Ok(payload) => payload,
+            Err(err) => {
+                checks.push(Check::fail("token", &AuthError::Token(err), None));
+                return checks;
+            }
+        };
+        checks.push(Check::pass("token", format!("verified, issued for user '{}'", payload._id)));
+
+        let app = match get_app(&payload.iss, self.apps.as_ref()).await {
+            Ok(app) => app,
+            Err(err) => {
+                checks.push(Check::fail("app", &err, Some(format!("`iss` is '{}'", payload.iss))));
+                return checks;
+            }
+        };
+        checks.push(Check::pass("app", format!("'{}' is registered as '{}'", app._id, app.name)));
+
+        if let Err(err) = granted_permissions(&app.permissions, &payload.claims) {
+            checks.push(Check::fail("claims", &err, None));
+            return checks;
+        }
+        let detail = if payload.claims.first().map(String::as_str) == Some("*") {
+            format!("`*` grants the app permissions: {}", app.permissions.join(", "))
+        } else {
+            format!("the claims narrow the app permissions: {}", payload.claims.join(", "))
+        };
+        checks.push(Check::pass("claims", detail));
+
+        let denied = validate_operation(&app.permissions, &payload.claims, query, operation_name, Some(&self.schema));
+        let denied = match denied {
+            Ok(denied) => denied,
+            Err(err @ AuthError::OperationNotAllowed) => {
+                checks.push(Check::pass("operation", selected(operation_name)));
+                checks.push(Check::fail("root fields", &err, None));
+                return checks;
+            }
+            Err(err) => {
+                checks.push(Check::fail("operation", &err, None));
+                return checks;
+            }
+        };
+        checks.push(Check::pass("operation", selected(operation_name)));
+        checks.push(Check::pass("root fields", "every root field is granted".to_string()));
+
+        let paths = denied.into_iter().map(|field| field.path).collect::<Vec<_>>();
+        if paths.is_empty() {
+            checks.push(Check::pass("nested fields", "every nested field is granted".to_string()));
+        } else if self.config.on_denied_field == DeniedFieldMode::Reject {
+            checks.push(Check::fail("nested fields", &AuthError::FieldsNotAllowed(paths), None));
+            return checks;
+        } else {
+            checks.push(Check::pass("nested fields", format!("redacted from the response: {}", paths.join(", "))));
+        }
+
+        let access = app.introspection.unwrap_or(self.config.default_introspection);
+        match introspection_filter(kind, access, &app.permissions, &payload.claims, query, operation_name) {
+            Err(err) => {
+                checks.push(Check::fail("introspection", &err, None));
+                return checks;
+            }
+            Ok(Some(_filter)) => {
+                checks.push(Check::pass("introspection", "filtered to the app permissions".to_string()));
+            }
+            Ok(None) if matches!(kind, OperationKind::Typename | OperationKind::Business) => {
+                checks.push(Check::pass("introspection", "the operation doesn't introspect the schema".to_string()));
+            }
+            Ok(None) => {
+                checks.push(Check::pass("introspection", "the app has full access".to_string()));
+            }
+        }
+
+        // Only the query plan knows which subgraphs the request reaches
+        let detail = match &app.subgraphs {
+            None => "every subgraph can be reached".to_string(),
+            Some(subgraphs) => format!("only {} can be reached, checked on each subgraph fetch", subgraphs.join(", ")),
+        };
+        checks.push(Check::pass("subgraphs", detail));
+
+        checks
+    }
+}
+
+fn selected(operation_name: Option<&str>) -> String {
+    let operation = operation_name.map(|name| format!("'{}'", name)).unwrap_or_else(|| "the operation".to_string());
+    format!("{} was selected", operation)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plugin_functions::{ KeyConfig, TokenValidation };
+
+    use super::*;
+
+    const SECRET: &str = "dev-secret";
+    const SDL: &str = "type Query { product: Product, review: String } type Product { id: ID, price: Int }";
+
+    fn hs256() -> SigningKey {
+        SigningKey { algorithm: KeyAlgorithm::HS256, kid: None, key: SECRET.to_string() }
+    }
+
+    fn now() -> u64 {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    // The plugin configuration with `overrides` on top, each test uses its own registry file
+    async fn explainer(name: &str, overrides: serde_json::Value) -> Explainer {
+        let path = std::env::temp_dir().join(format!("explain-{}-{}.json", name, std::process::id()));
+        let apps = json!([{ "_id": "1234", "name": "shop", "url": "http://shop/", "permissions": ["product"] }]);
+        std::fs::write(&path, apps.to_string()).unwrap();
+
+        let mut config = json!({
+            "header": "Authorization",
+            "source": { "file": { "path": path } },
+            "keys": [{ "algorithm": "HS256", "key": SECRET }],
+        });
+        for (key, value) in overrides.as_object().unwrap() {
+            config[key] = value.clone();
+        }
+
+        Explainer::load(serde_json::from_value(config).unwrap(), SDL).await.unwrap()
+    }
+
+    async fn explained(explainer: &Explainer, claims: &[&str], query: &str) -> Vec<(&'static str, bool)> {
+        let claims = claims.iter().map(|claim| claim.to_string()).collect::<Vec<_>>();
+        let token = mint_token(&hs256(), "1234", "user-1", &claims, 60, now()).unwrap();
+
+        explainer
+            .explain(&token, query, None).await
+            .into_iter()
+            .map(|check| (check.name, check.passed))
+            .collect()
+    }
+
+    #[test]
+    fn minted_tokens_are_accepted() {
+        let key = KeyConfig { algorithm: KeyAlgorithm::HS256, kid: None, key: SECRET.to_string() };
+        let token = mint_token(&hs256(), "1234", "user-1", &["*".to_string()], 60, now()).unwrap();
+
+        let payload = get_payload(&token, &verification_keys(&[key]).unwrap(), &TokenValidation::default()).unwrap();
+        assert_eq!((payload.iss.as_str(), payload._id.as_str()), ("1234", "user-1"));
+        assert_eq!(payload.claims, vec!["*"]);
+
+        let (header, payload) = decode_unverified(&token).unwrap();
+        assert_eq!(header["alg"], "HS256");
+        assert_eq!(payload["iss"], "1234");
+    }
+
+    #[tokio::test]
+    async fn minted_tokens_verify_with_the_dev_keys() {
+        for (algorithm, name, private, public) in [
+            (KeyAlgorithm::RS256, "RS256", "keys/dev_rs256.pem", "keys/dev_rs256.pub.pem"),
+            (KeyAlgorithm::ES256, "ES256", "keys/dev_es256.pem", "keys/dev_es256.pub.pem"),
+        ] {
+            let signing_key = SigningKey { algorithm, kid: None, key: std::fs::read_to_string(private).unwrap() };
+            let key = json!([{ "algorithm": name, "key": std::fs::read_to_string(public).unwrap() }]);
+            let explainer = explainer("dev-keys", json!({ "keys": key })).await;
+            let token = mint_token(&signing_key, "1234", "user-1", &["*".to_string()], 60, now()).unwrap();
+
+            let checks = explainer.explain(&token, "{ product { id } }", None).await;
+            assert!(checks.iter().all(|check| check.passed), "{:?}", checks);
+        }
+    }
+
+    #[tokio::test]
+    async fn explains_each_check() {
+        let explainer = explainer("each-check", json!({})).await;
+        let all = [
+            "document",
+            "token",
+            "app",
+            "claims",
+            "operation",
+            "root fields",
+            "nested fields",
+            "introspection",
+            "subgraphs",
+        ];
+        let passed = all.iter().map(|name| (*name, true)).collect::<Vec<_>>();
+        assert_eq!(explained(&explainer, &["*"], "{ product { id price } }").await, passed);
+
+        assert_eq!(
+            explained(&explainer, &["product.id"], "{ product { id price } }").await.last(),
+            Some(&("nested fields", false))
+        );
+        assert_eq!(explained(&explainer, &["*"], "{ review }").await.last(), Some(&("root fields", false)));
+        assert_eq!(
+            explained(&explainer, &["*"], "query A { product { id } } query B { review }").await.last(),
+            Some(&("operation", false))
+        );
+        assert_eq!(explained(&explainer, &[], "{ product { id } }").await.last(), Some(&("claims", false)));
+        assert_eq!(
+            explained(&explainer, &["*"], "{ __schema { queryType { name } } }").await.last(),
+            Some(&("introspection", false))
+        );
+        assert_eq!(
+            explained(&explainer, &["*"], "{ __schema { queryType { name } } product { id } }").await,
+            vec![("document", false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_token_and_the_app() {
+        let explainer = explainer("token-and-app", json!({})).await;
+
+        let expired = mint_token(&hs256(), "1234", "user-1", &["*".to_string()], 60, now() - 3600).unwrap();
+        let checks = explainer.explain(&expired, "{ product { id } }", None).await;
+        assert_eq!(checks.len(), 2);
+        assert!(!checks[1].passed);
+        assert!(checks[1].detail.starts_with("TOKEN_EXPIRED"), "{}", checks[1].detail);
+
+        let unknown = mint_token(&hs256(), "9999", "user-1", &["*".to_string()], 60, now()).unwrap();
+        let checks = explainer.explain(&unknown, "{ product { id } }", None).await;
+        assert_eq!(checks.last().map(|check| (check.name, check.passed)), Some(("app", false)));
+    }
+
+    #[tokio::test]
+    async fn follows_the_plugin_configuration() {
+        let bypassed = explainer("bypass", json!({ "bypass": ["typename"] })).await;
+        let checks = bypassed.explain("not a token", "{ __typename }", None).await;
+        assert_eq!(checks.last().map(|check| (check.name, check.passed)), Some(("bypass", true)));
+
+        let audience = explainer("audience", json!({ "token_validation": { "audiences": ["shop"] } })).await;
+        assert_eq!(explained(&audience, &["*"], "{ product { id } }").await.last(), Some(&("token", false)));
+
+        let redacted = explainer("redacted", json!({ "on_denied_field": "field_error" })).await;
+        let checks = explained(&redacted, &["product.id"], "{ product { id price } }").await;
+        assert!(checks.iter().all(|(_name, passed)| *passed), "{:?}", checks);
+
+        let introspection = json!({ "default_introspection": "full", "mixed_introspection": "authorize" });
+        let introspection = explainer("introspection", introspection).await;
+        let checks = explained(&introspection, &["*"], "{ __schema { queryType { name } } product { id } }").await;
+        assert!(checks.iter().all(|(_name, passed)| *passed), "{:?}", checks);
+    }
+}
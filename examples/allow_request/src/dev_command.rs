@@ -0,0 +1,130 @@
+use std::path::{ Path, PathBuf };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use anyhow::{ anyhow, Result };
+use clap::{ Args, Parser, Subcommand };
+
+use acme_router::config;
+use acme_router::dev;
+use acme_router::dev::SigningKey;
+use acme_router::plugin_functions::KeyAlgorithm;
+
+// `acme_router dev ...`, helpers to try the plugin locally without an identity provider
+#[derive(Parser)]
+#[command(name = "acme_router dev", about = "Mints and explains tokens for local development")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Prints a signed token for an app and a user")]
+    Mint {
+        #[arg(long, help = "App `_id` the token is issued for")]
+        iss: String,
+        #[arg(long = "id", help = "User `_id`")]
+        user_id: String,
+        #[arg(long = "claim", required = true, help = "Repeat for each claim, `*` grants every app permission")]
+        claims: Vec<String>,
+        #[arg(long, default_value_t = 3600)]
+        ttl_secs: u64,
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+    #[command(about = "Prints the header and payload of a token without verifying it")]
+    Decode {
+        token: String,
+    },
+    #[command(about = "Shows which of the plugin checks a token and an operation pass or fail")]
+    Explain {
+        #[arg(long)]
+        token: String,
+        #[arg(long)]
+        query: String,
+        #[arg(long)]
+        operation_name: Option<String>,
+        #[arg(long, default_value = "router.yaml", help = "Router configuration with the `auth.allow_request` plugin")]
+        config: PathBuf,
+        #[arg(long, help = "Supergraph schema the router serves")]
+        schema: PathBuf,
+    },
+}
+
+#[derive(Args)]
+struct KeyArgs {
+    #[arg(long, value_parser = parse_algorithm, default_value = "HS256", help = "HS256, RS256 or ES256")]
+    algorithm: KeyAlgorithm,
+    #[arg(long, help = "HS256 secret, read from ALLOW_REQUEST_HS256_SECRET when missing")]
+    secret: Option<String>,
+    #[arg(long, help = "PEM private key for RS256 and ES256")]
+    key_file: Option<PathBuf>,
+    #[arg(long)]
+    kid: Option<String>,
+}
+
+impl KeyArgs {
+    fn key(&self) -> Result<String> {
+        match (self.algorithm, &self.key_file) {
+            (KeyAlgorithm::HS256, Some(_key_file)) => Err(anyhow!("HS256 uses --secret, not --key-file")),
+            (KeyAlgorithm::HS256, None) =>
+                match &self.secret {
+                    Some(secret) => Ok(secret.clone()),
+                    None =>
+                        std::env
+                            ::var(dev::DEV_SECRET_ENV)
+                            .map_err(|_err| anyhow!("pass --secret or set {}", dev::DEV_SECRET_ENV)),
+                }
+            (algorithm, Some(key_file)) => read(key_file).map_err(|err| anyhow!("{:?} key: {}", algorithm, err)),
+            (algorithm, None) => Err(anyhow!("{:?} needs --key-file", algorithm)),
+        }
+    }
+}
+
+pub fn run(args: impl Iterator<Item = String>) -> Result<()> {
+    match Cli::parse_from(args).command {
+        Command::Mint { iss, user_id, claims, ttl_secs, key } => {
+            let signing_key = SigningKey { algorithm: key.algorithm, kid: key.kid.clone(), key: key.key()? };
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let token = dev::mint_token(&signing_key, &iss, &user_id, &claims, ttl_secs, now).map_err(|err| anyhow!(err))?;
+            println!("{}", token);
+            Ok(())
+        }
+        Command::Decode { token } => {
+            let (header, payload) = dev::decode_unverified(&token).map_err(|err| anyhow!(err))?;
+            println!("{}", serde_json::to_string_pretty(&header)?);
+            println!("{}", serde_json::to_string_pretty(&payload)?);
+            Ok(())
+        }
+        Command::Explain { token, query, operation_name, config, schema } => {
+            let plugin_config = config::from_router_yaml(&read(&config)?).map_err(|err| anyhow!("{:?}: {}", config, err))?;
+            let supergraph_sdl = read(&schema)?;
+
+            // Registries and JWKS are loaded asynchronously, like in the router
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            let checks = runtime.block_on(async {
+                let explainer = dev::Explainer::load(plugin_config, &supergraph_sdl).await.map_err(|err| anyhow!(err))?;
+                Ok::<_, anyhow::Error>(explainer.explain(&token, &query, operation_name.as_deref()).await)
+            })?;
+            for check in &checks {
+                let outcome = if check.passed { "pass" } else { "FAIL" };
+                println!("{:<4}  {:<13}  {}", outcome, check.name, check.detail);
+            }
+            // Exits with 1 when the plugin would reject the request, for scripts
+            if checks.iter().any(|check| !check.passed) {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|err| anyhow!("could not read {:?}: {}", path, err))
+}
+
+fn parse_algorithm(value: &str) -> Result<KeyAlgorithm, String> {
+    serde_json::from_value(serde_json::Value::String(value.to_string())).map_err(|_err|
+        format!("{:?} isn't one of HS256, RS256 or ES256", value)
+    )
+}
@@ -0,0 +1,782 @@
+use std::collections::{ HashMap, HashSet };
+
+use apollo_parser::cst;
+use apollo_router::graphql;
+use apollo_router::graphql::JsonPath;
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json_bytes::Value;
+
+use crate::messages::MessageCatalog;
+use crate::plugin_functions::{
+    fragment_definitions,
+    parse_document,
+    select_operation,
+    OperationError,
+    OperationType,
+    INTROSPECTION_FIELDS,
+};
+use crate::schema::SchemaTypes;
+
+// Context key holding the fields removed from the response when `on_denied_field: field_error`
+pub const DENIED_FIELDS_CONTEXT_KEY: &str = "acme::allow_request::denied_fields";
+// Locale negotiated for the request, used for the errors of redacted fields
+pub const LOCALE_CONTEXT_KEY: &str = "acme::allow_request::locale";
+
+#[derive(Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeniedFieldMode {
+    // The whole request is rejected when any selected field is denied
+    #[default]
+    Reject,
+    // The request runs, denied fields are nulled in the response with one error each. Non-null
+    // fields null their nearest nullable parent instead
+    FieldError,
+}
+
+// A single permission entry. Grants cover the matched field and everything below it,
+// an entry prefixed with `!` denies the matched field and everything below it.
+//
+// - `Query.product`, `Product.costPrice`: field coordinate, matched by the parent type
+// - `product`, `product.reviews.author.email`, `product.*.name`, `product.**`: path of
+//   field names from the root, `*` matches one field and `**` any number of fields
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rule {
+    Coordinate {
+        type_name: String,
+        field: String,
+    },
+    Path(Vec<String>),
+}
+
+impl Rule {
+    pub fn parse(entry: &str) -> Rule {
+        let segments: Vec<&str> = entry.split('.').collect();
+
+        match segments.as_slice() {
+            [type_name, field] if type_name.starts_with(|c: char| c.is_ascii_uppercase()) => {
+                Rule::Coordinate { type_name: type_name.to_string(), field: field.to_string() }
+            }
+            _ =>
+                Rule::Path(
+                    segments
+                        .into_iter()
+                        .map(|segment| segment.to_string())
+                        .collect()
+                ),
+        }
+    }
+
+    fn matches(&self, field: &SelectedField) -> bool {
+        match self {
+            Rule::Coordinate { type_name, field: name } => {
+                field.parent_type.as_deref() == Some(type_name.as_str()) && &field.name == name
+            }
+            Rule::Path(pattern) => path_matches(pattern, &field.path),
+        }
+    }
+
+    // Like `matches`, but a coordinate also matches a field selected through an interface
+    // or union the type belongs to, since it may resolve to that type at runtime
+    fn may_match(&self, field: &SelectedField, schema: Option<&SchemaTypes>) -> bool {
+        match (self, schema, field.parent_type.as_deref()) {
+            (Rule::Coordinate { type_name, field: name }, Some(schema), Some(parent_type)) => {
+                self.matches(field) || (&field.name == name && schema.is_possible_type(parent_type, type_name))
+            }
+            _ => self.matches(field),
+        }
+    }
+
+    // True when the field is an ancestor of something this rule could match
+    fn passes_through(&self, field: &SelectedField) -> bool {
+        match self {
+            Rule::Coordinate { .. } => false,
+            Rule::Path(pattern) => path_prefix_matches(pattern, &field.path),
+        }
+    }
+}
+
+fn path_matches(pattern: &[String], path: &[String]) -> bool {
+    match (pattern.first().map(String::as_str), path.first()) {
+        (None, None) => true,
+        (Some("**"), _) => path_matches(&pattern[1..], path) || (!path.is_empty() && path_matches(pattern, &path[1..])),
+        (Some("*"), Some(_)) => path_matches(&pattern[1..], &path[1..]),
+        (Some(segment), Some(field)) => segment == field && path_matches(&pattern[1..], &path[1..]),
+        _ => false,
+    }
+}
+
+// The path can still be extended into a match of the pattern
+fn path_prefix_matches(pattern: &[String], path: &[String]) -> bool {
+    match (pattern.first().map(String::as_str), path.first()) {
+        (_, None) => !pattern.is_empty(),
+        (Some("**"), Some(_)) => true,
+        (Some("*"), Some(_)) => path_prefix_matches(&pattern[1..], &path[1..]),
+        (Some(segment), Some(field)) => segment == field && path_prefix_matches(&pattern[1..], &path[1..]),
+        (None, Some(_)) => false,
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct RuleSet {
+    grants: Vec<Rule>,
+    denials: Vec<Rule>,
+}
+
+// Every rule set has to allow a field, the token claims narrow the app permissions this way
+#[derive(Debug, Clone)]
+pub struct Permissions {
+    sets: Vec<RuleSet>,
+}
+
+impl Permissions {
+    pub fn parse(entries: &[String]) -> Permissions {
+        Permissions { sets: Vec::new() }.narrowed_by(entries)
+    }
+
+    pub fn narrowed_by(mut self, entries: &[String]) -> Permissions {
+        let mut set = RuleSet::default();
+
+        for entry in entries {
+            match entry.strip_prefix('!') {
+                Some(denied) => set.denials.push(Rule::parse(denied)),
+                None => set.grants.push(Rule::parse(entry)),
+            }
+        }
+        self.sets.push(set);
+        self
+    }
+
+    fn no_grants(&self) -> Vec<bool> {
+        vec![false; self.sets.len()]
+    }
+
+    // What each set grants the field given what it granted the parent, none when a set denies it
+    fn evaluate(&self, selected: &SelectedField, schema: Option<&SchemaTypes>, granted: &[bool]) -> Option<Vec<bool>> {
+        self.sets
+            .iter()
+            .zip(granted)
+            .map(|(set, granted)| {
+                let denied = set.denials.iter().any(|rule| rule.may_match(selected, schema));
+                let granted = *granted || set.grants.iter().any(|rule| rule.matches(selected));
+                let passes_through = set.grants.iter().any(|rule| rule.passes_through(selected));
+
+                if denied || !(granted || passes_through) {
+                    return None;
+                }
+                Some(granted)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SelectedField {
+    name: String,
+    parent_type: Option<String>,
+    // Field names from the root
+    path: Vec<String>,
+    // Aliases when present, this is where the field lands in the response
+    response_path: Vec<String>,
+    // `SchemaTypes::nullability` of each field of the response path
+    nullability: Vec<Vec<bool>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeniedField {
+    pub path: String,
+    pub response_path: Vec<String>,
+    pub root: bool,
+    // Lets redaction find the nearest position that can be null, anything can without a schema
+    #[serde(default)]
+    pub nullability: Vec<Vec<bool>>,
+}
+
+impl DeniedField {
+    // `depth` 0 is the field of `response_path[index]` itself, then the items of each list around it
+    fn is_nullable(&self, index: usize, depth: usize) -> bool {
+        self.nullability
+            .get(index)
+            .and_then(|levels| levels.get(depth))
+            .copied()
+            .unwrap_or(true)
+    }
+}
+
+// Without a schema only the root type and fragment type conditions are known, so coordinates
+// can't match nested fields
+pub fn denied_fields(
+    permissions: &Permissions,
+    query_string: &str,
+    operation_name: Option<&str>,
+    schema: Option<&SchemaTypes>
+) -> Result<Vec<DeniedField>, OperationError> {
+    let doc = parse_document(query_string)?;
+
+    let fragments = fragment_definitions(&doc);
+    let op_def = select_operation(&doc, operation_name)?;
+    let operation_type = OperationType::from_definition(&op_def);
+    let root_type = match schema {
+        Some(schema) => schema.root_type(operation_type),
+        None => operation_type.root_type().to_string(),
+    };
+
+    let mut walker = Walker { permissions, schema, fragments: &fragments, visiting: Vec::new(), denied: Vec::new() };
+    if let Some(selection_set) = op_def.selection_set() {
+        let root = SelectedField {
+            name: String::new(),
+            parent_type: None,
+            path: Vec::new(),
+            response_path: Vec::new(),
+            nullability: Vec::new(),
+        };
+        walker.selection_set(&selection_set, &root, Some(root_type), &permissions.no_grants())?;
+    }
+
+    Ok(walker.denied)
+}
+
+// Fields each type exposes to an app, following the same rules as `denied_fields`, for every
+// selection the app could make from a root type. Only the composite types present are visible
+pub fn visible_fields(permissions: &Permissions, schema: &SchemaTypes) -> HashMap<String, HashSet<String>> {
+    // Deeper than the longest path rule only coordinates and globs can change the outcome, so
+    // each type is walked once per grant state instead of once per path
+    let depth = permissions.sets
+        .iter()
+        .flat_map(|set| set.grants.iter().chain(set.denials.iter()))
+        .map(|rule| {
+            match rule {
+                Rule::Path(segments) => segments.len(),
+                Rule::Coordinate { .. } => 0,
+            }
+        })
+        .max()
+        .unwrap_or_default();
+
+    let mut schema_walker = SchemaWalker {
+        permissions,
+        schema,
+        depth,
+        walked: HashSet::new(),
+        visible: HashMap::new(),
+    };
+    for operation_type in [OperationType::Query, OperationType::Mutation, OperationType::Subscription] {
+        let root_type = schema.root_type(operation_type);
+        if schema.fields_of(&root_type).is_some() {
+            // Root types stay visible, `__schema { queryType }` can't be null
+            schema_walker.visible.entry(root_type.clone()).or_default();
+            schema_walker.walk(&root_type, &mut Vec::new(), &permissions.no_grants());
+        }
+    }
+
+    schema_walker.visible
+}
+
+struct SchemaWalker<'a> {
+    permissions: &'a Permissions,
+    schema: &'a SchemaTypes,
+    depth: usize,
+    walked: HashSet<(String, Vec<bool>)>,
+    visible: HashMap<String, HashSet<String>>,
+}
+
+impl SchemaWalker<'_> {
+    fn walk(&mut self, type_name: &str, path: &mut Vec<String>, granted: &[bool]) {
+        if path.len() >= self.depth && !self.walked.insert((type_name.to_string(), granted.to_vec())) {
+            return;
+        }
+
+        // Fragments on the types behind an interface or union don't change the path
+        let object_types: Vec<String> = std::iter
+            ::once(type_name)
+            .chain(self.schema.possible_types(type_name))
+            .map(str::to_string)
+            .collect();
+
+        for object_type in object_types {
+            let Some(fields) = self.schema.fields_of(&object_type) else {
+                continue;
+            };
+
+            for (name, field_type) in fields {
+                let mut selected_path = path.clone();
+                selected_path.push(name.clone());
+                let selected = SelectedField {
+                    name: name.clone(),
+                    parent_type: Some(object_type.clone()),
+                    path: selected_path,
+                    response_path: Vec::new(),
+                    nullability: Vec::new(),
+                };
+
+                let Some(granted) = self.permissions.evaluate(&selected, Some(self.schema), granted) else {
+                    continue;
+                };
+
+                self.visible.entry(object_type.clone()).or_default().insert(name.clone());
+                if type_name != object_type {
+                    self.visible.entry(type_name.to_string()).or_default();
+                }
+                if self.schema.is_composite(field_type) {
+                    self.visible.entry(field_type.clone()).or_default();
+
+                    path.push(name.clone());
+                    self.walk(field_type, path, &granted);
+                    path.pop();
+                }
+            }
+        }
+    }
+}
+
+struct Walker<'a> {
+    permissions: &'a Permissions,
+    schema: Option<&'a SchemaTypes>,
+    fragments: &'a HashMap<String, cst::FragmentDefinition>,
+    visiting: Vec<String>,
+    denied: Vec<DeniedField>,
+}
+
+impl Walker<'_> {
+    fn selection_set(
+        &mut self,
+        selection_set: &cst::SelectionSet,
+        parent: &SelectedField,
+        parent_type: Option<String>,
+        granted: &[bool]
+    ) -> Result<(), OperationError> {
+        for selection in selection_set.selections() {
+            match selection {
+                cst::Selection::Field(field) => {
+                    let Some(name) = field.name().map(|name| name.text().to_string()) else {
+                        continue;
+                    };
+                    // `__typename` doesn't expose any data, introspection is governed by its own setting
+                    if name == "__typename" || (parent.path.is_empty() && INTROSPECTION_FIELDS.contains(&name.as_str())) {
+                        continue;
+                    }
+                    let response_key = field
+                        .alias()
+                        .and_then(|alias| alias.name())
+                        .map(|alias| alias.text().to_string())
+                        .unwrap_or_else(|| name.clone());
+
+                    let mut selected = parent.clone();
+                    selected.name = name.clone();
+                    selected.parent_type = parent_type.clone();
+                    selected.path.push(name);
+                    selected.response_path.push(response_key);
+                    selected.nullability.push(match (self.schema, &parent_type) {
+                        (Some(schema), Some(parent_type)) => schema.nullability(parent_type, &selected.name).to_vec(),
+                        _ => Vec::new(),
+                    });
+
+                    let Some(granted) = self.permissions.evaluate(&selected, self.schema, granted) else {
+                        self.denied.push(DeniedField {
+                            path: selected.path.join("."),
+                            response_path: selected.response_path.clone(),
+                            root: parent.path.is_empty(),
+                            nullability: selected.nullability.clone(),
+                        });
+                        continue;
+                    };
+
+                    if let Some(selection_set) = field.selection_set() {
+                        let field_type = match (self.schema, &parent_type) {
+                            (Some(schema), Some(parent_type)) => {
+                                schema.field_type(parent_type, &selected.name).map(str::to_string)
+                            }
+                            _ => None,
+                        };
+                        self.selection_set(&selection_set, &selected, field_type, &granted)?;
+                    }
+                }
+                cst::Selection::InlineFragment(fragment) => {
+                    let type_condition = fragment
+                        .type_condition()
+                        .and_then(|condition| condition.named_type())
+                        .and_then(|named_type| named_type.name())
+                        .map(|name| name.text().to_string());
+
+                    if let Some(selection_set) = fragment.selection_set() {
+                        self.selection_set(&selection_set, parent, type_condition.or(parent_type.clone()), granted)?;
+                    }
+                }
+                cst::Selection::FragmentSpread(spread) => {
+                    let name = spread
+                        .fragment_name()
+                        .and_then(|name| name.name())
+                        .map(|name| name.text().to_string())
+                        .unwrap_or_default();
+
+                    if self.visiting.contains(&name) {
+                        return Err(OperationError::FragmentCycle(name));
+                    }
+                    let fragment = self.fragments
+                        .get(&name)
+                        .ok_or_else(|| OperationError::UnknownFragment(name.clone()))?;
+                    let type_condition = fragment
+                        .type_condition()
+                        .and_then(|condition| condition.named_type())
+                        .and_then(|named_type| named_type.name())
+                        .map(|name| name.text().to_string());
+
+                    if let Some(selection_set) = fragment.selection_set() {
+                        self.visiting.push(name);
+                        self.selection_set(&selection_set, parent, type_condition.or(parent_type.clone()), granted)?;
+                        self.visiting.pop();
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Nulls every denied field present in the response and reports it with its own error. A field
+// that can't be null takes its nearest nullable parent with it, like a field error would
+pub fn redact_response(
+    response: &mut graphql::Response,
+    denied: &[DeniedField],
+    catalog: &MessageCatalog,
+    locale: &str
+) {
+    let mut errors = Vec::new();
+    let messages: Vec<String> = denied
+        .iter()
+        .map(|field| catalog.message(locale, "FIELD_NOT_AUTHORIZED", &[("field", &field.path)]))
+        .collect();
+
+    if let Some(data) = response.data.as_mut() {
+        let fields: Vec<(&DeniedField, &String)> = denied.iter().zip(&messages).collect();
+        redact(data, &[], 0, &fields, &mut errors);
+    }
+
+    // Deferred payloads carry their own position in the response
+    for incremental in response.incremental.iter_mut() {
+        let base: Vec<String> = incremental.path
+            .as_ref()
+            .map(|path| {
+                path.to_string()
+                    .split('/')
+                    .filter(|element| !element.is_empty())
+                    .map(|element| element.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let base_keys: Vec<&String> = base
+            .iter()
+            .filter(|element| element.parse::<usize>().is_err())
+            .collect();
+
+        if let Some(data) = incremental.data.as_mut() {
+            let fields: Vec<(&DeniedField, &String)> = denied
+                .iter()
+                .zip(&messages)
+                .filter(|(field, _message)| {
+                    field.response_path.len() > base_keys.len() &&
+                        base_keys
+                            .iter()
+                            .zip(field.response_path.iter())
+                            .all(|(base, key)| *base == key)
+                })
+                .collect();
+            redact(data, &base, base_keys.len(), &fields, &mut errors);
+        }
+    }
+
+    response.errors.extend(errors);
+}
+
+// `data` sits at `base` in the response, the first `skipped` keys of the response paths lead to it
+fn redact(
+    data: &mut Value,
+    base: &[String],
+    skipped: usize,
+    fields: &[(&DeniedField, &String)],
+    errors: &mut Vec<graphql::Error>
+) {
+    let mut nulled: Vec<(Vec<String>, &String)> = Vec::new();
+
+    for (field, message) in fields {
+        let mut found = Vec::new();
+        occurrences(data, field, skipped, 0, &mut Vec::new(), &mut found);
+
+        for positions in found {
+            // `data` itself is nulled when nothing on the way can be
+            let nullable = positions
+                .iter()
+                .rposition(|(_key, nullable)| *nullable)
+                .map(|index| index + 1)
+                .unwrap_or_default();
+            let path = positions[..nullable]
+                .iter()
+                .map(|(key, _nullable)| key.clone())
+                .collect();
+            nulled.push((path, *message));
+        }
+    }
+
+    // Shallower positions first, what is below a nulled position is gone already
+    nulled.sort_by_key(|(path, _message)| path.len());
+    for (path, message) in nulled {
+        let Some(value) = value_at(data, &path) else {
+            continue;
+        };
+        *value = Value::Null;
+
+        let full_path: Vec<&str> = base.iter().chain(&path).map(String::as_str).collect();
+        let error = graphql::Error
+            ::builder()
+            .message(message.as_str())
+            .path(JsonPath::from(full_path.join("/")))
+            .extension_code("FIELD_NOT_AUTHORIZED")
+            .build();
+        if !errors.contains(&error) {
+            errors.push(error);
+        }
+    }
+}
+
+// Every non-null occurrence of the field, as the positions leading to it and whether each can be null
+fn occurrences(
+    value: &Value,
+    field: &DeniedField,
+    index: usize,
+    depth: usize,
+    current: &mut Vec<(String, bool)>,
+    found: &mut Vec<Vec<(String, bool)>>
+) {
+    match value {
+        Value::Array(items) => {
+            // The items of the list returned by the previous field of the path
+            let nullable = index
+                .checked_sub(1)
+                .map(|list| field.is_nullable(list, depth + 1))
+                .unwrap_or(true);
+            for (item_index, item) in items.iter().enumerate() {
+                current.push((item_index.to_string(), nullable));
+                occurrences(item, field, index, depth + 1, current, found);
+                current.pop();
+            }
+        }
+        Value::Object(object) => {
+            let Some(key) = field.response_path.get(index) else {
+                return;
+            };
+            let Some(child) = object.get(key.as_str()).filter(|child| !child.is_null()) else {
+                return;
+            };
+
+            current.push((key.clone(), field.is_nullable(index, 0)));
+            if index + 1 == field.response_path.len() {
+                found.push(current.clone());
+            } else {
+                occurrences(child, field, index + 1, 0, current, found);
+            }
+            current.pop();
+        }
+        _value => {}
+    }
+}
+
+fn value_at<'a>(value: &'a mut Value, path: &[String]) -> Option<&'a mut Value> {
+    let Some((key, rest)) = path.split_first() else {
+        return Some(value);
+    };
+    let child = match value {
+        Value::Array(items) => key.parse::<usize>().ok().and_then(|index| items.get_mut(index)),
+        Value::Object(object) => object.get_mut(key.as_str()),
+        _value => None,
+    }?;
+    value_at(child, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::messages::bundled_catalog;
+    use crate::schema::SchemaTypes;
+
+    fn denied(permissions: &[&str], query: &str) -> Vec<String> {
+        let permissions: Vec<String> = permissions
+            .iter()
+            .map(|permission| permission.to_string())
+            .collect();
+
+        denied_fields(&Permissions::parse(&permissions), query, None, None)
+            .expect("query is valid")
+            .into_iter()
+            .map(|field| field.path)
+            .collect()
+    }
+
+    const QUERY: &str =
+        "{ product(id: 1) { name supplier { name costPrice } reviews { body author { name email } } } }";
+
+    #[test]
+    fn root_grants_cover_the_whole_subtree() {
+        assert!(denied(&["product"], QUERY).is_empty());
+        assert!(denied(&["Query.product"], QUERY).is_empty());
+        assert_eq!(denied(&["review"], QUERY), vec!["product"]);
+    }
+
+    #[test]
+    fn denials_carve_out_nested_fields() {
+        assert_eq!(denied(&["product", "!product.supplier.costPrice"], QUERY), vec!["product.supplier.costPrice"]);
+        assert_eq!(
+            denied(&["product", "!product.**.email", "!product.supplier"], QUERY),
+            vec!["product.supplier", "product.reviews.author.email"]
+        );
+    }
+
+    #[test]
+    fn path_grants_restrict_to_matching_fields() {
+        assert_eq!(
+            denied(&["product.name", "product.reviews.*"], QUERY),
+            vec!["product.supplier"]
+        );
+        assert_eq!(
+            denied(&["product.reviews.author.email"], QUERY),
+            vec!["product.name", "product.supplier", "product.reviews.body", "product.reviews.author.name"]
+        );
+        assert!(denied(&["product.**"], QUERY).is_empty());
+    }
+
+    #[test]
+    fn coordinates_use_known_parent_types() {
+        let query = "{ product(id: 1) { ... on Product { name costPrice } } }";
+
+        assert_eq!(denied(&["product", "!Product.costPrice"], query), vec!["product.costPrice"]);
+        assert_eq!(denied(&["Mutation.product"], query), vec!["product"]);
+    }
+
+    #[test]
+    fn coordinates_resolve_nested_types_from_the_schema() {
+        let schema = SchemaTypes::parse(
+            "type Query { product: Product node: Node } interface Node { id: ID! } \
+             type Product implements Node { id: ID! name: String costPrice: Float supplier: Supplier } \
+             type Supplier { name: String costPrice: Float }"
+        ).unwrap();
+        let denied = |permissions: &[&str], query: &str| -> Vec<String> {
+            let permissions: Vec<String> = permissions
+                .iter()
+                .map(|permission| permission.to_string())
+                .collect();
+
+            denied_fields(&Permissions::parse(&permissions), query, None, Some(&schema))
+                .expect("query is valid")
+                .into_iter()
+                .map(|field| field.path)
+                .collect()
+        };
+        let query = "{ product { name costPrice supplier { costPrice } } node { id ... on Product { costPrice } } }";
+
+        assert_eq!(denied(&["*", "!Product.costPrice"], query), vec!["product.costPrice", "node.costPrice"]);
+        assert_eq!(denied(&["*", "!Supplier.costPrice"], query), vec!["product.supplier.costPrice"]);
+        assert_eq!(
+            denied(&["product", "node", "!Node.id"], "{ node { id } product { id } }"),
+            vec!["node.id"]
+        );
+    }
+
+    #[test]
+    fn coordinate_denials_apply_through_interfaces() {
+        let schema = SchemaTypes::parse(
+            "type Query { node: Node } interface Node { id: ID! secret: String } \
+             type Product implements Node { id: ID! secret: String }"
+        ).unwrap();
+        let permissions: Vec<String> = vec!["node".to_string(), "!Product.secret".to_string()];
+        let fields = denied_fields(&Permissions::parse(&permissions), "{ node { id secret } }", None, Some(&schema))
+            .unwrap();
+
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields[0].path, "node.secret");
+    }
+
+    #[test]
+    fn aliases_and_fragments_cannot_hide_fields() {
+        let query =
+            "{ p: product(id: 1) { ...Sensitive } } fragment Sensitive on Product { secret: supplier { costPrice } }";
+        let permissions: Vec<String> = vec!["product".to_string(), "!product.supplier.costPrice".to_string()];
+        let fields = denied_fields(&Permissions::parse(&permissions), query, None, None).unwrap();
+
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields[0].path, "product.supplier.costPrice");
+        assert_eq!(fields[0].response_path, vec!["p", "secret", "costPrice"]);
+        assert!(!fields[0].root);
+    }
+
+    #[test]
+    fn redacts_denied_fields_in_lists() {
+        let data = json!({
+            "product": {
+                "name": "Table",
+                "reviews": [
+                    { "author": { "email": "a@acme.com" } },
+                    { "author": null },
+                    { "author": { "email": "c@acme.com" } }
+                ]
+            }
+        });
+        let mut response = graphql::Response::builder().data(data).build();
+        let denied = vec![DeniedField {
+            path: "product.reviews.author.email".to_string(),
+            response_path: vec!["product".into(), "reviews".into(), "author".into(), "email".into()],
+            root: false,
+            nullability: Vec::new(),
+        }];
+
+        redact_response(&mut response, &denied, bundled_catalog(), "en");
+
+        let data = serde_json::to_value(response.data.unwrap()).unwrap();
+        assert_eq!(data["product"]["name"], "Table");
+        assert_eq!(data["product"]["reviews"][0]["author"]["email"], serde_json::Value::Null);
+        assert_eq!(data["product"]["reviews"][2]["author"]["email"], serde_json::Value::Null);
+        assert_eq!(response.errors.len(), 2);
+        assert_eq!(response.errors[0].message, "You are not allowed to query the field 'product.reviews.author.email'");
+        assert_eq!(response.errors[0].path.as_ref().unwrap().to_string(), "/product/reviews/0/author/email");
+    }
+
+    #[test]
+    fn non_null_fields_null_their_nearest_nullable_parent() {
+        let schema = SchemaTypes::parse(
+            "
+            type Query { product: Product }
+            type Product { name: String supplier: Supplier reviews: [Review!] }
+            type Supplier { name: String costPrice: Float! }
+            type Review { body: String author: Author! }
+            type Author { email: String }
+        "
+        ).unwrap();
+        let permissions: Vec<String> = vec![
+            "product".to_string(),
+            "!product.supplier.costPrice".to_string(),
+            "!product.reviews.author".to_string()
+        ];
+        let query = "{ product { name supplier { name costPrice } reviews { body author { email } } } }";
+        let denied = denied_fields(&Permissions::parse(&permissions), query, None, Some(&schema)).unwrap();
+        let data = json!({
+            "product": {
+                "name": "Table",
+                "supplier": { "name": "Acme", "costPrice": 12.5 },
+                "reviews": [{ "body": "Sturdy", "author": { "email": "a@acme.com" } }]
+            }
+        });
+        let mut response = graphql::Response::builder().data(data).build();
+
+        redact_response(&mut response, &denied, bundled_catalog(), "en");
+
+        let data = serde_json::to_value(response.data.unwrap()).unwrap();
+        assert_eq!(data, json!({ "product": { "name": "Table", "supplier": null, "reviews": null } }));
+        let paths: Vec<String> = response.errors
+            .iter()
+            .map(|error| error.path.as_ref().unwrap().to_string())
+            .collect();
+        assert_eq!(paths, vec!["/product/supplier", "/product/reviews"]);
+        assert_eq!(response.errors[0].message, "You are not allowed to query the field 'product.supplier.costPrice'");
+    }
+}
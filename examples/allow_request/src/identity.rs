@@ -0,0 +1,429 @@
+use std::collections::BTreeMap;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use apollo_router::services::{ subgraph, supergraph };
+use http::{ HeaderMap, HeaderName, HeaderValue };
+use jsonwebtoken::{ encode, Algorithm, EncodingKey, Header };
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+
+use crate::plugin_functions::{ insert_header, AppConfig, AuthError, KeyAlgorithm, Payload };
+
+// Context keys set on authorized requests, for Rhai scripts, other plugins and telemetry.
+// The record is an `AuthenticatedIdentity`, the ids are plain strings
+pub const IDENTITY_CONTEXT_KEY: &str = "acme::allow_request::identity";
+pub const USER_ID_CONTEXT_KEY: &str = "acme::allow_request::user_id";
+pub const APP_ID_CONTEXT_KEY: &str = "acme::allow_request::app_id";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthenticatedIdentity {
+    pub user_id: String,
+    pub app_id: String,
+    pub app_name: String,
+    pub app_url: String,
+    pub claims: Vec<String>,
+    // The app permissions, the claims narrow them unless they are `*`
+    pub permissions: Vec<String>,
+    // From the app registry, any subgraph when missing
+    #[serde(default)]
+    pub subgraphs: Option<Vec<String>>,
+}
+
+impl AuthenticatedIdentity {
+    pub fn new(payload: &Payload, app: &AppConfig) -> AuthenticatedIdentity {
+        AuthenticatedIdentity {
+            user_id: payload._id.clone(),
+            app_id: app._id.clone(),
+            app_name: app.name.clone(),
+            app_url: app.url.clone(),
+            claims: payload.claims.clone(),
+            permissions: app.permissions.clone(),
+            subgraphs: app.subgraphs.clone(),
+        }
+    }
+}
+
+// What the plugin can forward to subgraphs about an authorized request
+#[derive(Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityField {
+    UserId,
+    AppId,
+    AppName,
+    AppUrl,
+    // Comma separated
+    Claims,
+}
+
+impl IdentityField {
+    pub fn value(&self, identity: &AuthenticatedIdentity) -> String {
+        match self {
+            IdentityField::UserId => identity.user_id.clone(),
+            IdentityField::AppId => identity.app_id.clone(),
+            IdentityField::AppName => identity.app_name.clone(),
+            IdentityField::AppUrl => identity.app_url.clone(),
+            IdentityField::Claims => identity.claims.join(","),
+        }
+    }
+}
+
+fn default_headers() -> BTreeMap<String, IdentityField> {
+    BTreeMap::from([
+        ("user_id".to_string(), IdentityField::UserId),
+        ("app_id".to_string(), IdentityField::AppId),
+        ("app_name".to_string(), IdentityField::AppName),
+        ("app_url".to_string(), IdentityField::AppUrl),
+    ])
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct IdentityConfig {
+    // Header name to the field it carries
+    #[serde(default = "default_headers")]
+    pub headers: BTreeMap<String, IdentityField>,
+    pub internal_token: Option<InternalTokenConfig>,
+    // Extra headers only sent to the named subgraphs
+    #[serde(default)]
+    pub subgraphs: BTreeMap<String, BTreeMap<String, IdentityField>>,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        Self { headers: default_headers(), internal_token: None, subgraphs: BTreeMap::new() }
+    }
+}
+
+fn default_issuer() -> String {
+    "acme-router".to_string()
+}
+
+fn default_ttl_secs() -> u64 {
+    60
+}
+
+// A token signed by the router with the whole identity, so subgraphs can verify where it
+// comes from instead of trusting plain headers
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InternalTokenConfig {
+    pub header: String,
+    pub algorithm: KeyAlgorithm,
+    pub kid: Option<String>,
+    // Shared secret for HS256, PEM encoded private key for RS256 and ES256
+    pub key: String,
+    #[serde(default = "default_issuer")]
+    pub issuer: String,
+    pub audience: Option<String>,
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct InternalClaims<'a> {
+    sub: &'a str,
+    app_id: &'a str,
+    app_name: &'a str,
+    app_url: &'a str,
+    claims: &'a [String],
+    iss: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<&'a str>,
+    iat: u64,
+    exp: u64,
+}
+
+struct InternalToken {
+    header: HeaderName,
+    jwt_header: Header,
+    key: EncodingKey,
+    issuer: String,
+    audience: Option<String>,
+    ttl_secs: u64,
+}
+
+impl InternalToken {
+    fn load(config: &InternalTokenConfig) -> Result<InternalToken, String> {
+        let header = header_name(&config.header)?;
+        let (algorithm, key) = match config.algorithm {
+            KeyAlgorithm::HS256 => (Algorithm::HS256, Ok(EncodingKey::from_secret(config.key.as_bytes()))),
+            KeyAlgorithm::RS256 => (Algorithm::RS256, EncodingKey::from_rsa_pem(config.key.as_bytes())),
+            KeyAlgorithm::ES256 => (Algorithm::ES256, EncodingKey::from_ec_pem(config.key.as_bytes())),
+        };
+        let key = key.map_err(|err| format!("invalid {:?} key for the internal token: {}", config.algorithm, err))?;
+
+        let mut jwt_header = Header::new(algorithm);
+        jwt_header.kid = config.kid.clone();
+
+        Ok(InternalToken {
+            header,
+            jwt_header,
+            key,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            ttl_secs: config.ttl_secs,
+        })
+    }
+
+    fn sign(&self, identity: &AuthenticatedIdentity) -> Result<String, String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| err.to_string())?
+            .as_secs();
+        let claims = InternalClaims {
+            sub: &identity.user_id,
+            app_id: &identity.app_id,
+            app_name: &identity.app_name,
+            app_url: &identity.app_url,
+            claims: &identity.claims,
+            iss: &self.issuer,
+            aud: self.audience.as_deref(),
+            iat: now,
+            exp: now + self.ttl_secs,
+        };
+
+        encode(&self.jwt_header, &claims, &self.key).map_err(|err| err.to_string())
+    }
+}
+
+// The headers the plugin sets on authorized requests. Any copy sent by the client is removed
+// first, whatever the request ends up doing
+pub struct IdentityHeaders {
+    headers: Vec<(HeaderName, IdentityField)>,
+    internal_token: Option<InternalToken>,
+    subgraphs: BTreeMap<String, Vec<(HeaderName, IdentityField)>>,
+}
+
+impl IdentityHeaders {
+    pub fn load(config: &IdentityConfig) -> Result<IdentityHeaders, String> {
+        let headers = header_fields(&config.headers)?;
+        let subgraphs = config.subgraphs
+            .iter()
+            .map(|(subgraph, headers)| Ok((subgraph.clone(), header_fields(headers)?)))
+            .collect::<Result<BTreeMap<_, _>, String>>()?;
+        let internal_token = config.internal_token.as_ref().map(InternalToken::load).transpose()?;
+
+        if let Some(token) = &internal_token {
+            if headers.iter().any(|(name, _field)| name == token.header) {
+                return Err(format!("identity header {:?} is also used for the internal token", token.header.as_str()));
+            }
+        }
+
+        Ok(IdentityHeaders { headers, internal_token, subgraphs })
+    }
+
+    pub fn strip(&self, headers: &mut HeaderMap) {
+        let names = self.headers
+            .iter()
+            .chain(self.subgraphs.values().flatten())
+            .map(|(name, _field)| name)
+            .chain(self.internal_token.iter().map(|token| &token.header));
+
+        for name in names {
+            headers.remove(name);
+        }
+    }
+
+    // Sets the headers and the context keys of an authorized request
+    pub fn insert(&self, req: &mut supergraph::Request, identity: &AuthenticatedIdentity) -> Result<(), AuthError> {
+        for (name, field) in &self.headers {
+            insert_header(req, name.as_str(), &field.value(identity))?;
+        }
+
+        if let Some(token) = &self.internal_token {
+            let signed = token.sign(identity).map_err(|err| {
+                tracing::error!("could not sign the internal token: {}", err);
+                AuthError::InvalidHeaderValue(token.header.to_string())
+            })?;
+            insert_header(req, token.header.as_str(), &signed)?;
+        }
+
+        let _ = req.context.insert(USER_ID_CONTEXT_KEY, identity.user_id.clone());
+        let _ = req.context.insert(APP_ID_CONTEXT_KEY, identity.app_id.clone());
+        let _ = req.context.insert(IDENTITY_CONTEXT_KEY, identity.clone());
+
+        Ok(())
+    }
+
+    pub fn has_subgraph_headers(&self, subgraph: &str) -> bool {
+        self.subgraphs.contains_key(subgraph)
+    }
+
+    // Requests without an identity, like bypassed ones, get none of the headers
+    pub fn insert_subgraph_headers(
+        &self,
+        subgraph: &str,
+        req: &mut subgraph::Request,
+        identity: Option<&AuthenticatedIdentity>
+    ) {
+        let Some(headers) = self.subgraphs.get(subgraph) else {
+            return;
+        };
+        let subgraph_headers = req.subgraph_request.headers_mut();
+
+        for (name, field) in headers {
+            subgraph_headers.remove(name);
+
+            if let Some(identity) = identity {
+                match HeaderValue::from_str(&field.value(identity)) {
+                    Ok(value) => {
+                        subgraph_headers.insert(name, value);
+                    }
+                    Err(_err) => tracing::error!("value for the '{}' header contains invalid characters", name),
+                }
+            }
+        }
+    }
+}
+
+fn header_fields(headers: &BTreeMap<String, IdentityField>) -> Result<Vec<(HeaderName, IdentityField)>, String> {
+    headers
+        .iter()
+        .map(|(name, field)| Ok((header_name(name)?, *field)))
+        .collect()
+}
+
+fn header_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_err| format!("{:?} is not a valid header name", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{ decode, DecodingKey, Validation };
+    use serde_json::json;
+
+    use super::*;
+
+    fn authorized() -> AuthenticatedIdentity {
+        let payload: Payload = serde_json
+            ::from_value(json!({ "_id": "user-1", "iss": "1234", "claims": ["product", "review"] }))
+            .unwrap();
+        let app: AppConfig = serde_json
+            ::from_value(json!({ "_id": "1234", "name": "app", "url": "http://app/", "permissions": ["*"] }))
+            .unwrap();
+        AuthenticatedIdentity::new(&payload, &app)
+    }
+
+    fn request(headers: &[(&str, &str)]) -> supergraph::Request {
+        let mut builder = supergraph::Request::fake_builder();
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn strips_spoofed_identity_headers() {
+        let identity = IdentityHeaders::load(&IdentityConfig::default()).unwrap();
+        let mut req = request(&[("user_id", "admin"), ("App_Name", "spoofed"), ("x-other", "kept")]);
+
+        identity.strip(req.supergraph_request.headers_mut());
+
+        let headers = req.supergraph_request.headers();
+        assert!(!headers.contains_key("user_id") && !headers.contains_key("app_name"));
+        assert!(headers.contains_key("x-other"));
+    }
+
+    #[test]
+    fn maps_fields_to_configured_headers() {
+        let config: IdentityConfig = serde_json
+            ::from_value(json!({ "headers": { "x-user": "user_id", "x-claims": "claims" } }))
+            .unwrap();
+        let identity = IdentityHeaders::load(&config).unwrap();
+        let mut req = request(&[]);
+
+        identity.insert(&mut req, &authorized()).unwrap();
+
+        let headers = req.supergraph_request.headers();
+        assert_eq!(headers["x-user"], "user-1");
+        assert_eq!(headers["x-claims"], "product,review");
+        assert!(!headers.contains_key("user_id"));
+    }
+
+    #[test]
+    fn signs_the_internal_token() {
+        let config: IdentityConfig = serde_json
+            ::from_value(
+                json!({
+                    "internal_token": { "header": "x-identity", "algorithm": "HS256", "key": "internal", "audience": "subgraphs" }
+                })
+            )
+            .unwrap();
+        let identity = IdentityHeaders::load(&config).unwrap();
+        let mut req = request(&[]);
+
+        identity.insert(&mut req, &authorized()).unwrap();
+
+        let token = req.supergraph_request.headers()["x-identity"].to_str().unwrap().to_string();
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_audience(&["subgraphs"]);
+        let claims = decode::<serde_json::Value>(&token, &DecodingKey::from_secret(b"internal"), &validation).unwrap().claims;
+        assert_eq!(claims["sub"], "user-1");
+        assert_eq!(claims["app_id"], "1234");
+        assert_eq!(claims["iss"], "acme-router");
+        assert_eq!(req.supergraph_request.headers()["app_name"], "app");
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        let invalid_name: IdentityConfig = serde_json::from_value(json!({ "headers": { "user id": "user_id" } })).unwrap();
+        assert!(IdentityHeaders::load(&invalid_name).is_err());
+
+        let reused: IdentityConfig = serde_json
+            ::from_value(json!({ "internal_token": { "header": "User_Id", "algorithm": "HS256", "key": "internal" } }))
+            .unwrap();
+        assert!(IdentityHeaders::load(&reused).is_err());
+
+        let bad_key: IdentityConfig = serde_json
+            ::from_value(json!({ "internal_token": { "header": "x-identity", "algorithm": "RS256", "key": "not a pem" } }))
+            .unwrap();
+        assert!(IdentityHeaders::load(&bad_key).is_err());
+        assert!(serde_json::from_value::<IdentityConfig>(json!({ "headers": { "x-user": "email" } })).is_err());
+    }
+
+    #[test]
+    fn values_that_are_not_header_bytes_fail_cleanly() {
+        let identity = IdentityHeaders::load(&IdentityConfig::default()).unwrap();
+        let mut authorized = authorized();
+        authorized.app_name = "line\nbreak".to_string();
+        let mut req = request(&[]);
+
+        let err = identity.insert(&mut req, &authorized).unwrap_err();
+        assert_eq!(err, AuthError::InvalidHeaderValue("app_name".to_string()));
+    }
+
+    #[test]
+    fn records_the_identity_in_the_context() {
+        let identity = IdentityHeaders::load(&IdentityConfig::default()).unwrap();
+        let mut req = request(&[]);
+
+        identity.insert(&mut req, &authorized()).unwrap();
+
+        let recorded = req.context.get::<_, AuthenticatedIdentity>(IDENTITY_CONTEXT_KEY).unwrap().unwrap();
+        assert_eq!(recorded, authorized());
+        assert_eq!(req.context.get::<_, String>(APP_ID_CONTEXT_KEY).unwrap(), Some("1234".to_string()));
+    }
+
+    #[test]
+    fn adds_headers_for_configured_subgraphs_only() {
+        let config: IdentityConfig = serde_json
+            ::from_value(json!({ "subgraphs": { "reviews": { "x-reviewer": "user_id" } } }))
+            .unwrap();
+        let identity = IdentityHeaders::load(&config).unwrap();
+        assert!(identity.has_subgraph_headers("reviews") && !identity.has_subgraph_headers("products"));
+
+        let subgraph_request = || {
+            let mut req = subgraph::Request::fake_builder().build();
+            req.subgraph_request.headers_mut().insert("x-reviewer", HeaderValue::from_static("spoofed"));
+            req
+        };
+
+        let mut req = subgraph_request();
+        identity.insert_subgraph_headers("reviews", &mut req, Some(&authorized()));
+        assert_eq!(req.subgraph_request.headers()["x-reviewer"], "user-1");
+
+        let mut anonymous = subgraph_request();
+        identity.insert_subgraph_headers("reviews", &mut anonymous, None);
+        assert!(!anonymous.subgraph_request.headers().contains_key("x-reviewer"));
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::{ HashMap, HashSet };
+
+use apollo_parser::{ cst, Parser };
+use apollo_router::graphql;
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use serde_json_bytes::Value;
+
+use crate::field_authorization::{ visible_fields, Permissions };
+use crate::plugin_functions::{
+    fragment_definitions,
+    granted_permissions,
+    select_operation,
+    AuthError,
+    OperationKind,
+};
+use crate::schema::SchemaTypes;
+
+// Set for requests whose introspection response must be filtered before it reaches the client
+pub const INTROSPECTION_FILTER_CONTEXT_KEY: &str = "acme::allow_request::introspection_filter";
+
+// How much of the schema an app can introspect
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IntrospectionAccess {
+    #[default]
+    None,
+    Full,
+    // Only the types and fields the app's permissions let it query
+    Permitted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntrospectionFilter {
+    pub permissions: Vec<String>,
+    pub claims: Vec<String>,
+    pub query: String,
+    pub operation_name: Option<String>,
+}
+
+// What the app gets for an introspection query with its access, the filter is applied to the response
+pub fn introspection_filter(
+    kind: OperationKind,
+    access: IntrospectionAccess,
+    permissions: &[String],
+    claims: &[String],
+    query: &str,
+    operation_name: Option<&str>
+) -> Result<Option<IntrospectionFilter>, AuthError> {
+    match (kind, access) {
+        (OperationKind::Typename | OperationKind::Business, _access) => Ok(None),
+        (_kind, IntrospectionAccess::None) => Err(AuthError::IntrospectionNotAllowed),
+        (_kind, IntrospectionAccess::Full) => Ok(None),
+        (_kind, IntrospectionAccess::Permitted) =>
+            Ok(
+                Some(IntrospectionFilter {
+                    permissions: permissions.to_vec(),
+                    claims: claims.to_vec(),
+                    query: query.to_string(),
+                    operation_name: operation_name.map(str::to_string),
+                })
+            ),
+    }
+}
+
+// Introspection types and the introspection type returned by each of their fields
+// that leads to more types or fields
+fn introspection_field_type(parent_type: &str, field: &str) -> Option<&'static str> {
+    match (parent_type, field) {
+        ("__Schema", "types" | "queryType" | "mutationType" | "subscriptionType") => Some("__Type"),
+        ("__Schema", "directives") => Some("__Directive"),
+        ("__Type", "fields") => Some("__Field"),
+        ("__Type", "interfaces" | "possibleTypes" | "ofType") => Some("__Type"),
+        ("__Type", "inputFields") => Some("__InputValue"),
+        ("__Field" | "__Directive", "args") => Some("__InputValue"),
+        ("__Field" | "__InputValue", "type") => Some("__Type"),
+        _ => None,
+    }
+}
+
+// Removes the types and fields the app can't query from `__schema` and `__type` results.
+// Types are identified by their `name`, so a type whose name wasn't selected is removed too
+pub fn filter_introspection(response: &mut graphql::Response, filter: &IntrospectionFilter, schema: &SchemaTypes) {
+    let parser = Parser::new(&filter.query);
+    let cst = parser.parse();
+    let doc = cst.document();
+    let Ok(op_def) = select_operation(&doc, filter.operation_name.as_deref()) else {
+        return;
+    };
+    let Some(selection_set) = op_def.selection_set() else {
+        return;
+    };
+    let Some(data) = response.data.as_mut() else {
+        return;
+    };
+
+    // Claims are checked before a filter is made, without any nothing is visible
+    let permissions = granted_permissions(&filter.permissions, &filter.claims).unwrap_or_else(|_err|
+        Permissions::parse(&[])
+    );
+    let visible = visible_fields(&permissions, schema);
+    let introspection_filter = Filter { schema, visible, fragments: fragment_definitions(&doc) };
+    introspection_filter.root(data, &selection_set, &mut Vec::new());
+}
+
+struct Filter<'a> {
+    schema: &'a SchemaTypes,
+    visible: HashMap<String, HashSet<String>>,
+    fragments: HashMap<String, cst::FragmentDefinition>,
+}
+
+impl Filter<'_> {
+    fn root(&self, data: &mut Value, selection_set: &cst::SelectionSet, visiting: &mut Vec<String>) {
+        for field in self.fields(selection_set, visiting) {
+            let (name, response_key) = field_keys(&field);
+            let Some(value) = data.as_object_mut().and_then(|object| object.get_mut(response_key.as_str())) else {
+                continue;
+            };
+
+            match name.as_str() {
+                "__schema" | "__type" => {
+                    let value_type = if name == "__schema" { "__Schema" } else { "__Type" };
+                    let name_argument = string_argument(&field, "name");
+                    if !self.value(value, &field, value_type, name_argument.as_deref()) {
+                        *value = Value::Null;
+                    }
+                }
+                _name => {}
+            }
+        }
+    }
+
+    // False when the value must be removed from the response
+    fn value(&self, value: &mut Value, field: &cst::Field, value_type: &str, known_name: Option<&str>) -> bool {
+        match value {
+            Value::Array(items) => {
+                items.retain_mut(|item| self.value(item, field, value_type, known_name));
+                true
+            }
+            Value::Object(_object) => {
+                match field.selection_set() {
+                    Some(selection_set) => self.object(value, &selection_set, value_type, known_name),
+                    None => true,
+                }
+            }
+            _value => true,
+        }
+    }
+
+    fn object(
+        &self,
+        value: &mut Value,
+        selection_set: &cst::SelectionSet,
+        value_type: &str,
+        known_name: Option<&str>
+    ) -> bool {
+        let fields = self.fields(selection_set, &mut Vec::new());
+
+        let name = fields
+            .iter()
+            .find(|field| field_keys(field).0 == "name")
+            .and_then(|field| value.as_object().and_then(|object| object.get(field_keys(field).1.as_str())))
+            .map(|name| name.as_str().map(str::to_string));
+
+        let type_name = match (value_type, name) {
+            // Lists and non-null wrappers have no name, what they wrap is checked through `ofType`
+            ("__Type", Some(None)) => None,
+            ("__Type", Some(Some(name))) => Some(name),
+            ("__Type", None) =>
+                match known_name {
+                    Some(name) => Some(name.to_string()),
+                    None => {
+                        return false;
+                    }
+                }
+            (_type, _name) => None,
+        };
+
+        if let Some(type_name) = &type_name {
+            if self.schema.is_composite(type_name) && !self.visible.contains_key(type_name) {
+                return false;
+            }
+        }
+
+        for field in &fields {
+            let (name, response_key) = field_keys(field);
+            let Some(field_type) = introspection_field_type(value_type, &name) else {
+                continue;
+            };
+            let Some(child) = value.as_object_mut().and_then(|object| object.get_mut(response_key.as_str())) else {
+                continue;
+            };
+
+            if let (Some(type_name), "__Field") = (&type_name, field_type) {
+                self.type_fields(child, field, type_name);
+            } else if !self.value(child, field, field_type, None) {
+                *child = Value::Null;
+            }
+        }
+
+        true
+    }
+
+    fn type_fields(&self, fields: &mut Value, field: &cst::Field, type_name: &str) {
+        let Value::Array(items) = fields else {
+            return;
+        };
+        let Some(selection_set) = field.selection_set() else {
+            return;
+        };
+        let selected = self.fields(&selection_set, &mut Vec::new());
+        let name_key = selected
+            .iter()
+            .map(field_keys)
+            .find(|(name, _response_key)| name == "name")
+            .map(|(_name, response_key)| response_key);
+
+        items.retain_mut(|item| {
+            let field_name = name_key
+                .as_ref()
+                .and_then(|key| item.as_object().and_then(|object| object.get(key.as_str())))
+                .and_then(|name| name.as_str());
+
+            // Fields can't be told apart without their name, they are all removed
+            let visible = match (field_name, self.visible.get(type_name)) {
+                (Some(field_name), Some(fields)) => fields.contains(field_name),
+                (Some(_field_name), None) => !self.schema.is_composite(type_name),
+                (None, _fields) => false,
+            };
+
+            visible && self.value(item, field, "__Field", None)
+        });
+    }
+
+    // Fields of a selection set with fragments expanded
+    fn fields(&self, selection_set: &cst::SelectionSet, visiting: &mut Vec<String>) -> Vec<cst::Field> {
+        let mut fields = Vec::new();
+
+        for selection in selection_set.selections() {
+            match selection {
+                cst::Selection::Field(field) => fields.push(field),
+                cst::Selection::InlineFragment(fragment) => {
+                    if let Some(selection_set) = fragment.selection_set() {
+                        fields.extend(self.fields(&selection_set, visiting));
+                    }
+                }
+                cst::Selection::FragmentSpread(spread) => {
+                    let name = spread
+                        .fragment_name()
+                        .and_then(|name| name.name())
+                        .map(|name| name.text().to_string())
+                        .unwrap_or_default();
+
+                    if visiting.contains(&name) {
+                        continue;
+                    }
+                    if let Some(selection_set) = self.fragments.get(&name).and_then(|fragment| fragment.selection_set()) {
+                        visiting.push(name);
+                        fields.extend(self.fields(&selection_set, visiting));
+                        visiting.pop();
+                    }
+                }
+            }
+        }
+
+        fields
+    }
+}
+
+// Field name and the key it has in the response
+fn field_keys(field: &cst::Field) -> (String, String) {
+    let name = field
+        .name()
+        .map(|name| name.text().to_string())
+        .unwrap_or_default();
+    let response_key = field
+        .alias()
+        .and_then(|alias| alias.name())
+        .map(|alias| alias.text().to_string())
+        .unwrap_or_else(|| name.clone());
+
+    (name, response_key)
+}
+
+// Literal string argument, variables aren't resolved
+fn string_argument(field: &cst::Field, argument: &str) -> Option<String> {
+    field
+        .arguments()?
+        .arguments()
+        .find(|arg| arg.name().map(|name| name.text() == argument).unwrap_or(false))
+        .and_then(|arg| arg.value())
+        .and_then(|value| {
+            match value {
+                cst::Value::StringValue(string) => Some(String::from(string)),
+                _value => None,
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const SDL: &str = "
+        type Query { product(id: ID): Product reviews: [Review] node: Node }
+        type Mutation { deleteProduct(id: ID): Product }
+        interface Node { id: ID }
+        type Product implements Node { id: ID name: String costPrice: Float supplier: Supplier }
+        type Supplier { name: String }
+        type Review implements Node { id: ID body: String }
+    ";
+
+    fn filtered(permissions: &[&str], query: &str, data: serde_json::Value) -> serde_json::Value {
+        let schema = SchemaTypes::parse(SDL).unwrap();
+        let filter = IntrospectionFilter {
+            permissions: permissions
+                .iter()
+                .map(|permission| permission.to_string())
+                .collect(),
+            claims: vec!["*".to_string()],
+            query: query.to_string(),
+            operation_name: None,
+        };
+        let mut response = graphql::Response::builder().data(data).build();
+
+        filter_introspection(&mut response, &filter, &schema);
+        serde_json::to_value(response.data.unwrap()).unwrap()
+    }
+
+    fn names(value: &serde_json::Value) -> Vec<&str> {
+        value
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["name"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn visible_fields_follow_permissions() {
+        let schema = SchemaTypes::parse(SDL).unwrap();
+        let permissions = Permissions::parse(&["product".to_string(), "!Product.costPrice".to_string()]);
+        let visible = visible_fields(&permissions, &schema);
+
+        assert_eq!(visible["Query"], HashSet::from(["product".to_string()]));
+        assert!(visible["Product"].contains("supplier") && !visible["Product"].contains("costPrice"));
+        assert!(visible.contains_key("Supplier") && visible.contains_key("Mutation"));
+        assert!(!visible.contains_key("Review") && !visible.contains_key("Node"));
+    }
+
+    #[test]
+    fn filters_schema_types_and_fields() {
+        let query = "{ __schema { types { kind name fields { name } } } }";
+        let data = json!({
+            "__schema": {
+                "types": [
+                    { "kind": "OBJECT", "name": "Query", "fields": [{ "name": "product" }, { "name": "reviews" }] },
+                    { "kind": "OBJECT", "name": "Product", "fields": [{ "name": "id" }, { "name": "costPrice" }] },
+                    { "kind": "OBJECT", "name": "Review", "fields": [{ "name": "body" }] },
+                    { "kind": "SCALAR", "name": "String", "fields": null },
+                ]
+            }
+        });
+        let data = filtered(&["product", "!Product.costPrice"], query, data);
+        let types = &data["__schema"]["types"];
+
+        assert_eq!(names(types), vec!["Query", "Product", "String"]);
+        assert_eq!(names(&types[0]["fields"]), vec!["product"]);
+        assert_eq!(names(&types[1]["fields"]), vec!["id"]);
+    }
+
+    #[test]
+    fn follows_aliases_and_fragments() {
+        let query =
+            "{ t: __type(name: \"Product\") { ...T } r: __type(name: \"Review\") { kind } } fragment T on __Type { n: name f: fields { n: name } }";
+        let data = json!({
+            "t": { "n": "Product", "f": [{ "n": "name" }, { "n": "costPrice" }] },
+            "r": { "kind": "OBJECT" }
+        });
+        let data = filtered(&["product.name"], query, data);
+
+        assert_eq!(data["t"]["f"], json!([{ "n": "name" }]));
+        assert_eq!(data["r"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn removes_types_without_a_selected_name() {
+        let query = "{ __schema { types { fields { name } } } }";
+        let data = json!({ "__schema": { "types": [{ "fields": [{ "name": "product" }] }] } });
+
+        assert_eq!(filtered(&["**"], query, data)["__schema"]["types"], json!([]));
+    }
+}
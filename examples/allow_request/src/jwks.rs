@@ -0,0 +1,293 @@
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex, RwLock };
+use std::time::{ Duration, Instant };
+
+use jsonwebtoken::jwk::{ AlgorithmParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm, PublicKeyUse };
+use jsonwebtoken::{ Algorithm, DecodingKey };
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::plugin_functions::VerificationKey;
+
+fn default_refresh_interval_secs() -> u64 {
+    300
+}
+
+fn default_refresh_cooldown_secs() -> u64 {
+    30
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum JwksSource {
+    File(PathBuf),
+    Url(String),
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct JwksConfig {
+    pub source: JwksSource,
+    // Scheduled refresh, independent of the refreshes triggered by unknown `kid`s
+    #[serde(default = "default_refresh_interval_secs")]
+    pub refresh_interval_secs: u64,
+    // Minimum time between two refreshes triggered by unknown `kid`s
+    #[serde(default = "default_refresh_cooldown_secs")]
+    pub refresh_cooldown_secs: u64,
+}
+
+pub struct Jwks {
+    source: JwksSource,
+    cooldown: Duration,
+    keys: RwLock<Vec<VerificationKey>>,
+    last_refresh: Mutex<Instant>,
+}
+
+impl Jwks {
+    // The first load has to succeed, we don't want to start a router that can't verify any token
+    pub async fn load(config: &JwksConfig) -> Result<Arc<Self>, String> {
+        let keys = fetch_keys(&config.source).await?;
+
+        let jwks = Arc::new(Self {
+            source: config.source.clone(),
+            cooldown: Duration::from_secs(config.refresh_cooldown_secs),
+            keys: RwLock::new(keys),
+            last_refresh: Mutex::new(Instant::now()),
+        });
+
+        if config.refresh_interval_secs > 0 {
+            spawn_refresh(&jwks, Duration::from_secs(config.refresh_interval_secs));
+        }
+
+        Ok(jwks)
+    }
+
+    pub fn keys(&self) -> Vec<VerificationKey> {
+        self.keys.read().expect("jwks lock poisoned").clone()
+    }
+
+    // On failure the last good key set keeps serving
+    pub async fn refresh(&self) -> Result<usize, String> {
+        let keys = fetch_keys(&self.source).await?;
+        let count = keys.len();
+
+        *self.keys.write().expect("jwks lock poisoned") = keys;
+        *self.last_refresh.lock().expect("jwks lock poisoned") = Instant::now();
+
+        Ok(count)
+    }
+
+    // Called when a token references a `kid` we don't know, returns true if the key set was refreshed
+    pub async fn refresh_on_miss(&self) -> bool {
+        {
+            let mut last_refresh = self.last_refresh.lock().expect("jwks lock poisoned");
+            if last_refresh.elapsed() < self.cooldown {
+                return false;
+            }
+            // Claim the slot before fetching so concurrent misses don't all hit the source
+            *last_refresh = Instant::now();
+        }
+
+        match self.refresh().await {
+            Ok(_count) => true,
+            Err(err) => {
+                tracing::warn!("could not refresh JWKS from {:?}: {}", self.source, err);
+                false
+            }
+        }
+    }
+}
+
+fn spawn_refresh(jwks: &Arc<Jwks>, interval: Duration) {
+    // Only a weak reference, the task ends once the plugin is dropped on reload
+    let jwks = Arc::downgrade(jwks);
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            let Some(jwks) = jwks.upgrade() else {
+                break;
+            };
+            if let Err(err) = jwks.refresh().await {
+                tracing::warn!("could not refresh JWKS from {:?}: {}", jwks.source, err);
+            }
+        }
+    });
+}
+
+async fn fetch_keys(source: &JwksSource) -> Result<Vec<VerificationKey>, String> {
+    let content = match source {
+        JwksSource::File(path) => {
+            tokio::fs::read_to_string(path).await.map_err(|err| format!("could not read {:?}: {}", path, err))?
+        }
+        JwksSource::Url(url) => {
+            let client = reqwest::Client
+                ::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .map_err(|err| err.to_string())?;
+
+            client
+                .get(url)
+                .send().await
+                .and_then(|response| response.error_for_status())
+                .map_err(|err| format!("could not fetch {}: {}", url, err))?
+                .text().await
+                .map_err(|err| format!("could not fetch {}: {}", url, err))?
+        }
+    };
+
+    parse_jwks(&content)
+}
+
+pub fn parse_jwks(content: &str) -> Result<Vec<VerificationKey>, String> {
+    let jwks: JwkSet = serde_json::from_str(content).map_err(|err| format!("invalid JWKS: {}", err))?;
+    let keys: Vec<VerificationKey> = jwks.keys.iter().filter_map(verification_key).collect();
+
+    if keys.is_empty() {
+        return Err("JWKS does not contain any usable signing key".to_string());
+    }
+
+    Ok(keys)
+}
+
+fn verification_key(jwk: &Jwk) -> Option<VerificationKey> {
+    if let Some(PublicKeyUse::Encryption) = jwk.common.public_key_use {
+        return None;
+    }
+
+    // Only public keys, anyone who can read the JWKS could sign tokens with an `oct` secret.
+    // HMAC secrets belong in the plugin `keys`
+    let algorithm = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+        (Some(KeyAlgorithm::RS256) | None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+        (Some(KeyAlgorithm::ES256) | None, AlgorithmParameters::EllipticCurve(params)) if
+            params.curve == EllipticCurve::P256
+        => Algorithm::ES256,
+        _ => {
+            tracing::debug!("ignoring unsupported JWK {:?}", jwk.common.key_id);
+            return None;
+        }
+    };
+
+    match DecodingKey::from_jwk(jwk) {
+        Ok(key) => Some(VerificationKey { kid: jwk.common.key_id.clone(), algorithm, key }),
+        Err(err) => {
+            tracing::warn!("ignoring invalid JWK {:?}: {}", jwk.common.key_id, err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{ Arc, Mutex };
+
+    use base64::{ encode_config, URL_SAFE_NO_PAD };
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::stub_server;
+
+    // The development RSA key published under another `kid`
+    fn rsa_jwks(kid: &str) -> String {
+        let mut jwks: serde_json::Value = serde_json::from_str(include_str!("../keys/dev_jwks.json")).unwrap();
+        let mut key = jwks["keys"][0].take();
+        key["kid"] = json!(kid);
+        json!({ "keys": [key] }).to_string()
+    }
+
+    fn kids(jwks: &Jwks) -> Vec<Option<String>> {
+        jwks.keys()
+            .into_iter()
+            .map(|key| key.kid)
+            .collect()
+    }
+
+    fn url_config(url: String) -> JwksConfig {
+        JwksConfig { source: JwksSource::Url(url), refresh_interval_secs: 0, refresh_cooldown_secs: 0 }
+    }
+
+    #[tokio::test]
+    async fn loads_rsa_and_ec_keys_from_file() {
+        let config = JwksConfig {
+            source: JwksSource::File(PathBuf::from("keys/dev_jwks.json")),
+            refresh_interval_secs: 0,
+            refresh_cooldown_secs: 0,
+        };
+        let jwks = Jwks::load(&config).await.expect("jwks is valid");
+        let keys = jwks.keys();
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].kid.as_deref(), Some("dev-rs256"));
+        assert_eq!(keys[0].algorithm, Algorithm::RS256);
+        assert_eq!(keys[1].kid.as_deref(), Some("dev-es256"));
+        assert_eq!(keys[1].algorithm, Algorithm::ES256);
+    }
+
+    #[tokio::test]
+    async fn rejects_jwks_without_usable_keys() {
+        assert!(parse_jwks(r#"{ "keys": [] }"#).is_err());
+        assert!(parse_jwks("not json").is_err());
+    }
+
+    #[test]
+    fn ignores_symmetric_keys_and_other_curves() {
+        let oct = json!({
+            "keys": [{ "kty": "oct", "kid": "secret", "alg": "HS256", "k": encode_config("secret", URL_SAFE_NO_PAD) }]
+        });
+        assert!(parse_jwks(&oct.to_string()).is_err());
+
+        let mut jwks: serde_json::Value = serde_json::from_str(include_str!("../keys/dev_jwks.json")).unwrap();
+        jwks["keys"][1]["crv"] = json!("P-384");
+        let keys = parse_jwks(&jwks.to_string()).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].algorithm, Algorithm::RS256);
+
+        jwks["keys"][1].as_object_mut().unwrap().remove("alg");
+        assert_eq!(parse_jwks(&jwks.to_string()).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn picks_up_rotated_keys_from_url() {
+        let response = Arc::new(Mutex::new((200, rsa_jwks("key-1"))));
+        let url = stub_server("/.well-known/jwks.json", response.clone()).await;
+        let jwks = Jwks::load(&url_config(url)).await.expect("jwks is valid");
+        assert_eq!(kids(&jwks), vec![Some("key-1".to_string())]);
+
+        *response.lock().unwrap() = (200, rsa_jwks("key-2"));
+        assert!(jwks.refresh_on_miss().await);
+        assert_eq!(kids(&jwks), vec![Some("key-2".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn keeps_last_good_keys_when_refresh_fails() {
+        let response = Arc::new(Mutex::new((200, rsa_jwks("key-1"))));
+        let url = stub_server("/.well-known/jwks.json", response.clone()).await;
+        let jwks = Jwks::load(&url_config(url)).await.expect("jwks is valid");
+
+        *response.lock().unwrap() = (500, "oops".to_string());
+        assert!(jwks.refresh().await.is_err());
+        assert!(!jwks.refresh_on_miss().await);
+
+        *response.lock().unwrap() = (200, r#"{ "keys": [] }"#.to_string());
+        assert!(jwks.refresh().await.is_err());
+
+        assert_eq!(kids(&jwks), vec![Some("key-1".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn refresh_on_miss_respects_cooldown() {
+        let response = Arc::new(Mutex::new((200, rsa_jwks("key-1"))));
+        let url = stub_server("/.well-known/jwks.json", response.clone()).await;
+        let mut config = url_config(url);
+        config.refresh_cooldown_secs = 60;
+        let jwks = Jwks::load(&config).await.expect("jwks is valid");
+
+        *response.lock().unwrap() = (200, rsa_jwks("key-2"));
+        assert!(!jwks.refresh_on_miss().await);
+        assert_eq!(kids(&jwks), vec![Some("key-1".to_string())]);
+    }
+}
@@ -1,17 +1,44 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
 
 use apollo_router::graphql;
+use apollo_router::services::subgraph;
 use apollo_router::services::supergraph;
 use apollo_parser::{ cst, Parser };
 
-use base64::decode;
 use http::StatusCode;
+use http::HeaderName;
 use http::HeaderValue;
-use serde::Deserialize;
+use jsonwebtoken::{ decode, decode_header, Algorithm, DecodingKey, Validation };
+use serde::{ Deserialize, Serialize };
 use schemars::JsonSchema;
 
+pub mod admin;
+pub mod bypass;
+pub mod config;
+pub mod dev;
+pub mod field_authorization;
+pub mod identity;
+pub mod introspection;
+pub mod jwks;
+pub mod messages;
+pub mod registry;
+pub mod schema;
+pub mod shadow;
+#[cfg(test)]
+mod test_support;
+
 pub mod plugin_functions {
     use super::*;
+    use acme_common::claims::{ validate_claims, ClaimError, RegisteredClaims };
+    use acme_common::credentials::CredentialError;
+    use crate::bypass::BypassRule;
+    use crate::field_authorization::{ denied_fields, DeniedField, Permissions };
+    use crate::introspection::IntrospectionAccess;
+    use crate::messages::{ bundled_catalog, MessageCatalog };
+    use crate::registry::AppRegistry;
+    use crate::schema::SchemaTypes;
+
+    pub use acme_common::claims::TokenValidation;
 
     #[warn(dead_code)]
     #[derive(Debug, serde::Deserialize, Clone)]
@@ -19,68 +46,465 @@ pub mod plugin_functions {
         pub _id: String,
         pub iss: String,
         pub claims: Vec<String>,
+        #[serde(flatten)]
+        pub registered: RegisteredClaims,
     }
 
     #[warn(dead_code)]
-    #[derive(Deserialize, JsonSchema, Clone)]
+    #[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, PartialEq)]
+    #[serde(deny_unknown_fields)]
     pub struct AppConfig {
         pub _id: String,
         pub name: String,
         pub url: String,
         pub permissions: Vec<String>,
+        // The plugin's `default_introspection` applies when missing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub introspection: Option<IntrospectionAccess>,
+        // Subgraphs the app's operations may fetch from, any when missing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub subgraphs: Option<Vec<String>>,
+    }
+
+    #[derive(Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+    pub enum KeyAlgorithm {
+        HS256,
+        RS256,
+        ES256,
     }
 
-    pub fn introspection(query_string: &str) -> bool {
-        let parser = Parser::new(query_string);
-        let cst = parser.parse();
-
-        let doc = cst.document();
-
-        for def in doc.definitions() {
-            if let cst::Definition::OperationDefinition(op_def) = def {
-                if let Some(selection_set) = op_def.selection_set() {
-                    for selection in selection_set.selections() {
-                        match selection {
-                            cst::Selection::Field(field) => {
-                                if let Some(name) = field.name() {
-                                    if name.text() == "__schema" {
-                                        return true;
-                                    }
-                                }
-                            }
-                            _selection => {}
-                        }
+    #[derive(Deserialize, JsonSchema, Clone)]
+    #[serde(deny_unknown_fields)]
+    pub struct KeyConfig {
+        pub algorithm: KeyAlgorithm,
+        // Only used to select the key when the token header carries a `kid`
+        pub kid: Option<String>,
+        // Shared secret for HS256, PEM encoded public key for RS256 and ES256
+        pub key: String,
+    }
+
+    #[derive(Clone)]
+    pub struct VerificationKey {
+        pub kid: Option<String>,
+        pub algorithm: Algorithm,
+        pub key: DecodingKey,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum TokenError {
+        Malformed,
+        InvalidSignature,
+        UnknownKey,
+        MissingExpiration,
+        Expired,
+        NotYetValid,
+        InvalidAudience,
+    }
+
+    impl TokenError {
+        pub fn extension_code(&self) -> &'static str {
+            match self {
+                TokenError::Malformed | TokenError::MissingExpiration => "UNAUTHORIZED",
+                TokenError::InvalidSignature | TokenError::UnknownKey => "INVALID_TOKEN_SIGNATURE",
+                TokenError::Expired => "TOKEN_EXPIRED",
+                TokenError::NotYetValid => "TOKEN_NOT_YET_VALID",
+                TokenError::InvalidAudience => "INVALID_AUDIENCE",
+            }
+        }
+    }
+
+    impl TokenError {
+        pub fn message_key(&self) -> &'static str {
+            match self {
+                TokenError::Malformed => "TOKEN_MALFORMED",
+                TokenError::InvalidSignature => "TOKEN_INVALID_SIGNATURE",
+                TokenError::UnknownKey => "TOKEN_UNKNOWN_KEY",
+                TokenError::MissingExpiration => "TOKEN_MISSING_EXPIRATION",
+                TokenError::Expired => "TOKEN_EXPIRED",
+                TokenError::NotYetValid => "TOKEN_NOT_YET_VALID",
+                TokenError::InvalidAudience => "TOKEN_INVALID_AUDIENCE",
+            }
+        }
+    }
+
+    impl From<ClaimError> for TokenError {
+        fn from(err: ClaimError) -> TokenError {
+            match err {
+                ClaimError::MissingExpiration => TokenError::MissingExpiration,
+                ClaimError::Expired => TokenError::Expired,
+                ClaimError::NotYetValid => TokenError::NotYetValid,
+                ClaimError::InvalidAudience => TokenError::InvalidAudience,
+            }
+        }
+    }
+
+    impl std::fmt::Display for TokenError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let catalog = bundled_catalog();
+            write!(f, "{}", catalog.message(catalog.default_locale(), self.message_key(), &[]))
+        }
+    }
+
+    // Every way a request can fail to be authorized. Faults on our side map to 5xx so they
+    // aren't mistaken for a client sending bad credentials
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum AuthError {
+        MissingQuery,
+        MissingHeader,
+        InvalidHeader,
+        Token(TokenError),
+        MissingClaims,
+        AppNotRegistered,
+        RegistryUnavailable,
+        InvalidOperation(OperationError),
+        OperationNotAllowed,
+        IntrospectionNotAllowed,
+        SubgraphNotAllowed(String),
+        FieldsNotAllowed(Vec<String>),
+        InvalidHeaderValue(String),
+    }
+
+    impl AuthError {
+        pub fn status_code(&self) -> StatusCode {
+            match self {
+                AuthError::MissingQuery | AuthError::InvalidOperation(_) => StatusCode::BAD_REQUEST,
+                AuthError::RegistryUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+                AuthError::InvalidHeaderValue(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::UNAUTHORIZED,
+            }
+        }
+
+        pub fn extension_code(&self) -> &'static str {
+            match self {
+                AuthError::MissingQuery => "GRAPHQL_ERROR",
+                AuthError::MissingHeader => "AUTH_ERROR",
+                AuthError::Token(err) => err.extension_code(),
+                AuthError::RegistryUnavailable => "SERVICE_UNAVAILABLE",
+                AuthError::InvalidOperation(_) => "GRAPHQL_VALIDATION_FAILED",
+                AuthError::InvalidHeaderValue(_) => "INTERNAL_SERVER_ERROR",
+                _ => "UNAUTHORIZED",
+            }
+        }
+    }
+
+    impl AuthError {
+        pub fn message_key(&self) -> &'static str {
+            match self {
+                AuthError::MissingQuery => "MISSING_QUERY",
+                AuthError::MissingHeader => "MISSING_HEADER",
+                AuthError::InvalidHeader => "INVALID_HEADER",
+                AuthError::Token(_) => "INVALID_TOKEN",
+                AuthError::MissingClaims => "MISSING_CLAIMS",
+                AuthError::AppNotRegistered => "APP_NOT_REGISTERED",
+                AuthError::RegistryUnavailable => "REGISTRY_UNAVAILABLE",
+                AuthError::InvalidOperation(err) => err.message_key(),
+                AuthError::OperationNotAllowed => "OPERATION_NOT_ALLOWED",
+                AuthError::IntrospectionNotAllowed => "INTROSPECTION_NOT_ALLOWED",
+                AuthError::SubgraphNotAllowed(_) => "SUBGRAPH_NOT_ALLOWED",
+                AuthError::FieldsNotAllowed(_) => "FIELDS_NOT_ALLOWED",
+                // The value comes from the token or the registry, it isn't shown to the client
+                AuthError::InvalidHeaderValue(_) => "IDENTITY_UNAVAILABLE",
+            }
+        }
+
+        pub fn localized(&self, catalog: &MessageCatalog, locale: &str) -> String {
+            match self {
+                AuthError::Token(err) => {
+                    let reason = catalog.message(locale, err.message_key(), &[]);
+                    catalog.message(locale, self.message_key(), &[("reason", &reason)])
+                }
+                AuthError::InvalidOperation(err) => err.localized(catalog, locale),
+                AuthError::FieldsNotAllowed(paths) => {
+                    catalog.message(locale, self.message_key(), &[("fields", &paths.join(", "))])
+                }
+                AuthError::SubgraphNotAllowed(subgraph) => {
+                    catalog.message(locale, self.message_key(), &[("subgraph", subgraph)])
+                }
+                _ => catalog.message(locale, self.message_key(), &[]),
+            }
+        }
+    }
+
+    impl std::fmt::Display for AuthError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let catalog = bundled_catalog();
+            write!(f, "{}", self.localized(catalog, catalog.default_locale()))
+        }
+    }
+
+    impl From<CredentialError> for AuthError {
+        fn from(err: CredentialError) -> Self {
+            match err {
+                CredentialError::Missing => AuthError::MissingHeader,
+                CredentialError::Invalid => AuthError::InvalidHeader,
+            }
+        }
+    }
+
+    // Documents we can't pick the operation to authorize from
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum OperationError {
+        Syntax(String),
+        UnknownOperation(String),
+        NoOperation,
+        OperationNameRequired,
+        UnknownFragment(String),
+        FragmentCycle(String),
+        MixedIntrospection,
+    }
+
+    impl OperationError {
+        pub fn message_key(&self) -> &'static str {
+            match self {
+                OperationError::Syntax(_) => "SYNTAX_ERROR",
+                OperationError::UnknownOperation(_) => "UNKNOWN_OPERATION",
+                OperationError::NoOperation => "NO_OPERATION",
+                OperationError::OperationNameRequired => "OPERATION_NAME_REQUIRED",
+                OperationError::UnknownFragment(_) => "UNKNOWN_FRAGMENT",
+                OperationError::FragmentCycle(_) => "FRAGMENT_CYCLE",
+                OperationError::MixedIntrospection => "MIXED_INTROSPECTION",
+            }
+        }
+
+        pub fn localized(&self, catalog: &MessageCatalog, locale: &str) -> String {
+            match self {
+                OperationError::Syntax(error) => catalog.message(locale, self.message_key(), &[("error", error)]),
+                OperationError::UnknownOperation(name) => {
+                    catalog.message(locale, self.message_key(), &[("operation", name)])
+                }
+                OperationError::UnknownFragment(name) | OperationError::FragmentCycle(name) => {
+                    catalog.message(locale, self.message_key(), &[("fragment", name)])
+                }
+                _ => catalog.message(locale, self.message_key(), &[]),
+            }
+        }
+    }
+
+    impl std::fmt::Display for OperationError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let catalog = bundled_catalog();
+            write!(f, "{}", self.localized(catalog, catalog.default_locale()))
+        }
+    }
+
+    // Authorizing a partial CST could let through whatever the parser dropped
+    pub fn parse_document(query_string: &str) -> Result<cst::Document, OperationError> {
+        let cst = Parser::new(query_string).parse();
+
+        if let Some(err) = cst.errors().next() {
+            return Err(OperationError::Syntax(err.message().to_string()));
+        }
+
+        Ok(cst.document())
+    }
+
+    // The operation the router will execute, following the GraphQL spec rules for `operationName`
+    pub fn select_operation(
+        doc: &cst::Document,
+        operation_name: Option<&str>
+    ) -> Result<cst::OperationDefinition, OperationError> {
+        let mut operations = doc.definitions().filter_map(|def| {
+            match def {
+                cst::Definition::OperationDefinition(op_def) => Some(op_def),
+                _definition => None,
+            }
+        });
+
+        match operation_name {
+            Some(operation_name) =>
+                operations
+                    .find(|op_def| op_def.name().map(|name| name.text() == operation_name).unwrap_or(false))
+                    .ok_or_else(|| OperationError::UnknownOperation(operation_name.to_string())),
+            None => {
+                let op_def = operations.next().ok_or(OperationError::NoOperation)?;
+
+                if operations.next().is_some() {
+                    return Err(OperationError::OperationNameRequired);
+                }
+                Ok(op_def)
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum OperationType {
+        Query,
+        Mutation,
+        Subscription,
+    }
+
+    impl OperationType {
+        pub fn from_definition(op_def: &cst::OperationDefinition) -> Self {
+            match op_def.operation_type() {
+                Some(op_type) if op_type.mutation_token().is_some() => OperationType::Mutation,
+                Some(op_type) if op_type.subscription_token().is_some() => OperationType::Subscription,
+                // The `{ ... }` shorthand is a query
+                _ => OperationType::Query,
+            }
+        }
+
+        pub fn root_type(&self) -> &'static str {
+            match self {
+                OperationType::Query => "Query",
+                OperationType::Mutation => "Mutation",
+                OperationType::Subscription => "Subscription",
+            }
+        }
+    }
+
+    pub fn fragment_definitions(doc: &cst::Document) -> HashMap<String, cst::FragmentDefinition> {
+        doc.definitions()
+            .filter_map(|def| {
+                match def {
+                    cst::Definition::FragmentDefinition(fragment) => {
+                        let name = fragment.fragment_name()?.name()?.text().to_string();
+                        Some((name, fragment))
                     }
+                    _definition => None,
                 }
+            })
+            .collect()
+    }
+
+    // Root fields resolved by the router from the schema, they never reach a subgraph
+    pub const INTROSPECTION_FIELDS: [&str; 2] = ["__schema", "__type"];
+
+    // What the selected operation reads, following aliases and fragments at the root
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum OperationKind {
+        // `__schema` or `__type`, possibly with `__typename`
+        Introspection,
+        // Only `__typename`, answered without reading any data
+        Typename,
+        // Introspection next to fields that read subgraph data
+        Mixed,
+        Business,
+    }
+
+    // What to do with documents mixing introspection and other fields
+    #[derive(Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum MixedIntrospection {
+        // Otherwise the introspection bypass could carry any data read along with it
+        #[default]
+        Reject,
+        // The other fields are authorized like in any operation
+        Authorize,
+    }
+
+    // Documents that can't be parsed are business operations, so authorizing them reports the error
+    pub fn classify_operation(query_string: &str, operation_name: Option<&str>) -> OperationKind {
+        let Ok(doc) = parse_document(query_string) else {
+            return OperationKind::Business;
+        };
+
+        let mut fields = Vec::new();
+        let Ok(op_def) = select_operation(&doc, operation_name) else {
+            return OperationKind::Business;
+        };
+        if let Some(selection_set) = op_def.selection_set() {
+            if root_fields(&selection_set, &fragment_definitions(&doc), &mut Vec::new(), &mut fields).is_err() {
+                return OperationKind::Business;
             }
         }
-        false
+
+        let introspection = fields.iter().any(|field| INTROSPECTION_FIELDS.contains(&field.as_str()));
+        let data = fields
+            .iter()
+            .any(|field| field != "__typename" && !INTROSPECTION_FIELDS.contains(&field.as_str()));
+
+        match (introspection, data) {
+            (true, true) => OperationKind::Mixed,
+            (true, false) => OperationKind::Introspection,
+            (false, false) if !fields.is_empty() => OperationKind::Typename,
+            _ => OperationKind::Business,
+        }
+    }
+
+    // Checks made before looking for a token: mixed documents may be rejected outright, and the
+    // first bypass rule that matches lets the request through without one
+    pub fn check_document<'a>(
+        kind: OperationKind,
+        mixed_introspection: MixedIntrospection,
+        bypass: &'a [BypassRule],
+        query_string: &str,
+        operation_name: Option<&str>,
+        schema: &SchemaTypes
+    ) -> Result<Option<&'a BypassRule>, AuthError> {
+        if kind == OperationKind::Mixed && mixed_introspection == MixedIntrospection::Reject {
+            return Err(AuthError::InvalidOperation(OperationError::MixedIntrospection));
+        }
+
+        Ok(bypass.iter().find(|rule| rule.matches(kind, query_string, operation_name, schema)))
     }
 
-    pub fn get_operations_name(query_string: &str) -> Vec<String> {
+    pub fn get_operations_name(
+        query_string: &str,
+        operation_name: Option<&str>
+    ) -> Result<Vec<String>, OperationError> {
         let mut operations = Vec::new();
-        let parser = Parser::new(query_string);
-        let cst = parser.parse();
-
-        let doc = cst.document();
-
-        for def in doc.definitions() {
-            if let cst::Definition::OperationDefinition(op_def) = def {
-                if let Some(selection_set) = op_def.selection_set() {
-                    for selection in selection_set.selections() {
-                        match selection {
-                            cst::Selection::Field(field) => {
-                                if let Some(name) = field.name() {
-                                    operations.push(name.text().to_string());
-                                }
-                            }
-                            _selection => {}
-                        }
+        let doc = parse_document(query_string)?;
+
+        let fragments = fragment_definitions(&doc);
+
+        // Only the selected operation runs, the others in the document are irrelevant
+        let op_def = select_operation(&doc, operation_name)?;
+        if let Some(selection_set) = op_def.selection_set() {
+            root_fields(&selection_set, &fragments, &mut Vec::new(), &mut operations)?;
+        }
+
+        // Qualify each root field with its operation type, e.g. `Mutation.createProduct`
+        let root_type = OperationType::from_definition(&op_def).root_type();
+        Ok(
+            operations
+                .into_iter()
+                .map(|field| format!("{}.{}", root_type, field))
+                .collect()
+        )
+    }
+
+    // Fragment spreads and inline fragments at the root are expanded, otherwise
+    // `query { ...F } fragment F on Query { secretField }` would not be checked at all
+    fn root_fields(
+        selection_set: &cst::SelectionSet,
+        fragments: &HashMap<String, cst::FragmentDefinition>,
+        visiting: &mut Vec<String>,
+        operations: &mut Vec<String>
+    ) -> Result<(), OperationError> {
+        for selection in selection_set.selections() {
+            match selection {
+                cst::Selection::Field(field) => {
+                    if let Some(name) = field.name() {
+                        operations.push(name.text().to_string());
+                    }
+                }
+                cst::Selection::InlineFragment(fragment) => {
+                    if let Some(selection_set) = fragment.selection_set() {
+                        root_fields(&selection_set, fragments, visiting, operations)?;
+                    }
+                }
+                cst::Selection::FragmentSpread(spread) => {
+                    let name = spread
+                        .fragment_name()
+                        .and_then(|name| name.name())
+                        .map(|name| name.text().to_string())
+                        .unwrap_or_default();
+
+                    if visiting.contains(&name) {
+                        return Err(OperationError::FragmentCycle(name));
+                    }
+                    let fragment = fragments.get(&name).ok_or_else(|| OperationError::UnknownFragment(name.clone()))?;
+
+                    if let Some(selection_set) = fragment.selection_set() {
+                        visiting.push(name);
+                        root_fields(&selection_set, fragments, visiting, operations)?;
+                        visiting.pop();
                     }
                 }
             }
         }
-        operations
+
+        Ok(())
     }
 
     pub fn error_response(
@@ -89,7 +513,7 @@ pub mod plugin_functions {
         extension_code: &str,
         req: &supergraph::Request
     ) -> Option<supergraph::Response> {
-        return Some(
+        Some(
             supergraph::Response
                 ::error_builder()
                 .error(graphql::Error::builder().message(message.to_string()).extension_code(extension_code).build())
@@ -97,70 +521,545 @@ pub mod plugin_functions {
                 .context(req.context.clone())
                 .build()
                 .expect("response is valid")
-        );
+        )
+    }
+
+    // In the language the client asked for through `Accept-Language`
+    pub fn auth_error_response(
+        err: &AuthError,
+        catalog: &MessageCatalog,
+        req: &supergraph::Request
+    ) -> Option<supergraph::Response> {
+        let message = err.localized(catalog, catalog.request_locale(&req.supergraph_request));
+        error_response(&message, err.status_code(), err.extension_code(), req)
+    }
+
+    // A `*` claim grants everything the app is registered with, other claims narrow it. A field
+    // has to be granted by both, so claims can't reach past the app or its `!` denials
+    pub fn granted_permissions(permissions: &[String], claims: &[String]) -> Result<Permissions, AuthError> {
+        match claims.first().map(String::as_str) {
+            None => Err(AuthError::MissingClaims),
+            Some("*") => Ok(Permissions::parse(permissions)),
+            Some(_claim) => Ok(Permissions::parse(permissions).narrowed_by(claims)),
+        }
+    }
+
+    // Fails a single fetch of the query plan, the error names the subgraph
+    pub fn subgraph_error_response(
+        err: &AuthError,
+        catalog: &MessageCatalog,
+        locale: &str,
+        req: &subgraph::Request
+    ) -> subgraph::Response {
+        let subgraph_name = match err {
+            AuthError::SubgraphNotAllowed(subgraph) => Some(subgraph.clone()),
+            _err => None,
+        };
+
+        subgraph::Response
+            ::error_builder()
+            .error(
+                graphql::Error
+                    ::builder()
+                    .message(err.localized(catalog, locale))
+                    .extension_code(err.extension_code())
+                    .build()
+            )
+            .status_code(err.status_code())
+            .context(req.context.clone())
+            .and_subgraph_name(subgraph_name)
+            .build()
+            .expect("response is valid")
     }
 
+    // Root fields that aren't granted fail the whole operation, denied nested fields are
+    // returned so the caller can reject the request or redact them from the response
     pub fn validate_operation(
-        permissions: &Vec<String>,
-        claims: &Vec<String>,
-        query_string: &str
-    ) -> Result<Vec<String>, &'static str> {
-        let mut _allowed_query = false;
-
-        // Get query to execute
-        let operations = get_operations_name(query_string);
-
-        if claims[0] == "*" {
-            _allowed_query = operations.iter().all(|operation| permissions.contains(operation));
-        } else {
-            _allowed_query = operations.iter().all(|operation| claims.contains(operation));
-        }
-
-        if !_allowed_query {
-            return Err("No tienes permisos para ejecutar esta acción");
-        }
-
-        Ok(operations)
-    }
-
-    pub fn get_payload(token: &str) -> Result<Payload, &'static str> {
-        let token_base_64: Vec<&str> = token.split('.').collect();
-
-        // Decode payload from jwt
-        match token_base_64.get(1) {
-            Some(token_payload) => {
-                match decode(token_payload) {
-                    Ok(decoded_bytes) => {
-                        match String::from_utf8(decoded_bytes) {
-                            Ok(payload) => {
-                                // Cast to Payload
-                                if let Ok(payload_data) = serde_json::from_str::<Payload>(&payload) {
-                                    return Ok(payload_data.clone());
-                                } else {
-                                    return Err("El formato es incorrecto");
-                                }
-                            }
-                            Err(_err) => Err("No se pudo decodificar el payload del token"),
-                        }
+        permissions: &[String],
+        claims: &[String],
+        query_string: &str,
+        operation_name: Option<&str>,
+        schema: Option<&SchemaTypes>
+    ) -> Result<Vec<DeniedField>, AuthError> {
+        let grants = granted_permissions(permissions, claims)?;
+
+        let denied = denied_fields(&grants, query_string, operation_name, schema).map_err(
+            AuthError::InvalidOperation
+        )?;
+
+        if denied.iter().any(|field| field.root) {
+            return Err(AuthError::OperationNotAllowed);
+        }
+
+        Ok(denied)
+    }
+
+    pub fn verification_keys(keys: &[KeyConfig]) -> Result<Vec<VerificationKey>, String> {
+        keys.iter()
+            .map(|config| {
+                let (algorithm, key) = match config.algorithm {
+                    KeyAlgorithm::HS256 => (Algorithm::HS256, Ok(DecodingKey::from_secret(config.key.as_bytes()))),
+                    KeyAlgorithm::RS256 => (Algorithm::RS256, DecodingKey::from_rsa_pem(config.key.as_bytes())),
+                    KeyAlgorithm::ES256 => (Algorithm::ES256, DecodingKey::from_ec_pem(config.key.as_bytes())),
+                };
+
+                match key {
+                    Ok(key) => Ok(VerificationKey { kid: config.kid.clone(), algorithm, key }),
+                    Err(err) => Err(format!("invalid {:?} key {:?}: {}", config.algorithm, config.kid, err)),
+                }
+            })
+            .collect()
+    }
+
+    pub fn get_payload(
+        token: &str,
+        keys: &[VerificationKey],
+        token_validation: &TokenValidation
+    ) -> Result<Payload, TokenError> {
+        // Tokens using an algorithm we don't know about (including `none`) fail here
+        let header = decode_header(token).map_err(|_err| TokenError::Malformed)?;
+
+        // Only keys configured for the algorithm announced by the token are candidates,
+        // so a token can't make us verify an HMAC with a public key
+        let candidates = keys
+            .iter()
+            .filter(|key| key.algorithm == header.alg)
+            .filter(|key| {
+                match (&header.kid, &key.kid) {
+                    (Some(token_kid), Some(key_kid)) => token_kid == key_kid,
+                    _ => true,
+                }
+            });
+
+        let mut validation = Validation::new(header.alg);
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+        validation.validate_aud = false;
+
+        // A `kid` none of our keys carry usually means the issuer rotated its keys
+        let mut error = match &header.kid {
+            Some(kid) if !keys.iter().any(|key| key.kid.as_ref() == Some(kid)) => TokenError::UnknownKey,
+            _ => TokenError::InvalidSignature,
+        };
+        for candidate in candidates {
+            match decode::<Payload>(token, &candidate.key, &validation) {
+                Ok(token_data) => {
+                    let now = std::time::SystemTime
+                        ::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .expect("system clock is after the epoch")
+                        .as_secs();
+                    validate_claims(&token_data.claims.registered, token_validation, now)?;
+
+                    return Ok(token_data.claims);
+                }
+                Err(err) => {
+                    if let jsonwebtoken::errors::ErrorKind::Json(_) = err.kind() {
+                        error = TokenError::Malformed;
                     }
-                    Err(_err) => Err("No se pudo decodificar el token."),
                 }
             }
-            None => Err("El formato es incorrecto"),
         }
+
+        Err(error)
     }
 
-    pub fn get_app(app_id: &str, file_path: PathBuf) -> Result<AppConfig, &'static str> {
-        let apps: Vec<AppConfig> = serde_json::from_str(std::fs::read_to_string(file_path).unwrap().as_str()).unwrap();
+    // Registered apps indexed by `_id`
+    pub fn parse_apps(content: &str) -> Result<HashMap<String, AppConfig>, String> {
+        let apps: Vec<AppConfig> = serde_json::from_str(content).map_err(|err| err.to_string())?;
 
-        if let Some(app) = apps.iter().find(|app| app._id == app_id) {
-            Ok(app.clone())
-        } else {
-            Err("Aplicación no registrada")
+        let mut indexed = HashMap::new();
+        for app in apps {
+            validate_app(&app)?;
+            if indexed.contains_key(&app._id) {
+                return Err(format!("app '{}' is registered more than once", app._id));
+            }
+            indexed.insert(app._id.clone(), app);
+        }
+        Ok(indexed)
+    }
+
+    // Checks a single registry entry, whatever backend it comes from
+    pub fn validate_app(app: &AppConfig) -> Result<(), String> {
+        if app._id.trim().is_empty() {
+            return Err("an app has an empty `_id`".to_string());
+        }
+        // An app without permissions could never run anything, it's most likely a mistake
+        if app.permissions.is_empty() {
+            return Err(format!("app '{}' has no permissions", app._id));
+        }
+        if app.subgraphs.as_ref().map(Vec::is_empty).unwrap_or(false) {
+            return Err(format!("app '{}' can't reach any subgraph", app._id));
+        }
+        Ok(())
+    }
+
+    pub async fn get_app(app_id: &str, registry: &dyn AppRegistry) -> Result<AppConfig, AuthError> {
+        match registry.get_app(app_id).await {
+            Ok(Some(app)) => Ok(app),
+            Ok(None) => Err(AuthError::AppNotRegistered),
+            Err(err) => {
+                tracing::error!("could not look up app '{}': {}", app_id, err);
+                Err(AuthError::RegistryUnavailable)
+            }
         }
     }
 
-    pub fn insert_header(req: &mut supergraph::Request, key: &'static str, value: &str) {
-        req.supergraph_request.headers_mut().insert(key, HeaderValue::from_str(value).unwrap());
+    pub fn insert_header(req: &mut supergraph::Request, key: &str, value: &str) -> Result<(), AuthError> {
+        let name = HeaderName::from_bytes(key.as_bytes()).map_err(|_err| {
+            tracing::error!("'{}' is not a valid header name", key);
+            AuthError::InvalidHeaderValue(key.to_string())
+        })?;
+        let value = HeaderValue::from_str(value).map_err(|_err| {
+            tracing::error!("value for the '{}' header contains invalid characters", key);
+            AuthError::InvalidHeaderValue(key.to_string())
+        })?;
+        req.supergraph_request.headers_mut().insert(name, value);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{ encode_config, URL_SAFE_NO_PAD };
+    use jsonwebtoken::{ encode, EncodingKey, Header };
+    use serde_json::json;
+
+    use super::plugin_functions::*;
+
+    const SECRET: &str = "dev-secret";
+    const RS256_PRIVATE: &str = include_str!("../keys/dev_rs256.pem");
+    const RS256_PUBLIC: &str = include_str!("../keys/dev_rs256.pub.pem");
+    const ES256_PRIVATE: &str = include_str!("../keys/dev_es256.pem");
+    const ES256_PUBLIC: &str = include_str!("../keys/dev_es256.pub.pem");
+
+    fn now() -> u64 {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn claims() -> serde_json::Value {
+        json!({ "_id": "user-1", "iss": "1234", "claims": ["product"], "exp": now() + 3600 })
+    }
+
+    fn keys(configs: Vec<(KeyAlgorithm, &str)>) -> Vec<VerificationKey> {
+        let configs: Vec<KeyConfig> = configs
+            .into_iter()
+            .map(|(algorithm, key)| KeyConfig { algorithm, kid: None, key: key.to_string() })
+            .collect();
+        verification_keys(&configs).expect("keys are valid")
+    }
+
+    fn verify(token: &str, configs: Vec<(KeyAlgorithm, &str)>) -> Result<Payload, TokenError> {
+        get_payload(token, &keys(configs), &TokenValidation::default())
+    }
+
+    fn hs256_token() -> String {
+        encode(&Header::default(), &claims(), &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn accepts_hs256_rs256_and_es256_tokens() {
+        let keys = keys(
+            vec![
+                (KeyAlgorithm::HS256, SECRET),
+                (KeyAlgorithm::RS256, RS256_PUBLIC),
+                (KeyAlgorithm::ES256, ES256_PUBLIC)
+            ]
+        );
+
+        let rs256 = encode(
+            &Header::new(jsonwebtoken::Algorithm::RS256),
+            &claims(),
+            &EncodingKey::from_rsa_pem(RS256_PRIVATE.as_bytes()).unwrap()
+        ).unwrap();
+        let es256 = encode(
+            &Header::new(jsonwebtoken::Algorithm::ES256),
+            &claims(),
+            &EncodingKey::from_ec_pem(ES256_PRIVATE.as_bytes()).unwrap()
+        ).unwrap();
+
+        for token in [hs256_token(), rs256, es256] {
+            let payload = get_payload(&token, &keys, &TokenValidation::default()).expect("token is valid");
+            assert_eq!(payload._id, "user-1");
+            assert_eq!(payload.iss, "1234");
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_payload() {
+        let token = hs256_token();
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged = json!({ "_id": "user-1", "iss": "1233", "claims": ["*"] });
+        let forged_payload = encode_config(forged.to_string(), URL_SAFE_NO_PAD);
+        let tampered = format!("{}.{}.{}", parts[0], forged_payload, parts[2]);
+
+        assert_eq!(
+            verify(&tampered, vec![(KeyAlgorithm::HS256, SECRET)]).unwrap_err(),
+            TokenError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_secret() {
+        assert_eq!(
+            verify(&hs256_token(), vec![(KeyAlgorithm::HS256, "another-secret")]).unwrap_err(),
+            TokenError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn rejects_algorithm_without_configured_key() {
+        // Only RS256 is configured, so an HS256 token must not be verified at all
+        assert_eq!(
+            verify(&hs256_token(), vec![(KeyAlgorithm::RS256, RS256_PUBLIC)]).unwrap_err(),
+            TokenError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn rejects_hs256_signed_with_public_key() {
+        // Classic algorithm confusion: HMAC signed with the RSA public key as the secret
+        let token = encode(
+            &Header::default(),
+            &claims(),
+            &EncodingKey::from_secret(RS256_PUBLIC.as_bytes())
+        ).unwrap();
+
+        assert_eq!(
+            verify(&token, vec![(KeyAlgorithm::RS256, RS256_PUBLIC)]).unwrap_err(),
+            TokenError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn rejects_alg_none() {
+        let header = encode_config(json!({ "alg": "none", "typ": "JWT" }).to_string(), URL_SAFE_NO_PAD);
+        let payload = encode_config(claims().to_string(), URL_SAFE_NO_PAD);
+
+        for token in [format!("{}.{}.", header, payload), format!("{}.{}", header, payload)] {
+            assert!(verify(&token, vec![(KeyAlgorithm::HS256, SECRET)]).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_key_configuration() {
+        let configs = vec![KeyConfig { algorithm: KeyAlgorithm::RS256, kid: None, key: "not a pem".to_string() }];
+        assert!(verification_keys(&configs).is_err());
+    }
+
+    #[test]
+    fn expired_token_maps_to_its_extension_code() {
+        let expired = json!({ "_id": "user-1", "iss": "1234", "claims": ["product"], "exp": now() - 3600 });
+        let token = encode(&Header::default(), &expired, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap();
+        let err = verify(&token, vec![(KeyAlgorithm::HS256, SECRET)]).unwrap_err();
+
+        assert_eq!(err, TokenError::Expired);
+        assert_eq!(err.extension_code(), "TOKEN_EXPIRED");
+    }
+
+    #[test]
+    fn expands_root_fragments() {
+        let query = "query { ...F ... on Query { review { id } } } fragment F on Query { secretField }";
+        assert_eq!(get_operations_name(query, None).unwrap(), vec!["Query.secretField", "Query.review"]);
+
+        let nested = "query { ...A } fragment A on Query { ...B product { id } } fragment B on Query { panda }";
+        assert_eq!(get_operations_name(nested, None).unwrap(), vec!["Query.panda", "Query.product"]);
+    }
+
+    #[test]
+    fn fragments_cannot_bypass_permissions() {
+        let query = "query { ...F } fragment F on Query { secretField }";
+        let permissions = vec!["product".to_string()];
+
+        assert!(validate_operation(&permissions, &["*".to_string()], query, None, None).is_err());
+        assert!(validate_operation(&permissions, &["product".to_string()], query, None, None).is_err());
+        let inline = "{ ...on Query { product { id } } }";
+        assert!(validate_operation(&permissions, &["*".to_string()], inline, None, None).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_and_cyclic_fragments() {
+        assert!(get_operations_name("query { ...Missing }", None).is_err());
+        let cycle = "query { ...A } fragment A on Query { ...B } fragment B on Query { ...A }";
+        assert!(get_operations_name(cycle, None).is_err());
+    }
+
+    #[test]
+    fn authorizes_only_the_selected_operation() {
+        let query = "query Allowed { product { id } } query Padding { secretField }";
+        let permissions = vec!["product".to_string()];
+        let claims = vec!["*".to_string()];
+
+        assert_eq!(get_operations_name(query, Some("Allowed")).unwrap(), vec!["Query.product"]);
+        assert!(validate_operation(&permissions, &claims, query, Some("Allowed"), None).is_ok());
+        assert!(validate_operation(&permissions, &claims, query, Some("Padding"), None).is_err());
+    }
+
+    #[test]
+    fn rejects_ambiguous_or_unknown_operations() {
+        let query = "query A { product { id } } query B { panda }";
+
+        assert!(get_operations_name(query, None).is_err());
+        assert!(get_operations_name(query, Some("C")).is_err());
+        assert!(get_operations_name("fragment F on Query { panda }", None).is_err());
+        assert_eq!(get_operations_name("{ product { id } }", None).unwrap(), vec!["Query.product"]);
+    }
+
+    #[test]
+    fn introspection_follows_operation_name() {
+        let query = "query I { __schema { types { name } } } query E { secretField }";
+
+        assert_eq!(classify_operation(query, Some("I")), OperationKind::Introspection);
+        assert_eq!(classify_operation(query, Some("E")), OperationKind::Business);
+        assert_eq!(classify_operation(query, None), OperationKind::Business);
+    }
+
+    #[test]
+    fn classifies_every_form_of_introspection() {
+        let kind = |query| classify_operation(query, None);
+
+        assert_eq!(kind("{ __type(name: \"Product\") { fields { name } } }"), OperationKind::Introspection);
+        assert_eq!(kind("{ s: __schema { queryType { name } } __typename }"), OperationKind::Introspection);
+        assert_eq!(kind("{ ...I } fragment I on Query { __schema { types { name } } }"), OperationKind::Introspection);
+        assert_eq!(kind("{ ... on Query { __type(name: \"Query\") { name } } }"), OperationKind::Introspection);
+        assert_eq!(kind("{ __typename t: __typename }"), OperationKind::Typename);
+        assert_eq!(kind("{ __schema { types { name } } product { id } }"), OperationKind::Mixed);
+        assert_eq!(kind("{ ...F __type(name: \"Product\") { name } } fragment F on Query { s: product { id } }"), OperationKind::Mixed);
+        assert_eq!(kind("{ __typename product { id } }"), OperationKind::Business);
+        // Aliasing a business field doesn't make it introspection
+        assert_eq!(kind("{ __schema: product { id } }"), OperationKind::Business);
+        assert_eq!(kind("{ __schema { types { name } }"), OperationKind::Business);
+        assert_eq!(kind("{ ...Missing __schema { types { name } } }"), OperationKind::Business);
+    }
+
+    #[test]
+    fn qualifies_root_fields_with_operation_type() {
+        let fields = |query| get_operations_name(query, None).unwrap();
+
+        assert_eq!(fields("mutation { createProduct { id } }"), vec!["Mutation.createProduct"]);
+        assert_eq!(fields("subscription { onOrder { id } }"), vec!["Subscription.onOrder"]);
+        assert_eq!(fields("query { product { id } }"), vec!["Query.product"]);
+    }
+
+    #[test]
+    fn qualified_permissions_only_grant_their_operation_type() {
+        let permissions = vec!["Query.product".to_string(), "Mutation.createProduct".to_string()];
+        let claims = vec!["*".to_string()];
+
+        assert!(validate_operation(&permissions, &claims, "{ product { id } }", None, None).is_ok());
+        assert!(validate_operation(&permissions, &claims, "mutation { product { id } }", None, None).is_err());
+        assert!(validate_operation(&permissions, &claims, "mutation { createProduct { id } }", None, None).is_ok());
+        assert!(validate_operation(&permissions, &claims, "query { createProduct { id } }", None, None).is_err());
+
+        let claims = vec!["Mutation.createProduct".to_string()];
+        assert!(validate_operation(&permissions, &claims, "mutation { createProduct { id } }", None, None).is_ok());
+        assert!(validate_operation(&permissions, &claims, "{ product { id } }", None, None).is_err());
+    }
+
+    #[test]
+    fn bare_permissions_stay_backwards_compatible() {
+        let permissions = vec!["product".to_string()];
+        let claims = vec!["*".to_string()];
+
+        assert!(validate_operation(&permissions, &claims, "{ product { id } }", None, None).is_ok());
+        assert!(validate_operation(&permissions, &claims, "mutation { product { id } }", None, None).is_ok());
+        assert!(validate_operation(&permissions, &claims, "{ review { id } }", None, None).is_err());
+    }
+
+    #[test]
+    fn nested_denials_are_returned_to_the_caller() {
+        let permissions = vec!["product".to_string(), "!product.supplier.costPrice".to_string()];
+        let query = "{ product { name supplier { costPrice } } }";
+        let denied = validate_operation(&permissions, &["*".to_string()], query, None, None).unwrap();
+
+        assert_eq!(denied.len(), 1);
+        assert_eq!(denied[0].path, "product.supplier.costPrice");
+    }
+
+    #[test]
+    fn claims_narrow_the_app_permissions() {
+        let permissions = vec!["product".to_string(), "!product.costPrice".to_string()];
+        let claims = vec!["product".to_string(), "review".to_string()];
+
+        let denied = validate_operation(&permissions, &claims, "{ product { name costPrice } }", None, None).unwrap();
+        assert_eq!(denied.len(), 1);
+        assert_eq!(denied[0].path, "product.costPrice");
+        // A claim can't grant what the app isn't registered with
+        assert_eq!(
+            validate_operation(&permissions, &claims, "{ review { id } }", None, None),
+            Err(AuthError::OperationNotAllowed)
+        );
+
+        let claims = vec!["product.name".to_string()];
+        let denied = validate_operation(&permissions, &claims, "{ product { id name } }", None, None).unwrap();
+        assert_eq!(denied.len(), 1);
+        assert_eq!(denied[0].path, "product.id");
+    }
+
+    #[test]
+    fn empty_claims_are_rejected_without_panicking() {
+        let permissions = vec!["product".to_string()];
+        let err = validate_operation(&permissions, &[], "{ product { id } }", None, None).unwrap_err();
+
+        assert_eq!(err, AuthError::MissingClaims);
+        assert_eq!(err.status_code(), http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn invalid_header_values_are_server_errors() {
+        let mut req = apollo_router::services::supergraph::Request::fake_builder().build().unwrap();
+
+        let err = insert_header(&mut req, "app_name", "line\nbreak").unwrap_err();
+        assert_eq!(err, AuthError::InvalidHeaderValue("app_name".to_string()));
+        assert_eq!(err.status_code(), http::StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!req.supergraph_request.headers().contains_key("app_name"));
+
+        assert!(insert_header(&mut req, "app_name", "app1-Name").is_ok());
+    }
+
+    #[test]
+    fn corrupt_registry_json_is_an_error() {
+        for content in ["", "[{", "{}", r#"[{ "_id": "1234" }]"#] {
+            assert!(parse_apps(content).is_err(), "{:?} should be rejected", content);
+        }
+        assert_eq!(parse_apps(&std::fs::read_to_string("allowedApps.json").unwrap()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_invalid_registry_entries() {
+        let app = |id: &str, permissions: &[&str]| {
+            json!({ "_id": id, "name": "app", "url": "http://app/", "permissions": permissions })
+        };
+
+        let duplicated = json!([app("1234", &["product"]), app("1234", &["review"])]).to_string();
+        assert_eq!(parse_apps(&duplicated).err().unwrap(), "app '1234' is registered more than once");
+
+        let empty = json!([app("1234", &[])]).to_string();
+        assert_eq!(parse_apps(&empty).err().unwrap(), "app '1234' has no permissions");
+
+        let mut no_subgraphs = app("1234", &["product"]);
+        no_subgraphs["subgraphs"] = json!([]);
+        assert_eq!(parse_apps(&json!([no_subgraphs]).to_string()).err().unwrap(), "app '1234' can't reach any subgraph");
+
+        let blank_id = json!([app(" ", &["product"])]).to_string();
+        assert!(parse_apps(&blank_id).is_err());
+
+        let mut unknown_key = app("1234", &["product"]);
+        unknown_key["permisions"] = json!(["product"]);
+        assert!(parse_apps(&json!([unknown_key]).to_string()).unwrap_err().contains("permisions"));
+    }
+
+    #[test]
+    fn operation_errors_map_to_client_errors() {
+        let claims = vec!["*".to_string()];
+        let err = validate_operation(&claims, &claims, "{ ...Missing }", None, None).unwrap_err();
+
+        assert!(matches!(err, AuthError::InvalidOperation(_)));
+        let malformed = validate_operation(&claims, &claims, "{ product { id }", None, None).unwrap_err();
+        assert!(matches!(malformed, AuthError::InvalidOperation(OperationError::Syntax(_))));
+        assert!(matches!(get_operations_name("query { product(id: ) { id } }", None), Err(OperationError::Syntax(_))));
+        assert_eq!(err.status_code(), http::StatusCode::BAD_REQUEST);
+        assert_eq!(AuthError::RegistryUnavailable.status_code(), http::StatusCode::SERVICE_UNAVAILABLE);
     }
 }
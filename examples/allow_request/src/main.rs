@@ -1,7 +1,12 @@
 mod allow_request;
+mod dev_command;
 
 use anyhow::Result;
 
 fn main() -> Result<()> {
+    // `acme_router dev ...` runs the local development helpers instead of the router
+    if std::env::args().nth(1).as_deref() == Some("dev") {
+        return dev_command::run(std::env::args().skip(1));
+    }
     apollo_router::main()
 }
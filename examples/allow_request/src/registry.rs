@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex, RwLock };
+use std::time::Duration;
+
+use acme_common::watched_file::{ WatchedFile, RELOAD_DEBOUNCE };
+use rusqlite::{ Connection, OpenFlags, OptionalExtension, Row };
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::plugin_functions::{ parse_apps, validate_app, AppConfig };
+use crate::schema::SchemaTypes;
+
+fn default_refresh_interval_secs() -> u64 {
+    60
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum RegistrySource {
+    // JSON file with the list of apps, reloaded when it changes
+    File {
+        path: PathBuf,
+    },
+    // SQLite database with an `apps` table, queried on every request
+    Sqlite {
+        path: PathBuf,
+    },
+    // Endpoint answering with the same JSON list as the file, refreshed periodically
+    Http {
+        url: String,
+        #[serde(default = "default_refresh_interval_secs")]
+        refresh_interval_secs: u64,
+    },
+}
+
+// Checks applied to every app before it is served, whatever the backend
+pub type AppsValidator = Arc<dyn Fn(&HashMap<String, AppConfig>) -> Result<(), String> + Send + Sync>;
+
+#[async_trait::async_trait]
+pub trait AppRegistry: Send + Sync {
+    // `Ok(None)` when the app isn't registered, `Err` when the registry couldn't be queried
+    async fn get_app(&self, app_id: &str) -> Result<Option<AppConfig>, String>;
+}
+
+pub async fn load_registry(
+    source: &RegistrySource,
+    validate: AppsValidator
+) -> Result<Arc<dyn AppRegistry>, String> {
+    let registry: Arc<dyn AppRegistry> = match source {
+        RegistrySource::File { path } => Arc::new(FileRegistry::load(path, validate)?),
+        RegistrySource::Sqlite { path } => Arc::new(SqliteRegistry::open(path, validate)?),
+        RegistrySource::Http { url, refresh_interval_secs } => {
+            HttpRegistry::load(url, Duration::from_secs(*refresh_interval_secs), validate).await?
+        }
+    };
+
+    Ok(registry)
+}
+
+// Permissions naming fields the schema doesn't have would silently never match, and unknown
+// subgraphs would never be reached. Apps carrying one are rejected like any other invalid entry
+pub fn check_apps(schema: &SchemaTypes, apps: &HashMap<String, AppConfig>) -> Result<(), String> {
+    let mut unknown_subgraphs: Vec<String> = apps
+        .values()
+        .flat_map(|app| {
+            schema
+                .unknown_subgraphs(app.subgraphs.as_deref().unwrap_or_default())
+                .into_iter()
+                .map(move |subgraph| format!("{} ({})", subgraph, app._id))
+        })
+        .collect();
+    unknown_subgraphs.sort();
+
+    if !unknown_subgraphs.is_empty() {
+        return Err(format!("subgraphs not found in the supergraph schema: {}", unknown_subgraphs.join(", ")));
+    }
+
+    let mut unknown: Vec<String> = apps
+        .values()
+        .flat_map(|app| {
+            schema
+                .unknown_permissions(&app.permissions)
+                .into_iter()
+                .map(move |permission| format!("{} ({})", permission, app._id))
+        })
+        .collect();
+    unknown.sort();
+
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("permissions not found in the supergraph schema: {}", unknown.join(", ")))
+    }
+}
+
+pub struct FileRegistry {
+    apps: Arc<WatchedFile<HashMap<String, AppConfig>>>,
+}
+
+impl FileRegistry {
+    pub fn load(path: &Path, validate: AppsValidator) -> Result<Self, String> {
+        let apps = WatchedFile::load(path, RELOAD_DEBOUNCE, move |content| {
+            let apps = parse_apps(content)?;
+            validate(&apps)?;
+            Ok(apps)
+        })?;
+
+        Ok(Self { apps })
+    }
+}
+
+#[async_trait::async_trait]
+impl AppRegistry for FileRegistry {
+    async fn get_app(&self, app_id: &str) -> Result<Option<AppConfig>, String> {
+        Ok(self.apps.current().get(app_id).cloned())
+    }
+}
+
+// Expects a table like
+//
+//   CREATE TABLE apps (
+//     _id TEXT PRIMARY KEY, name TEXT NOT NULL, url TEXT NOT NULL, permissions TEXT NOT NULL,
+//     introspection TEXT, subgraphs TEXT
+//   )
+//
+// where `permissions` and `subgraphs` hold JSON lists of strings and `introspection` is one of
+// none, full or permitted. NULL introspection or subgraphs mean what missing keys do in the file
+pub struct SqliteRegistry {
+    connection: Arc<Mutex<Connection>>,
+    validate: AppsValidator,
+}
+
+impl SqliteRegistry {
+    // Every row is validated once at startup, rows changed later are validated when they are read
+    pub fn open(path: &Path, validate: AppsValidator) -> Result<Self, String> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(|err|
+            format!("could not open {:?}: {}", path, err)
+        )?;
+
+        let apps = all_rows(&connection).map_err(|err| format!("invalid app registry {:?}: {}", path, err))?;
+        validate(&apps).map_err(|err| format!("invalid app registry {:?}: {}", path, err))?;
+
+        Ok(Self { connection: Arc::new(Mutex::new(connection)), validate })
+    }
+}
+
+#[async_trait::async_trait]
+impl AppRegistry for SqliteRegistry {
+    async fn get_app(&self, app_id: &str) -> Result<Option<AppConfig>, String> {
+        let connection = self.connection.clone();
+        let app_id = app_id.to_string();
+
+        // rusqlite is blocking, keep it off the executor threads
+        let app = tokio::task
+            ::spawn_blocking(move || {
+                let connection = connection.lock().expect("sqlite lock poisoned");
+                connection
+                    .query_row(
+                        &format!("{} WHERE _id = ?1", SELECT_APPS),
+                        [&app_id],
+                        app_row
+                    )
+                    .optional()
+                    .map_err(|err| err.to_string())
+            }).await
+            .map_err(|err| err.to_string())??;
+
+        match app {
+            Some(row) => {
+                let app = app_from_row(row)?;
+                (self.validate)(&HashMap::from([(app._id.clone(), app.clone())]))?;
+                Ok(Some(app))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+const SELECT_APPS: &str = "SELECT _id, name, url, permissions, introspection, subgraphs FROM apps";
+
+type AppRow = (String, String, String, String, Option<String>, Option<String>);
+
+fn app_row(row: &Row) -> rusqlite::Result<AppRow> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+}
+
+fn all_rows(connection: &Connection) -> Result<HashMap<String, AppConfig>, String> {
+    let mut statement = connection
+        .prepare(SELECT_APPS)
+        .map_err(|err| err.to_string())?;
+    let rows = statement
+        .query_map([], app_row)
+        .map_err(|err| err.to_string())?;
+
+    let mut apps = HashMap::new();
+    for row in rows {
+        let app = app_from_row(row.map_err(|err| err.to_string())?)?;
+        apps.insert(app._id.clone(), app);
+    }
+    Ok(apps)
+}
+
+fn app_from_row((_id, name, url, permissions, introspection, subgraphs): AppRow) -> Result<AppConfig, String> {
+    let permissions = serde_json
+        ::from_str(&permissions)
+        .map_err(|err| format!("invalid permissions for app '{}': {}", _id, err))?;
+    let introspection = introspection
+        .map(|introspection| serde_json::from_value(serde_json::Value::String(introspection)))
+        .transpose()
+        .map_err(|err| format!("invalid introspection for app '{}': {}", _id, err))?;
+    let subgraphs = subgraphs
+        .map(|subgraphs| serde_json::from_str(&subgraphs))
+        .transpose()
+        .map_err(|err| format!("invalid subgraphs for app '{}': {}", _id, err))?;
+
+    let app = AppConfig { _id, name, url, permissions, introspection, subgraphs };
+    validate_app(&app)?;
+
+    Ok(app)
+}
+
+pub struct HttpRegistry {
+    url: String,
+    validate: AppsValidator,
+    apps: RwLock<Arc<HashMap<String, AppConfig>>>,
+}
+
+impl HttpRegistry {
+    // The first fetch has to succeed, later failures keep serving the last good registry
+    pub async fn load(url: &str, refresh_interval: Duration, validate: AppsValidator) -> Result<Arc<Self>, String> {
+        let apps = fetch_apps(url, &validate).await?;
+        let registry = Arc::new(Self {
+            url: url.to_string(),
+            validate,
+            apps: RwLock::new(Arc::new(apps)),
+        });
+
+        if !refresh_interval.is_zero() {
+            spawn_refresh(&registry, refresh_interval);
+        }
+
+        Ok(registry)
+    }
+
+    pub async fn refresh(&self) -> Result<usize, String> {
+        let apps = fetch_apps(&self.url, &self.validate).await?;
+        let count = apps.len();
+
+        *self.apps.write().expect("registry lock poisoned") = Arc::new(apps);
+
+        Ok(count)
+    }
+}
+
+#[async_trait::async_trait]
+impl AppRegistry for HttpRegistry {
+    async fn get_app(&self, app_id: &str) -> Result<Option<AppConfig>, String> {
+        let apps = self.apps.read().expect("registry lock poisoned").clone();
+
+        Ok(apps.get(app_id).cloned())
+    }
+}
+
+fn spawn_refresh(registry: &Arc<HttpRegistry>, interval: Duration) {
+    // Only a weak reference, the task ends once the plugin is dropped on reload
+    let registry = Arc::downgrade(registry);
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            let Some(registry) = registry.upgrade() else {
+                break;
+            };
+            if let Err(err) = registry.refresh().await {
+                tracing::warn!("keeping the previous app registry from {}: {}", registry.url, err);
+            }
+        }
+    });
+}
+
+async fn fetch_apps(url: &str, validate: &AppsValidator) -> Result<HashMap<String, AppConfig>, String> {
+    let client = reqwest::Client
+        ::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .map_err(|err| err.to_string())?;
+
+    let content = client
+        .get(url)
+        .send().await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| format!("could not fetch {}: {}", url, err))?
+        .text().await
+        .map_err(|err| format!("could not fetch {}: {}", url, err))?;
+
+    let apps = parse_apps(&content).map_err(|err| format!("invalid app registry from {}: {}", url, err))?;
+    validate(&apps).map_err(|err| format!("invalid app registry from {}: {}", url, err))?;
+
+    Ok(apps)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::introspection::IntrospectionAccess;
+    use crate::test_support::stub_server;
+
+    fn accept_all() -> AppsValidator {
+        Arc::new(|_apps| Ok(()))
+    }
+
+    // Rejects apps granting the `secret` field, standing in for the schema checks
+    fn reject_secret() -> AppsValidator {
+        Arc::new(|apps| {
+            match apps.values().find(|app| app.permissions.iter().any(|permission| permission == "secret")) {
+                Some(app) => Err(format!("unknown permission in {}", app._id)),
+                None => Ok(()),
+            }
+        })
+    }
+
+    fn registry_json(apps: &[(&str, &[&str])]) -> String {
+        let apps: Vec<_> = apps
+            .iter()
+            .map(|(id, permissions)| json!({ "_id": id, "name": id, "url": "http://app/", "permissions": permissions }))
+            .collect();
+        json!(apps).to_string()
+    }
+
+    static NEXT_DATABASE: AtomicUsize = AtomicUsize::new(0);
+
+    fn sqlite_file(apps: &[(&str, &str)]) -> PathBuf {
+        let path = std::env
+            ::temp_dir()
+            .join(format!("registry-{}-{}.db", std::process::id(), NEXT_DATABASE.fetch_add(1, Ordering::SeqCst)));
+        let _ = std::fs::remove_file(&path);
+
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute(
+                "CREATE TABLE apps (
+                    _id TEXT PRIMARY KEY, name TEXT NOT NULL, url TEXT NOT NULL, permissions TEXT NOT NULL,
+                    introspection TEXT, subgraphs TEXT
+                )",
+                []
+            )
+            .unwrap();
+        for (id, permissions) in apps {
+            connection
+                .execute("INSERT INTO apps (_id, name, url, permissions) VALUES (?1, ?1, 'http://app/', ?2)", [
+                    id,
+                    permissions,
+                ])
+                .unwrap();
+        }
+        path
+    }
+
+    #[tokio::test]
+    async fn file_registry_serves_apps_from_the_file() {
+        let source = RegistrySource::File { path: PathBuf::from("allowedApps.json") };
+        let registry = load_registry(&source, accept_all()).await.unwrap();
+
+        assert_eq!(registry.get_app("1234").await.unwrap().unwrap().name, "app1-Name");
+        assert!(registry.get_app("9999").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn sqlite_registry_queries_the_database() {
+        let path = sqlite_file(&[("1234", r#"["product"]"#), ("1233", r#"["review", "!review.author"]"#)]);
+        let registry = load_registry(&RegistrySource::Sqlite { path }, accept_all()).await.unwrap();
+
+        let app = registry.get_app("1233").await.unwrap().unwrap();
+        assert_eq!(app.permissions, vec!["review", "!review.author"]);
+        assert_eq!(app.introspection, None);
+        assert_eq!(app.subgraphs, None);
+        assert!(registry.get_app("9999").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn sqlite_registry_reads_introspection_and_subgraphs() {
+        let path = sqlite_file(&[("1234", r#"["product"]"#)]);
+        Connection::open(&path)
+            .unwrap()
+            .execute("UPDATE apps SET introspection = 'permitted', subgraphs = '[\"products\"]' WHERE _id = '1234'", [])
+            .unwrap();
+        let registry = load_registry(&RegistrySource::Sqlite { path: path.clone() }, accept_all()).await.unwrap();
+
+        let app = registry.get_app("1234").await.unwrap().unwrap();
+        assert_eq!(app.introspection, Some(IntrospectionAccess::Permitted));
+        assert_eq!(app.subgraphs, Some(vec!["products".to_string()]));
+
+        Connection::open(&path).unwrap().execute("UPDATE apps SET introspection = 'some'", []).unwrap();
+        assert!(load_registry(&RegistrySource::Sqlite { path }, accept_all()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sqlite_registry_validates_rows() {
+        let path = sqlite_file(&[("1234", r#"["secret"]"#)]);
+        assert!(load_registry(&RegistrySource::Sqlite { path }, reject_secret()).await.is_err());
+
+        let path = sqlite_file(&[("1234", "product")]);
+        assert!(load_registry(&RegistrySource::Sqlite { path }, accept_all()).await.is_err());
+
+        let path = sqlite_file(&[("1234", r#"["product"]"#), ("1233", "[]")]);
+        let err = load_registry(&RegistrySource::Sqlite { path }, accept_all()).await.err().unwrap();
+        assert!(err.contains("app '1233' has no permissions"), "{}", err);
+
+        let missing = RegistrySource::Sqlite { path: PathBuf::from("missing.db") };
+        assert!(load_registry(&missing, accept_all()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn http_registry_keeps_last_good_apps() {
+        let response = Arc::new(Mutex::new((200, registry_json(&[("1234", &["product"])]))));
+        let url = stub_server("/apps", response.clone()).await;
+        let registry = HttpRegistry::load(&url, Duration::ZERO, reject_secret()).await.unwrap();
+        assert!(registry.get_app("1234").await.unwrap().is_some());
+
+        *response.lock().unwrap() = (200, registry_json(&[("1233", &["review"])]));
+        assert_eq!(registry.refresh().await, Ok(1));
+        assert!(registry.get_app("1234").await.unwrap().is_none());
+        assert!(registry.get_app("1233").await.unwrap().is_some());
+
+        for (status, body) in [(500, "oops".to_string()), (200, "[{".to_string()), (200, registry_json(&[("1", &["secret"])]))] {
+            *response.lock().unwrap() = (status, body);
+            assert!(registry.refresh().await.is_err());
+        }
+        assert!(registry.get_app("1233").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn http_registry_first_fetch_must_succeed() {
+        let response = Arc::new(Mutex::new((503, String::new())));
+        let url = stub_server("/apps", response).await;
+
+        assert!(HttpRegistry::load(&url, Duration::ZERO, accept_all()).await.is_err());
+    }
+}
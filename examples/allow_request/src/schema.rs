@@ -0,0 +1,412 @@
+use std::collections::{ HashMap, HashSet };
+
+use apollo_parser::{ cst, Parser };
+
+use crate::field_authorization::Rule;
+use crate::plugin_functions::OperationType;
+
+// The parts of the supergraph schema authorization needs: the type returned by each field
+// and the concrete types behind interfaces and unions
+#[derive(Debug, Default)]
+pub struct SchemaTypes {
+    fields: HashMap<String, HashMap<String, String>>,
+    nullability: HashMap<String, HashMap<String, Vec<bool>>>,
+    possible_types: HashMap<String, HashSet<String>>,
+    root_types: HashMap<&'static str, String>,
+    // From the `join__Graph` enum, empty when the schema isn't a supergraph
+    subgraphs: HashSet<String>,
+}
+
+impl SchemaTypes {
+    pub fn parse(sdl: &str) -> Result<SchemaTypes, String> {
+        let parser = Parser::new(sdl);
+        let cst = parser.parse();
+
+        if let Some(err) = cst.errors().next() {
+            return Err(format!("invalid supergraph schema: {}", err.message()));
+        }
+
+        let mut schema = SchemaTypes::default();
+
+        for def in cst.document().definitions() {
+            match def {
+                cst::Definition::SchemaDefinition(schema_def) => {
+                    for root in schema_def.root_operation_type_definitions() {
+                        schema.add_root_type(root.operation_type(), root.named_type());
+                    }
+                }
+                cst::Definition::SchemaExtension(schema_ext) => {
+                    for root in schema_ext.root_operation_type_definitions() {
+                        schema.add_root_type(root.operation_type(), root.named_type());
+                    }
+                }
+                cst::Definition::ObjectTypeDefinition(object) => {
+                    let name = type_name(object.name());
+                    schema.add_fields(&name, object.fields_definition());
+                    schema.add_implements(&name, object.implements_interfaces());
+                }
+                cst::Definition::ObjectTypeExtension(object) => {
+                    let name = type_name(object.name());
+                    schema.add_fields(&name, object.fields_definition());
+                    schema.add_implements(&name, object.implements_interfaces());
+                }
+                cst::Definition::InterfaceTypeDefinition(interface) => {
+                    let name = type_name(interface.name());
+                    schema.add_fields(&name, interface.fields_definition());
+                }
+                cst::Definition::InterfaceTypeExtension(interface) => {
+                    let name = type_name(interface.name());
+                    schema.add_fields(&name, interface.fields_definition());
+                }
+                cst::Definition::UnionTypeDefinition(union) => {
+                    let name = type_name(union.name());
+                    schema.add_union_members(&name, union.union_member_types());
+                }
+                cst::Definition::UnionTypeExtension(union) => {
+                    let name = type_name(union.name());
+                    schema.add_union_members(&name, union.union_member_types());
+                }
+                cst::Definition::EnumTypeDefinition(enum_def) if type_name(enum_def.name()) == "join__Graph" => {
+                    schema.add_subgraphs(enum_def.enum_values_definition());
+                }
+                _definition => {}
+            }
+        }
+
+        Ok(schema)
+    }
+
+    pub fn root_type(&self, operation_type: OperationType) -> String {
+        self.root_types
+            .get(operation_type.root_type())
+            .cloned()
+            .unwrap_or_else(|| operation_type.root_type().to_string())
+    }
+
+    // Named type returned by the field, lists and non-null wrappers removed
+    pub fn field_type(&self, parent_type: &str, field: &str) -> Option<&str> {
+        self.fields
+            .get(parent_type)
+            .and_then(|fields| fields.get(field))
+            .map(String::as_str)
+    }
+
+    // Whether the field can be null, then whether the items of each list it returns can be.
+    // `[Review!]` is `[true, false]`, empty for unknown fields
+    pub fn nullability(&self, parent_type: &str, field: &str) -> &[bool] {
+        self.nullability
+            .get(parent_type)
+            .and_then(|fields| fields.get(field))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    // Field names and the named type they return, `None` for scalars, enums and input types
+    pub fn fields_of(&self, type_name: &str) -> Option<&HashMap<String, String>> {
+        self.fields.get(type_name)
+    }
+
+    // Object types behind an interface or union, empty for any other type
+    pub fn possible_types(&self, abstract_type: &str) -> impl Iterator<Item = &str> {
+        self.possible_types
+            .get(abstract_type)
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+    }
+
+    // Types introspection could expose fields or members of
+    pub fn is_composite(&self, type_name: &str) -> bool {
+        self.fields.contains_key(type_name) || self.possible_types.contains_key(type_name)
+    }
+
+    // True when an object of `object_type` can be selected through `abstract_type`
+    pub fn is_possible_type(&self, abstract_type: &str, object_type: &str) -> bool {
+        self.possible_types
+            .get(abstract_type)
+            .map(|types| types.contains(object_type))
+            .unwrap_or(false)
+    }
+
+    // Subgraph names the supergraph doesn't have. Nothing can be checked without `join__Graph`
+    pub fn unknown_subgraphs(&self, names: &[String]) -> Vec<String> {
+        if self.subgraphs.is_empty() {
+            return Vec::new();
+        }
+        names
+            .iter()
+            .filter(|name| !self.subgraphs.contains(*name))
+            .cloned()
+            .collect()
+    }
+
+    // Permission entries that don't match anything in the schema
+    pub fn unknown_permissions(&self, entries: &[String]) -> Vec<String> {
+        entries
+            .iter()
+            .filter(|entry| {
+                let rule = Rule::parse(entry.strip_prefix('!').unwrap_or(entry));
+                !self.rule_exists(&rule)
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn rule_exists(&self, rule: &Rule) -> bool {
+        match rule {
+            Rule::Coordinate { type_name, field } => self.field_type(type_name, field).is_some(),
+            Rule::Path(segments) => {
+                // A path starts at the root field of any operation type
+                let roots: Vec<String> = [OperationType::Query, OperationType::Mutation, OperationType::Subscription]
+                    .into_iter()
+                    .map(|operation_type| self.root_type(operation_type))
+                    .collect();
+
+                roots.iter().any(|root| self.path_exists(root, segments))
+            }
+        }
+    }
+
+    fn path_exists(&self, parent_type: &str, segments: &[String]) -> bool {
+        match segments.split_first() {
+            None => true,
+            // Globs can't be checked any further
+            Some((segment, _rest)) if segment == "*" || segment == "**" => self.fields.contains_key(parent_type),
+            Some((segment, rest)) =>
+                match self.field_type(parent_type, segment) {
+                    Some(field_type) => self.path_exists(field_type, rest),
+                    None => false,
+                }
+        }
+    }
+
+    fn add_root_type(&mut self, operation_type: Option<cst::OperationType>, named_type: Option<cst::NamedType>) {
+        let Some(operation_type) = operation_type else {
+            return;
+        };
+        let root = if operation_type.mutation_token().is_some() {
+            OperationType::Mutation
+        } else if operation_type.subscription_token().is_some() {
+            OperationType::Subscription
+        } else {
+            OperationType::Query
+        };
+
+        if let Some(name) = named_type.and_then(|named_type| named_type.name()) {
+            self.root_types.insert(root.root_type(), name.text().to_string());
+        }
+    }
+
+    fn add_fields(&mut self, type_name: &str, fields: Option<cst::FieldsDefinition>) {
+        let entry = self.fields.entry(type_name.to_string()).or_default();
+        let nullability = self.nullability.entry(type_name.to_string()).or_default();
+
+        for field in fields.iter().flat_map(|fields| fields.field_definitions()) {
+            if let (Some(name), Some(ty)) = (field.name(), field.ty()) {
+                if let Some(named) = named_type(ty.clone()) {
+                    entry.insert(name.text().to_string(), named);
+                    nullability.insert(name.text().to_string(), type_nullability(ty));
+                }
+            }
+        }
+    }
+
+    fn add_implements(&mut self, type_name: &str, implements: Option<cst::ImplementsInterfaces>) {
+        for interface in implements.iter().flat_map(|implements| implements.named_types()) {
+            if let Some(name) = interface.name() {
+                self.possible_types.entry(name.text().to_string()).or_default().insert(type_name.to_string());
+            }
+        }
+    }
+
+    // `PRODUCTS @join__graph(name: "products", url: "...")`
+    fn add_subgraphs(&mut self, values: Option<cst::EnumValuesDefinition>) {
+        for value in values.iter().flat_map(|values| values.enum_value_definitions()) {
+            let join_graph = value
+                .directives()
+                .into_iter()
+                .flat_map(|directives| directives.directives())
+                .find(|directive| directive.name().map(|name| name.text() == "join__graph").unwrap_or(false));
+            let name = join_graph
+                .and_then(|directive| directive.arguments())
+                .into_iter()
+                .flat_map(|arguments| arguments.arguments())
+                .find(|argument| argument.name().map(|name| name.text() == "name").unwrap_or(false))
+                .and_then(|argument| argument.value());
+
+            if let Some(cst::Value::StringValue(name)) = name {
+                self.subgraphs.insert(String::from(name));
+            }
+        }
+    }
+
+    fn add_union_members(&mut self, type_name: &str, members: Option<cst::UnionMemberTypes>) {
+        let entry = self.possible_types.entry(type_name.to_string()).or_default();
+
+        for member in members.iter().flat_map(|members| members.named_types()) {
+            if let Some(name) = member.name() {
+                entry.insert(name.text().to_string());
+            }
+        }
+    }
+}
+
+fn type_name(name: Option<cst::Name>) -> String {
+    name.map(|name| name.text().to_string()).unwrap_or_default()
+}
+
+fn named_type(ty: cst::Type) -> Option<String> {
+    match ty {
+        cst::Type::NamedType(named_type) => named_type.name().map(|name| name.text().to_string()),
+        cst::Type::ListType(list) => list.ty().and_then(named_type),
+        cst::Type::NonNullType(non_null) => {
+            match (non_null.named_type(), non_null.list_type()) {
+                (Some(named_type), _) => named_type.name().map(|name| name.text().to_string()),
+                (None, Some(list)) => list.ty().and_then(named_type),
+                (None, None) => None,
+            }
+        }
+    }
+}
+
+fn type_nullability(ty: cst::Type) -> Vec<bool> {
+    match ty {
+        cst::Type::NamedType(_named_type) => vec![true],
+        cst::Type::ListType(list) => list_nullability(list),
+        cst::Type::NonNullType(non_null) => {
+            let mut levels = non_null.list_type().map(list_nullability).unwrap_or_else(|| vec![true]);
+            levels[0] = false;
+            levels
+        }
+    }
+}
+
+fn list_nullability(list: cst::ListType) -> Vec<bool> {
+    std::iter
+        ::once(true)
+        .chain(list.ty().map(type_nullability).unwrap_or_default())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SDL: &str = r#"
+        type Query {
+            product(id: ID!): Product
+            allProducts: [Product!]!
+            search(text: String): [SearchResult]
+            node(id: ID!): Node
+        }
+
+        type Mutation {
+            createProduct(name: String!): Product!
+        }
+
+        interface Node {
+            id: ID!
+        }
+
+        type Product implements Node {
+            id: ID!
+            name: String
+            costPrice: Float
+            supplier: Supplier
+            reviews: [Review!]
+        }
+
+        type Supplier {
+            name: String
+            costPrice: Float
+        }
+
+        type Review {
+            body: String
+        }
+
+        union SearchResult = Product | Supplier
+    "#;
+
+    #[test]
+    fn resolves_field_types_through_wrappers() {
+        let schema = SchemaTypes::parse(SDL).unwrap();
+
+        assert_eq!(schema.field_type("Query", "product"), Some("Product"));
+        assert_eq!(schema.field_type("Query", "allProducts"), Some("Product"));
+        assert_eq!(schema.field_type("Product", "reviews"), Some("Review"));
+        assert_eq!(schema.field_type("Product", "missing"), None);
+        assert_eq!(schema.root_type(OperationType::Mutation), "Mutation");
+    }
+
+    #[test]
+    fn tracks_nullability_of_fields_and_list_items() {
+        let schema = SchemaTypes::parse(SDL).unwrap();
+
+        assert_eq!(schema.nullability("Query", "product"), [true]);
+        assert_eq!(schema.nullability("Query", "allProducts"), [false, false]);
+        assert_eq!(schema.nullability("Query", "search"), [true, true]);
+        assert_eq!(schema.nullability("Product", "reviews"), [true, false]);
+        assert_eq!(schema.nullability("Product", "id"), [false]);
+        assert!(schema.nullability("Product", "missing").is_empty());
+    }
+
+    #[test]
+    fn knows_possible_types_of_interfaces_and_unions() {
+        let schema = SchemaTypes::parse(SDL).unwrap();
+
+        assert!(schema.is_possible_type("Node", "Product"));
+        assert!(schema.is_possible_type("SearchResult", "Supplier"));
+        assert!(!schema.is_possible_type("Node", "Supplier"));
+    }
+
+    #[test]
+    fn honors_custom_root_type_names() {
+        let schema = SchemaTypes::parse("schema { query: RootQuery } type RootQuery { hello: String }").unwrap();
+
+        assert_eq!(schema.root_type(OperationType::Query), "RootQuery");
+        assert!(schema.unknown_permissions(&["hello".to_string(), "RootQuery.hello".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn reports_unknown_permissions() {
+        let schema = SchemaTypes::parse(SDL).unwrap();
+        let entries: Vec<String> = [
+            "product",
+            "Query.product",
+            "Mutation.createProduct",
+            "product.supplier.costPrice",
+            "!Product.costPrice",
+            "product.*.name",
+            "product.**",
+            "Product.price",
+            "products",
+            "product.supplier.email",
+            "Order.id",
+        ]
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect();
+
+        assert_eq!(
+            schema.unknown_permissions(&entries),
+            vec!["Product.price", "products", "product.supplier.email", "Order.id"]
+        );
+    }
+
+    #[test]
+    fn reads_subgraph_names_from_join_graph() {
+        let sdl = r#"
+            enum join__Graph {
+                PRODUCTS @join__graph(name: "products", url: "http://products:4001/graphql")
+                REVIEWS @join__graph(name: "reviews", url: "http://reviews:4002/graphql")
+            }
+            type Query { product: String }
+        "#;
+        let schema = SchemaTypes::parse(sdl).unwrap();
+        let names = ["products".to_string(), "inventory".to_string()];
+
+        assert_eq!(schema.unknown_subgraphs(&names), vec!["inventory"]);
+        assert!(SchemaTypes::parse(SDL).unwrap().unknown_subgraphs(&names).is_empty());
+    }
+}
@@ -0,0 +1,31 @@
+pub use acme_common::shadow::{ EnforcementMode, ShadowDecision };
+
+use crate::messages::MessageCatalog;
+use crate::plugin_functions::AuthError;
+
+// Set in shadow mode on every request the plugin checks, with what enforce mode would have done
+pub const SHADOW_DECISION_CONTEXT_KEY: &str = "acme::allow_request::shadow_decision";
+
+// The reason is in the catalog's default locale, whatever language the client asked for
+pub fn denied_decision(err: &AuthError, catalog: &MessageCatalog) -> ShadowDecision {
+    ShadowDecision::denied(err.extension_code(), err.localized(catalog, catalog.default_locale()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::bundled_catalog;
+
+    #[test]
+    fn denied_decisions_carry_the_error() {
+        let catalog = bundled_catalog();
+        let decision = denied_decision(&AuthError::AppNotRegistered, catalog);
+
+        assert!(!decision.allowed);
+        assert_eq!(decision.code.as_deref(), Some(AuthError::AppNotRegistered.extension_code()));
+        assert_eq!(
+            decision.reason,
+            Some(AuthError::AppNotRegistered.localized(catalog, catalog.default_locale()))
+        );
+    }
+}
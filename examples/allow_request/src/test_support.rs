@@ -0,0 +1,30 @@
+use std::sync::{ Arc, Mutex };
+
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tokio::net::TcpListener;
+
+// Minimal HTTP server answering every request with the current status and body, returns the
+// URL of `path` on it
+pub async fn stub_server(path: &str, response: Arc<Mutex<(u16, String)>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 4096];
+            let _ = socket.read(&mut buffer).await;
+
+            let (status, body) = response.lock().unwrap().clone();
+            let reply = format!(
+                "HTTP/1.1 {} STUB\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(reply.as_bytes()).await;
+        }
+    });
+
+    format!("http://{}{}", address, path)
+}